@@ -0,0 +1,335 @@
+use crate::ChatType;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+    types::Json,
+    Decode, Encode, Postgres, Type,
+};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+/// Structured message body, stored as jsonb next to the plain text content of the message
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct MessageBody {
+    pub blocks: Vec<ContentBlock>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Markdown {
+        text: String,
+    },
+    Code {
+        language: Option<String>,
+        code: String,
+    },
+    Quote {
+        text: String,
+        author: Option<String>,
+    },
+    Attachment {
+        // url of an uploaded chat file
+        url: String,
+        name: String,
+        mime: Option<String>,
+        size: Option<u64>,
+    },
+    // app defined blocks, mostly used by bots
+    Buttons {
+        buttons: Vec<Button>,
+    },
+    Fields {
+        fields: Vec<Field>,
+    },
+    // created through the poll api only, the poll id is the id of the message
+    Poll {
+        question: String,
+        options: Vec<String>,
+    },
+    // created by the server only, the event of a system message rendered for its actor
+    System {
+        text: String,
+        event: SystemEvent,
+        actor_id: i64,
+    },
+}
+
+/// Chat lifecycle event of a system message
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SystemEvent {
+    MemberJoined,
+    MemberLeft,
+    MembersAdded { user_ids: Vec<i64> },
+    MembersRemoved { user_ids: Vec<i64> },
+    ChatRenamed { name: Option<String> },
+    TopicChanged { topic: Option<String> },
+    ChatConverted { chat_type: ChatType },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Button {
+    pub label: String,
+    // sent back to the app when the button is clicked
+    pub action_id: String,
+    pub value: Option<String>,
+    #[serde(default)]
+    pub style: ButtonStyle,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonStyle {
+    #[default]
+    Default,
+    Primary,
+    Danger,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Field {
+    pub title: String,
+    pub value: String,
+    // short fields can be displayed side by side
+    #[serde(default)]
+    pub short: bool,
+}
+
+impl MessageBody {
+    pub fn new(blocks: Vec<ContentBlock>) -> Self {
+        Self { blocks }
+    }
+
+    /// Plain text fallback of the body, used for search and notifications
+    pub fn to_plain_text(&self) -> String {
+        self.blocks
+            .iter()
+            .map(ContentBlock::to_plain_text)
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Urls of the chat files attached to the body
+    pub fn file_urls(&self) -> impl Iterator<Item = &str> {
+        self.blocks.iter().filter_map(|block| match block {
+            ContentBlock::Attachment { url, .. } => Some(url.as_str()),
+            _ => None,
+        })
+    }
+}
+
+impl ContentBlock {
+    pub fn to_plain_text(&self) -> String {
+        match self {
+            ContentBlock::Markdown { text } => text.clone(),
+            ContentBlock::Code { code, .. } => code.clone(),
+            ContentBlock::Quote { text, author } => {
+                let quote = text
+                    .lines()
+                    .map(|line| format!("> {}", line))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                match author {
+                    Some(author) => format!("{}\n> -- {}", quote, author),
+                    None => quote,
+                }
+            }
+            ContentBlock::Attachment { name, .. } => format!("[{}]", name),
+            ContentBlock::Buttons { buttons } => buttons
+                .iter()
+                .map(|b| format!("[{}]", b.label))
+                .collect::<Vec<_>>()
+                .join(" "),
+            ContentBlock::Fields { fields } => fields
+                .iter()
+                .map(|f| format!("{}: {}", f.title, f.value))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ContentBlock::Poll { question, options } => std::iter::once(question.clone())
+                .chain(options.iter().map(|o| format!("- {}", o)))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ContentBlock::System { text, .. } => text.clone(),
+        }
+    }
+}
+
+impl SystemEvent {
+    /// Users the event is about, besides its actor
+    pub fn user_ids(&self) -> &[i64] {
+        match self {
+            SystemEvent::MembersAdded { user_ids } | SystemEvent::MembersRemoved { user_ids } => {
+                user_ids
+            }
+            _ => &[],
+        }
+    }
+
+    /// Text of the event from its template, users are named by `names`
+    pub fn render(&self, actor: &str, names: &HashMap<i64, String>) -> String {
+        let users = || {
+            self.user_ids()
+                .iter()
+                .map(|id| {
+                    names
+                        .get(id)
+                        .cloned()
+                        .unwrap_or_else(|| format!("user {}", id))
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            SystemEvent::MemberJoined => format!("{} joined the channel", actor),
+            SystemEvent::MemberLeft => format!("{} left the chat", actor),
+            SystemEvent::MembersAdded { .. } => format!("{} added {}", actor, users()),
+            SystemEvent::MembersRemoved { .. } => format!("{} removed {}", actor, users()),
+            SystemEvent::ChatRenamed { name: Some(name) } => {
+                format!("{} renamed the chat to {}", actor, name)
+            }
+            SystemEvent::ChatRenamed { name: None } => format!("{} removed the chat name", actor),
+            SystemEvent::TopicChanged { topic: Some(topic) } => {
+                format!("{} changed the topic to {}", actor, topic)
+            }
+            SystemEvent::TopicChanged { topic: None } => format!("{} cleared the topic", actor),
+            SystemEvent::ChatConverted { chat_type } => {
+                format!("{} converted the chat to a {}", actor, chat_type)
+            }
+        }
+    }
+}
+
+/// Reference to the original message of a forwarded message, kept even if the original is deleted
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct ForwardedFrom {
+    pub message_id: i64,
+    pub chat_id: i64,
+    pub sender_id: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+// stored as jsonb, so that an optional value maps to a nullable column
+macro_rules! impl_jsonb_type {
+    ($ty:ty) => {
+        impl Type<Postgres> for $ty {
+            fn type_info() -> PgTypeInfo {
+                <Json<Self> as Type<Postgres>>::type_info()
+            }
+
+            fn compatible(ty: &PgTypeInfo) -> bool {
+                <Json<Self> as Type<Postgres>>::compatible(ty)
+            }
+        }
+
+        impl Encode<'_, Postgres> for $ty {
+            fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+                <Json<&Self> as Encode<Postgres>>::encode(Json(self), buf)
+            }
+        }
+
+        impl<'r> Decode<'r, Postgres> for $ty {
+            fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+                Ok(<Json<Self> as Decode<Postgres>>::decode(value)?.0)
+            }
+        }
+    };
+}
+
+impl_jsonb_type!(MessageBody);
+impl_jsonb_type!(ForwardedFrom);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_body_plain_text_should_work() {
+        let body = MessageBody::new(vec![
+            ContentBlock::Markdown {
+                text: "**deploy** done".to_string(),
+            },
+            ContentBlock::Code {
+                language: Some("rust".to_string()),
+                code: "fn main() {}".to_string(),
+            },
+            ContentBlock::Quote {
+                text: "ship it\nnow".to_string(),
+                author: Some("taki".to_string()),
+            },
+            ContentBlock::Fields {
+                fields: vec![Field {
+                    title: "env".to_string(),
+                    value: "prod".to_string(),
+                    short: true,
+                }],
+            },
+            ContentBlock::Buttons {
+                buttons: vec![Button {
+                    label: "Rollback".to_string(),
+                    action_id: "rollback".to_string(),
+                    value: None,
+                    style: ButtonStyle::Danger,
+                }],
+            },
+        ]);
+        assert_eq!(
+            body.to_plain_text(),
+            "**deploy** done\nfn main() {}\n> ship it\n> now\n> -- taki\nenv: prod\n[Rollback]"
+        );
+    }
+
+    #[test]
+    fn system_event_render_should_work() {
+        let names = HashMap::from([(1, "mitsuha".to_string()), (2, "yotsuha".to_string())]);
+        let event = SystemEvent::MembersAdded {
+            user_ids: vec![1, 2, 6],
+        };
+        assert_eq!(
+            event.render("taki", &names),
+            "taki added mitsuha, yotsuha, user 6"
+        );
+        let event = SystemEvent::TopicChanged { topic: None };
+        assert_eq!(event.render("taki", &names), "taki cleared the topic");
+        let event = SystemEvent::ChatRenamed {
+            name: Some("town square".to_string()),
+        };
+        assert_eq!(
+            event.render("taki", &names),
+            "taki renamed the chat to town square"
+        );
+    }
+
+    #[test]
+    fn message_body_serde_should_work() -> anyhow::Result<()> {
+        let json = r#"{"blocks":[{"type":"code","language":null,"code":"ls"},{"type":"buttons","buttons":[{"label":"Ok","action_id":"ok","value":null}]}]}"#;
+        let body: MessageBody = serde_json::from_str(json)?;
+        assert_eq!(
+            body.blocks[0],
+            ContentBlock::Code {
+                language: None,
+                code: "ls".to_string()
+            }
+        );
+        let ContentBlock::Buttons { buttons } = &body.blocks[1] else {
+            panic!("expect buttons block");
+        };
+        assert_eq!(buttons[0].style, ButtonStyle::Default);
+
+        let json = r#"{"blocks":[{"type":"system","text":"taki added mitsuha","event":{"kind":"members_added","user_ids":[1]},"actor_id":3}]}"#;
+        let body: MessageBody = serde_json::from_str(json)?;
+        let ContentBlock::System { event, .. } = &body.blocks[0] else {
+            panic!("expect system block");
+        };
+        assert_eq!(event.user_ids(), &[1]);
+
+        // unknown block type should be rejected
+        let json = r#"{"blocks":[{"type":"video","url":"/files/1.mp4"}]}"#;
+        assert!(serde_json::from_str::<MessageBody>(json).is_err());
+        Ok(())
+    }
+}
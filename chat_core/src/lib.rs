@@ -1,11 +1,14 @@
+mod content;
 pub mod middlewares;
 mod utils;
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use std::fmt;
 use utoipa::ToSchema;
 
+pub use content::*;
 pub use utils::*;
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
@@ -15,6 +18,18 @@ pub struct Chat {
     pub name: Option<String>,
     pub r#type: ChatType,
     pub members: Vec<i64>,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    // url of the avatar file, uploaded in the workspace of the chat
+    pub avatar: Option<String>,
+    // archived chats are read-only until restored
+    pub archived: bool,
+    // only owners and admins post in announcement chats
+    pub announcement: bool,
+    // seconds a member waits between two messages, owners and admins are not limited
+    pub slow_mode: Option<i64>,
+    // seconds after which new messages of the chat expire, unless the message sets its own
+    pub expires_after: Option<i64>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -27,14 +42,108 @@ pub enum ChatType {
     PublicChannel,
 }
 
+impl fmt::Display for ChatType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ChatType::Single => "direct chat",
+            ChatType::Group => "group",
+            ChatType::PrivateChannel => "private channel",
+            ChatType::PublicChannel => "public channel",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// system messages are sent by the server for chat events, e.g. a member joining
+#[derive(
+    Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default, sqlx::Type, ToSchema,
+)]
+#[sqlx(type_name = "message_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    #[default]
+    User,
+    System,
+}
+
+// role of a member in a chat, owners and admins manage the chat
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "chat_role", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ChatRole {
+    Owner,
+    Admin,
+    Member,
+}
+
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
 pub struct Message {
     pub id: i64,
     pub chat_id: i64,
+    // sequence number of the message in the chat, starts from 1 without gaps on insert
+    pub seq: i64,
     pub sender_id: i64,
+    // plain text of the message, derived from body if the body is given
     pub content: String,
+    pub body: Option<MessageBody>,
     pub files: Vec<String>,
+    // set if the message is forwarded from another chat
+    pub forwarded_from: Option<ForwardedFrom>,
+    pub kind: MessageKind,
     pub created_at: DateTime<Utc>,
+    // ephemeral message is deleted after this time and never listed again
+    pub expires_at: Option<DateTime<Utc>>,
+    // sanitized html rendered from the markdown content, only present if requested
+    #[sqlx(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct ChatPin {
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub pinned_by: i64,
+    pub pinned_at: DateTime<Utc>,
+}
+
+// live results of a poll, voters are not included as the poll may be anonymous
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct PollUpdate {
+    pub poll_id: i64,
+    pub chat_id: i64,
+    // number of votes of each option
+    pub votes: Vec<i64>,
+    pub total_voters: i64,
+}
+
+// unsent message of a user in a chat, synced across the sessions of the user
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Draft {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub content: String,
+    pub body: Option<MessageBody>,
+    pub files: Vec<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// the last message a user has read in a chat
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct ReadState {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub last_read_seq: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+// a saved message to look at again, sent to the user who saved it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Reminder {
+    pub user_id: i64,
+    pub message_id: i64,
+    pub chat_id: i64,
+    pub remind_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
@@ -44,18 +44,50 @@ VALUES
 --insert 4 chats
 --named chats
 INSERT INTO
-    chats (ws_id, name, type, members)
+    chats (ws_id, name, type)
 VALUES
-    (0, 'single_chat', 'single', '{1,2}'),
-    (0, 'group_chat', 'group', '{1,2,3,4,5}'),
-    (0, 'private_chat', 'private_channel', '{1,2,3,4,5}'),
-    (0, 'public_chat', 'public_channel', '{1,2,3,4,5}');
+    (0, 'single_chat', 'single'),
+    (0, 'group_chat', 'group'),
+    (0, 'private_chat', 'private_channel'),
+    (0, 'public_chat', 'public_channel');
     --unnamed chat
 INSERT INTO
-    chats (ws_id, type, members)
+    chats (ws_id, type)
 VALUES
-    (0, 'single', '{1,2}'),
-    (0, 'group', '{1,2,3,4,5}');
+    (0, 'single'),
+    (0, 'group');
+
+--single_chat is the direct chat of mitsuha and yotsuha
+UPDATE chats SET dm_pair = '{1,2}' WHERE id = 1;
+
+--mitsuha owns all chats, yotsuha is an admin of group_chat
+INSERT INTO
+    chat_members (chat_id, user_id, role)
+VALUES
+    (1, 1, 'owner'),
+    (1, 2, 'member'),
+    (2, 1, 'owner'),
+    (2, 2, 'admin'),
+    (2, 3, 'member'),
+    (2, 4, 'member'),
+    (2, 5, 'member'),
+    (3, 1, 'owner'),
+    (3, 2, 'member'),
+    (3, 3, 'member'),
+    (3, 4, 'member'),
+    (3, 5, 'member'),
+    (4, 1, 'owner'),
+    (4, 2, 'member'),
+    (4, 3, 'member'),
+    (4, 4, 'member'),
+    (4, 5, 'member'),
+    (5, 1, 'owner'),
+    (5, 2, 'member'),
+    (6, 1, 'owner'),
+    (6, 2, 'member'),
+    (6, 3, 'member'),
+    (6, 4, 'member'),
+    (6, 5, 'member');
 
 
 
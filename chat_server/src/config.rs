@@ -5,6 +5,7 @@ use std::{env, fs::File, path::PathBuf};
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    pub chat: ChatConfig,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -20,6 +21,28 @@ pub struct AuthConfig {
     pub pk: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChatConfig {
+    // max number of pinned messages per chat
+    pub max_pins: u64,
+    // max number of messages returned by one page, also used when no limit is given
+    pub max_page_size: u64,
+    // max size in bytes of a message or draft, its encoded content, body and files together.
+    // Both are sent through pg_notify which limits the payload to 8000 bytes, keep it well
+    // below to leave room for the member ids sent along
+    pub max_body_size: u64,
+    // seconds between two runs of the background workers, which deliver scheduled messages,
+    // delete expired messages and fire reminders
+    pub worker_interval: u64,
+    // seconds during which a retried send with the same idempotency key returns the original message
+    pub idempotency_window: u64,
+    // seconds during which changes are kept for delta sync, older cursors need a full sync
+    pub sync_retention: u64,
+    // seconds a running transaction can hold back the changes of others in delta sync, a
+    // transaction running longer may have its changes skipped by cursors issued meanwhile
+    pub sync_max_lag: u64,
+}
+
 impl AppConfig {
     pub fn load() -> anyhow::Result<Self> {
         //read from ./chat.yaml or  /etc/config/chat.yaml or from env CHAT_CONFIG
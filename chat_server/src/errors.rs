@@ -42,6 +42,36 @@ pub enum AppError {
     CreateMessageError(String),
     #[error("{0}")]
     ChatFileError(String),
+    #[error("pin message error: {0}")]
+    PinMessageError(String),
+    #[error("list message error: {0}")]
+    ListMessageError(String),
+    #[error("forward message error: {0}")]
+    ForwardMessageError(String),
+    #[error("poll error: {0}")]
+    PollError(String),
+    #[error("save message error: {0}")]
+    SaveMessageError(String),
+    #[error("draft error: {0}")]
+    DraftError(String),
+    #[error("sync error: {0}")]
+    SyncError(String),
+    #[error("sync cursor expired, a full sync is needed")]
+    SyncCursorExpired,
+    #[error("inbox error: {0}")]
+    InboxError(String),
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("chat {0} is archived")]
+    ChatArchived(u64),
+    #[error("notification error: {0}")]
+    NotificationError(String),
+    #[error("only owners and admins can post in announcement chat {0}")]
+    AnnouncementOnly(u64),
+    #[error("slow mode is on in chat {0}, wait {1} seconds before posting again")]
+    SlowMode(u64, u64),
+    #[error("chat conversion error: {0}")]
+    ChatConversionError(String),
 }
 
 impl IntoResponse for AppError {
@@ -57,6 +87,21 @@ impl IntoResponse for AppError {
             AppError::MultipartError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             AppError::CreateMessageError(_) => StatusCode::BAD_REQUEST,
             AppError::ChatFileError(_) => StatusCode::BAD_REQUEST,
+            AppError::PinMessageError(_) => StatusCode::BAD_REQUEST,
+            AppError::ListMessageError(_) => StatusCode::BAD_REQUEST,
+            AppError::ForwardMessageError(_) => StatusCode::BAD_REQUEST,
+            AppError::PollError(_) => StatusCode::BAD_REQUEST,
+            AppError::SaveMessageError(_) => StatusCode::BAD_REQUEST,
+            AppError::DraftError(_) => StatusCode::BAD_REQUEST,
+            AppError::SyncError(_) => StatusCode::BAD_REQUEST,
+            AppError::SyncCursorExpired => StatusCode::GONE,
+            AppError::InboxError(_) => StatusCode::BAD_REQUEST,
+            AppError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            AppError::ChatArchived(_) => StatusCode::FORBIDDEN,
+            AppError::NotificationError(_) => StatusCode::BAD_REQUEST,
+            AppError::AnnouncementOnly(_) => StatusCode::FORBIDDEN,
+            AppError::SlowMode(_, _) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::ChatConversionError(_) => StatusCode::UNPROCESSABLE_ENTITY,
         };
         let body = (status_code, Json(OutputError::new(self.to_string())));
         body.into_response()
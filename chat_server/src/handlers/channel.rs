@@ -0,0 +1,73 @@
+use crate::{AppError, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/channels",
+    responses(
+        (status = 200, description = "Public channels of the workspace", body = Vec<Channel>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_channel_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let channels = state.list_channels(user.ws_id as _, user.id as _).await?;
+    Ok(Json(channels))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/join",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    responses(
+        (status = 200, description = "Join a public channel", body = Chat),
+        (status = 403, description = "The channel is archived", body = OutputError),
+        (status = 404, description = "Channel not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn join_channel_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.join_channel(id, user.id as _).await?;
+    Ok(Json(chat))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/leave",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    responses(
+        (status = 204, description = "Leave the chat"),
+        (status = 403, description = "The owner has to transfer the ownership first", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn leave_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.leave_chat(id, user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,5 +1,5 @@
 use crate::{
-    models::{CreateChat, UpdateChat},
+    models::{ConvertChat, CreateChat, UpdateChat},
     AppError, AppState,
 };
 use axum::{
@@ -14,7 +14,7 @@ use chat_core::User;
     get,
     path = "/api/chats",
     responses(
-        (status = 200, description = "List of chats", body = Vec<Chat>),
+        (status = 200, description = "Chats the user is a member of and public channels of the workspace", body = Vec<UserChat>),
     ),
     security(
         ("token" = [])
@@ -24,7 +24,7 @@ pub(crate) async fn list_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chats = state.fetch_chats_by_ws_id(user.ws_id).await?;
+    let chats = state.fetch_chats_by_ws_id(user.ws_id, user.id).await?;
     Ok((StatusCode::OK, Json(chats)))
 }
 
@@ -33,6 +33,7 @@ pub(crate) async fn list_chat_handler(
     path = "/api/chats",
     responses(
         (status = 201, description = "Create chat", body = Chat),
+        (status = 200, description = "The direct chat of the members already exists", body = Chat),
     ),
     params(
         ("id" = u64, Path, description = "Chat id"),
@@ -47,8 +48,46 @@ pub(crate) async fn create_chat_handler(
     State(state): State<AppState>,
     Json(input): Json<CreateChat>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.create_chat(&input, user.ws_id).await?;
-    Ok((StatusCode::CREATED, Json(chat)))
+    let (chat, created) = state
+        .create_or_open_chat(&input, user.ws_id, user.id)
+        .await?;
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(chat)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/dm/{user_id}",
+    responses(
+        (status = 200, description = "The direct chat with the user", body = Chat),
+        (status = 201, description = "The direct chat with the user was created", body = Chat),
+        (status = 422, description = "Invalid user", body = OutputError),
+    ),
+    params(
+        ("user_id" = u64, Path, description = "User id")
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn open_direct_chat_handler(
+    Extension(user): Extension<User>,
+    Path(other_id): Path<i64>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let (chat, created) = state
+        .open_direct_chat(user.ws_id, user.id, other_id)
+        .await?;
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(chat)))
 }
 
 #[utoipa::path(
@@ -56,6 +95,7 @@ pub(crate) async fn create_chat_handler(
     path = "/api/chats/{id}",
     responses(
         (status = 200, description = "Update chat", body = Chat),
+        (status = 403, description = "Not allowed by the role of the user", body = OutputError),
         (status = 404, description = "Chat not found", body = OutputError),
     ),
     params(
@@ -67,11 +107,38 @@ pub(crate) async fn create_chat_handler(
     )
 )]
 pub(crate) async fn update_chat_handler(
+    Extension(user): Extension<User>,
     Path(id): Path<i64>,
     State(state): State<AppState>,
     Json(input): Json<UpdateChat>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.update_chat_by_id(&input, id).await?;
+    let chat = state.update_chat_by_id(&input, id, user.id).await?;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/convert",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    request_body = ConvertChat,
+    responses(
+        (status = 200, description = "Chat converted to the type", body = Chat),
+        (status = 403, description = "Not allowed by the role of the user", body = OutputError),
+        (status = 422, description = "The chat can't be converted to the type", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn convert_chat_handler(
+    Extension(user): Extension<User>,
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Json(input): Json<ConvertChat>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.convert_chat(&input, id, user.id).await?;
     Ok((StatusCode::OK, Json(chat)))
 }
 
@@ -80,6 +147,7 @@ pub(crate) async fn update_chat_handler(
     path = "/api/chats/{id}",
     responses(
         (status = 204, description = "delete chat"),
+        (status = 403, description = "Only owners and admins can delete the chat", body = OutputError),
         (status = 404, description = "Chat not found", body = OutputError),
     ),
     security(
@@ -87,9 +155,10 @@ pub(crate) async fn update_chat_handler(
     )
 )]
 pub(crate) async fn delete_chat_handler(
+    Extension(user): Extension<User>,
     Path(id): Path<i64>,
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, AppError> {
-    state.delete_chat_by_id(id).await?;
+    state.delete_chat_by_id(id, user.id).await?;
     Ok(StatusCode::NO_CONTENT)
 }
@@ -0,0 +1,79 @@
+use crate::{AppError, AppState, UpdateDraft};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/draft",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    responses(
+        (status = 200, description = "Draft of the chat", body = Draft),
+        (status = 404, description = "Draft not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_draft_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let draft = state.get_draft(id, user.id as _).await?;
+    Ok(Json(draft))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/chats/{id}/draft",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    request_body = UpdateDraft,
+    responses(
+        (status = 200, description = "Save the draft of the chat", body = Draft),
+        (status = 400, description = "Invalid input", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn save_draft_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateDraft>,
+) -> Result<impl IntoResponse, AppError> {
+    let draft = state.save_draft(input, id, user.id as _).await?;
+    Ok(Json(draft))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/draft",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    responses(
+        (status = 204, description = "Delete the draft of the chat"),
+        (status = 404, description = "Draft not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn delete_draft_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_draft(id, user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,54 @@
+use crate::{AppError, AppState, ListInbox, UpdateChatSettings};
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/inbox",
+    params(
+        ListInbox
+    ),
+    responses(
+        (status = 200, description = "Chats of the user, the most recently active first", body = InboxPage),
+        (status = 400, description = "Invalid input", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_inbox_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListInbox>,
+) -> Result<impl IntoResponse, AppError> {
+    let page = state.list_inbox(input, user.id as _).await?;
+    Ok(Json(page))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/chats/{id}/settings",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    request_body = UpdateChatSettings,
+    responses(
+        (status = 200, description = "Inbox settings of the chat", body = ChatSettings),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_chat_settings_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateChatSettings>,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = state.update_chat_settings(input, id, user.id as _).await?;
+    Ok(Json(settings))
+}
@@ -5,13 +5,19 @@ use axum::{
     Extension, Json,
 };
 use axum_extra::extract::Multipart;
+use chrono::Utc;
 use std::vec;
 use tokio::fs;
 use tracing::{info, warn};
 
-use crate::{models::ChatFile, AppError, AppState, CreateMessage, ListMessages};
+use crate::{
+    models::ChatFile, AppError, AppState, CreateMessage, ForwardMessages, ListMessages, Scheduled,
+};
 use chat_core::User;
 
+// retried sends with the same key return the original message
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
 #[utoipa::path(
     get,
     path = "/api/chats/{id}/messages",
@@ -20,7 +26,7 @@ use chat_core::User;
         ListMessages
     ),
     responses(
-        (status = 200, description = "List of messages", body = Vec<Message>),
+        (status = 200, description = "A page of messages", body = MessagePage),
         (status = 400, description = "Invalid input", body = OutputError),
     ),
     security(
@@ -29,11 +35,16 @@ use chat_core::User;
 )]
 pub(crate) async fn list_message_handler(
     State(state): State<AppState>,
+    Extension(user): Extension<User>,
     Path(id): Path<u64>,
     Query(input): Query<ListMessages>,
 ) -> Result<impl IntoResponse, AppError> {
-    let messages = state.list_messages(input, id).await?;
-    Ok(Json(messages))
+    let format = input.format;
+    let mut page = state.list_messages(input, id).await?;
+    state
+        .format_messages(&mut page.messages, format, id, user.id as _)
+        .await?;
+    Ok(Json(page))
 }
 
 #[utoipa::path(
@@ -41,10 +52,12 @@ pub(crate) async fn list_message_handler(
     path = "/api/chats/{id}",
     params(
         ("id" = u64, Path, description = "Chat id"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Same as client_msg_id"),
         CreateMessage
     ),
     responses(
-        (status = 201, description = "Send a message", body = Message),
+        (status = 201, description = "Send a message, a retried schedule gets the delivered message", body = Message),
+        (status = 202, description = "Schedule a message to send later", body = ScheduledMessage),
     ),
     security(
         ("token" = [])
@@ -54,11 +67,70 @@ pub(crate) async fn send_message_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<u64>,
-    Json(input): Json<CreateMessage>,
+    headers: HeaderMap,
+    Json(mut input): Json<CreateMessage>,
 ) -> Result<impl IntoResponse, AppError> {
-    let msg = state.create_message(input, id, user.id as _).await?;
+    if let Some(key) = headers.get(IDEMPOTENCY_KEY) {
+        let key = key
+            .to_str()
+            .map_err(|_| AppError::CreateMessageError("Invalid idempotency key".to_string()))?;
+        match &input.client_msg_id {
+            Some(id) if id != key => {
+                return Err(AppError::CreateMessageError(
+                    "Idempotency key doesn't match client message id".to_string(),
+                ))
+            }
+            _ => input.client_msg_id = Some(key.to_string()),
+        }
+    }
+    if input.send_at.is_some_and(|send_at| send_at > Utc::now()) {
+        return Ok(
+            match state.schedule_message(input, id, user.id as _).await? {
+                Scheduled::Created(scheduled) => {
+                    state.clear_draft(id, user.id as _).await?;
+                    (StatusCode::ACCEPTED, Json(scheduled)).into_response()
+                }
+                Scheduled::Pending(scheduled) => {
+                    (StatusCode::ACCEPTED, Json(scheduled)).into_response()
+                }
+                Scheduled::Sent(msg) => (StatusCode::CREATED, Json(msg)).into_response(),
+            },
+        );
+    }
+    let (msg, created) = state.send_message(input, id, user.id as _).await?;
+    // the draft is sent, other sessions of the user are notified by the db trigger,
+    // a retry keeps the draft written since the first send
+    if created {
+        state.clear_draft(id, user.id as _).await?;
+    }
+
+    Ok((StatusCode::CREATED, Json(msg)).into_response())
+}
 
-    Ok((StatusCode::CREATED, Json(msg)))
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/forward",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    request_body = ForwardMessages,
+    responses(
+        (status = 201, description = "Forwarded messages, oldest first", body = Vec<Message>),
+        (status = 400, description = "Invalid input", body = OutputError),
+        (status = 404, description = "Message not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn forward_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<ForwardMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state.forward_messages(input, id, user.id as _).await?;
+    Ok((StatusCode::CREATED, Json(messages)))
 }
 
 ///handle file upload
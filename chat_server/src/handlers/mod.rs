@@ -1,11 +1,33 @@
 mod auth;
+mod channel;
 mod chat;
+mod draft;
+mod inbox;
 mod message;
+mod notification;
+mod pin;
+mod poll;
+mod read_state;
+mod role;
+mod saved;
+mod scheduled;
+mod sync;
 
 pub(crate) use auth::*;
 use axum::response::IntoResponse;
+pub(crate) use channel::*;
 pub(crate) use chat::*;
+pub(crate) use draft::*;
+pub(crate) use inbox::*;
 pub(crate) use message::*;
+pub(crate) use notification::*;
+pub(crate) use pin::*;
+pub(crate) use poll::*;
+pub(crate) use read_state::*;
+pub(crate) use role::*;
+pub(crate) use saved::*;
+pub(crate) use scheduled::*;
+pub(crate) use sync::*;
 
 pub(crate) async fn index_handler() -> impl IntoResponse {
     "index_handler"
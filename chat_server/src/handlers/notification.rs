@@ -0,0 +1,64 @@
+use crate::{AppError, AppState, UpdateDndSchedule};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/dnd",
+    responses(
+        (status = 200, description = "Do not disturb schedule of the user", body = DndSchedule),
+        (status = 404, description = "The user has no schedule", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_dnd_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let schedule = state
+        .get_dnd_schedule(user.id as _)
+        .await?
+        .ok_or_else(|| AppError::NotFountError("Do not disturb schedule not found".into()))?;
+    Ok(Json(schedule))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/dnd",
+    request_body = UpdateDndSchedule,
+    responses(
+        (status = 200, description = "Do not disturb schedule of the user", body = DndSchedule),
+        (status = 400, description = "Invalid input", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn set_dnd_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateDndSchedule>,
+) -> Result<impl IntoResponse, AppError> {
+    let schedule = state.set_dnd_schedule(input, user.id as _).await?;
+    Ok(Json(schedule))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/dnd",
+    responses(
+        (status = 204, description = "Remove the do not disturb schedule of the user"),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn clear_dnd_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.clear_dnd_schedule(user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,77 @@
+use crate::{AppError, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/pins",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    responses(
+        (status = 200, description = "List of pinned messages", body = Vec<PinnedMessage>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_pin_handler(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let pins = state.list_pinned_messages(id).await?;
+    Ok(Json(pins))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/pins/{mid}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("mid" = u64, Path, description = "Message id"),
+    ),
+    responses(
+        (status = 201, description = "Pin a message", body = ChatPin),
+        (status = 400, description = "Too many pinned messages", body = OutputError),
+        (status = 404, description = "Message not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn pin_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, mid)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let pin = state.pin_message(id, mid, user.id as _).await?;
+    Ok((StatusCode::CREATED, Json(pin)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/pins/{mid}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("mid" = u64, Path, description = "Message id"),
+    ),
+    responses(
+        (status = 204, description = "Unpin a message"),
+        (status = 404, description = "Pinned message not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn unpin_message_handler(
+    State(state): State<AppState>,
+    Path((id, mid)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.unpin_message(id, mid).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
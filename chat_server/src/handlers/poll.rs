@@ -0,0 +1,84 @@
+use crate::{AppError, AppState, CreatePoll, CreateVote};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/polls",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    request_body = CreatePoll,
+    responses(
+        (status = 201, description = "Create a poll with its message", body = PollResult),
+        (status = 400, description = "Invalid input", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn create_poll_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<CreatePoll>,
+) -> Result<impl IntoResponse, AppError> {
+    let poll = state.create_poll(input, id, user.id as _).await?;
+    Ok((StatusCode::CREATED, Json(poll)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/polls/{pid}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("pid" = u64, Path, description = "Poll id"),
+    ),
+    responses(
+        (status = 200, description = "Poll with its results", body = PollResult),
+        (status = 404, description = "Poll not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn get_poll_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, pid)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let poll = state.get_poll(id, pid, user.id as _).await?;
+    Ok(Json(poll))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/polls/{pid}/votes",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("pid" = u64, Path, description = "Poll id"),
+    ),
+    request_body = CreateVote,
+    responses(
+        (status = 200, description = "Poll with the updated results", body = PollResult),
+        (status = 400, description = "Invalid vote or poll closed", body = OutputError),
+        (status = 404, description = "Poll not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn vote_poll_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, pid)): Path<(u64, u64)>,
+    Json(input): Json<CreateVote>,
+) -> Result<impl IntoResponse, AppError> {
+    let poll = state.vote_poll(input, id, pid, user.id as _).await?;
+    Ok(Json(poll))
+}
@@ -0,0 +1,31 @@
+use crate::{AppError, AppState, MarkRead};
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/read",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    request_body = MarkRead,
+    responses(
+        (status = 200, description = "Read state of the chat", body = ReadState),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn mark_read_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<MarkRead>,
+) -> Result<impl IntoResponse, AppError> {
+    let read = state.mark_chat_read(input, id, user.id as _).await?;
+    Ok(Json(read))
+}
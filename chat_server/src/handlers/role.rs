@@ -0,0 +1,83 @@
+use crate::{AppError, AppState, TransferOwnership, UpdateChatRole};
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/members",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    responses(
+        (status = 200, description = "Members of the chat with their role", body = Vec<ChatMember>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_chat_member_handler(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let members = state.list_chat_members(id).await?;
+    Ok(Json(members))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/chats/{id}/members/{uid}/role",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("uid" = u64, Path, description = "Member id"),
+    ),
+    request_body = UpdateChatRole,
+    responses(
+        (status = 200, description = "Role of the member", body = ChatMember),
+        (status = 403, description = "Only the owner can change roles", body = OutputError),
+        (status = 404, description = "Member not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn update_chat_role_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, uid)): Path<(u64, u64)>,
+    Json(input): Json<UpdateChatRole>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state.update_chat_role(input, id, user.id as _, uid).await?;
+    Ok(Json(member))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/transfer",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+    ),
+    request_body = TransferOwnership,
+    responses(
+        (status = 200, description = "Members of the chat with their new role", body = Vec<ChatMember>),
+        (status = 403, description = "Only the owner can transfer the ownership", body = OutputError),
+        (status = 404, description = "Member not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn transfer_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<TransferOwnership>,
+) -> Result<impl IntoResponse, AppError> {
+    let members = state
+        .transfer_chat_ownership(input, id, user.id as _)
+        .await?;
+    Ok(Json(members))
+}
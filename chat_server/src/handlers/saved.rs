@@ -0,0 +1,71 @@
+use crate::{AppError, AppState, SaveMessage};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/saved",
+    responses(
+        (status = 200, description = "List of saved messages", body = Vec<SavedMessage>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_saved_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let saved = state.list_saved_messages(user.id as _).await?;
+    Ok(Json(saved))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/saved",
+    request_body = SaveMessage,
+    responses(
+        (status = 204, description = "Save a message"),
+        (status = 400, description = "Invalid input", body = OutputError),
+        (status = 404, description = "Message not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn save_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<SaveMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    state.save_message(input, user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/saved/{mid}",
+    params(
+        ("mid" = u64, Path, description = "Message id"),
+    ),
+    responses(
+        (status = 204, description = "Remove a saved message"),
+        (status = 404, description = "Saved message not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn unsave_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(mid): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.unsave_message(mid, user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
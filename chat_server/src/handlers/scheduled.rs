@@ -0,0 +1,49 @@
+use crate::{AppError, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/scheduled-messages",
+    responses(
+        (status = 200, description = "List of pending scheduled messages", body = Vec<ScheduledMessage>),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn list_scheduled_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let scheduled = state.list_scheduled_messages(user.id as _).await?;
+    Ok(Json(scheduled))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/scheduled-messages/{id}",
+    params(
+        ("id" = u64, Path, description = "Scheduled message id"),
+    ),
+    responses(
+        (status = 204, description = "Cancel a scheduled message"),
+        (status = 404, description = "Scheduled message not found", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn cancel_scheduled_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.cancel_scheduled_message(id, user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
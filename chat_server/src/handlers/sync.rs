@@ -0,0 +1,31 @@
+use crate::{AppError, AppState, SyncQuery};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/sync",
+    params(
+        SyncQuery
+    ),
+    responses(
+        (status = 200, description = "Changes since the cursor", body = SyncChanges),
+        (status = 400, description = "Invalid cursor", body = OutputError),
+        (status = 410, description = "Cursor expired, a full sync is needed", body = OutputError),
+    ),
+    security(
+        ("token" = [])
+    )
+)]
+pub(crate) async fn sync_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<SyncQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let changes = state.sync(input, user.id as _).await?;
+    Ok(Json(changes))
+}
@@ -4,11 +4,12 @@ mod handlers;
 mod middlewares;
 mod models;
 mod openapi;
+mod worker;
 
 use anyhow::Context;
 use axum::{
     middleware::from_fn_with_state,
-    routing::{get, patch, post},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use chat_core::{
@@ -23,6 +24,7 @@ pub use models::*;
 use openapi::OpenApiRouter;
 use sqlx::PgPool;
 use std::{fmt, ops::Deref, sync::Arc};
+pub use worker::spawn_workers;
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub inner: Arc<AppStateInner>,
@@ -78,11 +80,52 @@ pub async fn get_router(state: AppState) -> Result<Router, AppError> {
                 .post(send_message_handler),
         )
         .route("/:id/messages", get(list_message_handler))
+        .route("/:id/forward", post(forward_message_handler))
+        .route("/:id/read", post(mark_read_handler))
+        .route("/:id/settings", patch(update_chat_settings_handler))
+        .route("/:id/members", get(list_chat_member_handler))
+        .route("/:id/members/:uid/role", put(update_chat_role_handler))
+        .route("/:id/transfer", post(transfer_chat_handler))
+        .route("/:id/leave", post(leave_chat_handler))
+        .route("/:id/convert", post(convert_chat_handler))
+        .route(
+            "/:id/draft",
+            get(get_draft_handler)
+                .put(save_draft_handler)
+                .delete(delete_draft_handler),
+        )
+        .route("/:id/pins", get(list_pin_handler))
+        .route(
+            "/:id/pins/:mid",
+            post(pin_message_handler).delete(unpin_message_handler),
+        )
+        .route("/:id/polls", post(create_poll_handler))
+        .route("/:id/polls/:pid", get(get_poll_handler))
+        .route("/:id/polls/:pid/votes", post(vote_poll_handler))
         .layer(from_fn_with_state(state.clone(), verify_chat))
+        // non-members join public channels
+        .route("/:id/join", post(join_channel_handler))
         .route("/", get(list_chat_handler).post(create_chat_handler));
     let api = Router::new()
         .route("/users", get(list_chat_handler))
         .nest("/chats", chat)
+        .route("/channels", get(list_channel_handler))
+        .route("/dm/:user_id", get(open_direct_chat_handler))
+        .route("/scheduled-messages", get(list_scheduled_message_handler))
+        .route(
+            "/scheduled-messages/:id",
+            delete(cancel_scheduled_message_handler),
+        )
+        .route("/saved", get(list_saved_handler).post(save_message_handler))
+        .route("/saved/:mid", delete(unsave_message_handler))
+        .route("/inbox", get(list_inbox_handler))
+        .route(
+            "/dnd",
+            get(get_dnd_handler)
+                .put(set_dnd_handler)
+                .delete(clear_dnd_handler),
+        )
+        .route("/sync", get(sync_handler))
         .route("/upload", post(file_upload_handler))
         .route("/files/:ws_id/*path", get(file_download_handler))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use chat_server::{get_router, AppConfig, AppState};
+use chat_server::{get_router, spawn_workers, AppConfig, AppState};
 use tokio::net::TcpListener;
 use tracing::info;
 use tracing_subscriber::{
@@ -15,6 +15,7 @@ async fn main() -> Result<()> {
     let addr = format!("0.0.0.0:{}", config.server.port);
 
     let state = AppState::try_new(config).await?;
+    spawn_workers(state.clone());
     let app = get_router(state).await?;
     let listener = TcpListener::bind(&addr).await?;
     info!("Listening on: {}", addr);
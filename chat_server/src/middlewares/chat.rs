@@ -5,13 +5,21 @@ use axum::{
     response::{IntoResponse, Response},
 };
 use chat_core::User;
+use serde::Deserialize;
+
+// chat routes may carry more path params (e.g. /:id/pins/:mid), only the chat id matters here
+#[derive(Debug, Deserialize)]
+struct ChatParams {
+    id: u64,
+}
 
 #[allow(unused)]
 pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next) -> Response {
     let (mut parts, body) = req.into_parts();
-    let Path(chat_id) = Path::<u64>::from_request_parts(&mut parts, &state)
-        .await
-        .unwrap();
+    let Path(ChatParams { id: chat_id }) =
+        Path::<ChatParams>::from_request_parts(&mut parts, &state)
+            .await
+            .unwrap();
 
     let user = parts.extensions.get::<User>().unwrap();
     if !state
@@ -54,6 +62,7 @@ mod tests {
 
         let app = Router::new()
             .route("/chat/:id/messages", get(handler))
+            .route("/chat/:id/pins/:mid", get(handler))
             .layer(from_fn_with_state(state.clone(), verify_chat))
             .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
             .with_state(state);
@@ -66,6 +75,14 @@ mod tests {
         let res = app.clone().oneshot(req).await?;
         assert_eq!(res.status(), StatusCode::OK);
 
+        // user in chat with nested path params
+        let req = Request::builder()
+            .uri("/chat/1/pins/3")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
         // user not in chat
         let req = Request::builder()
             .uri("/chat/50/messages")
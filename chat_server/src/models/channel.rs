@@ -0,0 +1,275 @@
+use crate::{AppError, AppState, ChatAction};
+use chat_core::{Chat, ChatType, SystemEvent};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Channel {
+    pub id: i64,
+    pub ws_id: i64,
+    pub name: String,
+    pub topic: Option<String>,
+    pub member_count: i64,
+    /// the user is a member of the channel
+    pub joined: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// Public channels of the workspace, joined or not
+    pub async fn list_channels(&self, ws_id: u64, user_id: u64) -> Result<Vec<Channel>, AppError> {
+        let channels = sqlx::query_as(
+            r#"
+            SELECT c.id, c.ws_id, c.name, c.topic,
+              (SELECT COUNT(*) FROM chat_members m WHERE m.chat_id = c.id) AS member_count,
+              EXISTS(SELECT 1 FROM chat_members m WHERE m.chat_id = c.id AND m.user_id = $2) AS joined,
+              c.created_at
+            FROM chats c
+            WHERE c.ws_id = $1 AND c.type = 'public_channel'
+            ORDER BY c.name, c.id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(channels)
+    }
+
+    /// Join a public channel of the workspace of the user, joining twice does nothing.
+    /// Archived channels are read-only and can't be joined.
+    pub async fn join_channel(&self, chat_id: u64, user_id: u64) -> Result<Chat, AppError> {
+        let user = self
+            .find_user_by_id(user_id as _)
+            .await?
+            .ok_or_else(|| AppError::NotFountError(format!("User {} not found", user_id)))?;
+        // private chats are not shown to users outside of them
+        let chat = self
+            .fetch_chat_by_id(chat_id as _)
+            .await?
+            .filter(|c| c.r#type == ChatType::PublicChannel && c.ws_id == user.ws_id)
+            .ok_or_else(|| AppError::NotFountError("Channel not found".into()))?;
+        if chat.members.contains(&user.id) {
+            return Ok(chat);
+        }
+        if chat.archived {
+            return Err(AppError::ChatArchived(chat_id));
+        }
+
+        let body = self
+            .system_message_body(SystemEvent::MemberJoined, user_id)
+            .await?;
+        let mut tx = self.pool.begin().await?;
+        let res = sqlx::query(
+            r#"
+            INSERT INTO chat_members (chat_id, user_id) VALUES ($1, $2)
+            ON CONFLICT (chat_id, user_id) DO NOTHING
+            "#,
+        )
+        .bind(chat.id)
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            // joined by a concurrent request
+            tx.rollback().await?;
+        } else {
+            Self::insert_system_message(&mut *tx, body, chat_id, user_id).await?;
+            tx.commit().await?;
+        }
+
+        self.fetch_chat_by_id(chat_id as _)
+            .await?
+            .ok_or_else(|| AppError::NotFountError("Channel not found".into()))
+    }
+
+    /// Leave a chat, the owner has to transfer the ownership first. Archived chats can be
+    /// left too, without a system message as they are read-only.
+    pub async fn leave_chat(&self, chat_id: u64, user_id: u64) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        Self::authorize_chat(&mut tx, chat_id, user_id, &[ChatAction::Leave]).await?;
+        let body = self
+            .system_message_body(SystemEvent::MemberLeft, user_id)
+            .await?;
+
+        let res = sqlx::query("DELETE FROM chat_members WHERE chat_id = $1 AND user_id = $2")
+            .bind(chat_id as i64)
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Err(AppError::NotFountError(format!(
+                "User {} is not a member of chat {}",
+                user_id, chat_id
+            )));
+        }
+        // the other member of a direct chat opens a new one
+        sqlx::query("UPDATE chats SET dm_pair = NULL WHERE id = $1 AND dm_pair IS NOT NULL")
+            .bind(chat_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        let archived: bool = sqlx::query_scalar("SELECT archived FROM chats WHERE id = $1")
+            .bind(chat_id as i64)
+            .fetch_one(&mut *tx)
+            .await?;
+        if !archived {
+            Self::insert_system_message(&mut *tx, body, chat_id, user_id).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateChat, ListMessages};
+    use anyhow::Result;
+    use chat_core::MessageKind;
+
+    #[tokio::test]
+    async fn list_channels_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateChat {
+            name: Some("announcements".to_string()),
+            public: true,
+            members: vec![1, 2],
+            expires_after: None,
+        };
+        let chat = state.create_chat(&input, 0, 1).await?;
+
+        let channels = state.list_channels(0, 5).await?;
+        assert_eq!(channels.len(), 2);
+        let channel = &channels[0];
+        assert_eq!(channel.id, chat.id);
+        assert_eq!(channel.member_count, 2);
+        assert!(!channel.joined);
+        let channel = &channels[1];
+        assert_eq!(channel.name, "public_chat");
+        assert_eq!(channel.member_count, 5);
+        assert!(channel.joined);
+
+        assert!(state.list_channels(1, 3).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn join_channel_should_add_member_and_system_message() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateChat {
+            name: Some("announcements".to_string()),
+            public: true,
+            members: vec![1, 2],
+            expires_after: None,
+        };
+        let chat = state.create_chat(&input, 0, 1).await?;
+
+        let joined = state.join_channel(chat.id as _, 5).await?;
+        assert_eq!(joined.members, vec![1, 2, 5]);
+        // joining again does nothing
+        assert_eq!(state.join_channel(chat.id as _, 5).await?, joined);
+
+        let page = state
+            .list_messages(ListMessages::default(), chat.id as _)
+            .await?;
+        assert_eq!(page.messages.len(), 1);
+        let message = &page.messages[0];
+        assert_eq!(message.kind, MessageKind::System);
+        assert_eq!(message.sender_id, 5);
+        assert_eq!(message.content, "sayaka joined the channel");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn join_channel_should_reject_private_and_foreign_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // chat 3 is a private channel
+        let err = state.join_channel(3, 5).await.unwrap_err();
+        assert_eq!(err.to_string(), "not fount error: Channel not found");
+        // user 3 is in another workspace
+        let input = CreateChat {
+            name: Some("announcements".to_string()),
+            public: true,
+            members: vec![1, 2],
+            expires_after: None,
+        };
+        let chat = state.create_chat(&input, 0, 1).await?;
+        let err = state.join_channel(chat.id as _, 3).await.unwrap_err();
+        assert_eq!(err.to_string(), "not fount error: Channel not found");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archived_channel_should_not_get_join_or_leave_messages() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateChat {
+            name: Some("announcements".to_string()),
+            public: true,
+            members: vec![1, 2],
+            expires_after: None,
+        };
+        let chat = state.create_chat(&input, 0, 1).await?;
+        let input = crate::UpdateChat {
+            archived: Some(true),
+            ..Default::default()
+        };
+        state.update_chat_by_id(&input, chat.id, 1).await?;
+        let count = || async {
+            let page = state
+                .list_messages(ListMessages::default(), chat.id as _)
+                .await?;
+            anyhow::Ok(page.messages.len())
+        };
+        let messages = count().await?;
+
+        let err = state.join_channel(chat.id as _, 5).await.unwrap_err();
+        assert!(matches!(err, AppError::ChatArchived(id) if id == chat.id as u64));
+        // members can still leave
+        state.leave_chat(chat.id as _, 2).await?;
+        assert!(!state.is_chat_member(chat.id as _, 2).await?);
+        assert_eq!(count().await?, messages);
+        Ok(())
+    }
+
+    // transaction which last wrote the chat row
+    async fn chat_version(state: &AppState, id: i64) -> Result<String> {
+        let version = sqlx::query_scalar("SELECT xmin::text FROM chats WHERE id = $1")
+            .bind(id)
+            .fetch_one(&state.pool)
+            .await?;
+        Ok(version)
+    }
+
+    #[tokio::test]
+    async fn leave_chat_should_remove_member() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let before = chat_version(&state, 2).await?;
+        // user 2 is an admin of chat 2, a group without a direct chat pair to clear
+        state.leave_chat(2, 2).await?;
+        assert_eq!(chat_version(&state, 2).await?, before);
+        assert!(!state.is_chat_member(2, 2).await?);
+        assert_eq!(
+            state.get_chat_role(2, 2).await?,
+            chat_core::ChatRole::Member
+        );
+        let page = state.list_messages(ListMessages::default(), 2).await?;
+        assert_eq!(page.messages[0].content, "yotsuha left the chat");
+
+        let err = state.leave_chat(2, 2).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "not fount error: User 2 is not a member of chat 2"
+        );
+        let err = state.leave_chat(2, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: owner can't leave the chat before transferring the ownership"
+        );
+        Ok(())
+    }
+}
@@ -1,55 +1,254 @@
-use crate::{AppError, AppState};
-use chat_core::{Chat, ChatType};
-use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use crate::{AppError, AppState, ChatAction, ChatFile, NotifyLevel};
+use chat_core::{Chat, ChatRole, ChatType, SystemEvent};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::{FromRow, PgExecutor, Postgres, QueryBuilder};
+use std::str::FromStr;
 use utoipa::{IntoParams, ToSchema};
+
+// longest wait between two messages of a member in slow mode, 6 hours
+const MAX_SLOW_MODE: u64 = 6 * 3600;
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema, IntoParams)]
 pub struct CreateChat {
     pub name: Option<String>,
     pub public: bool,
     pub members: Vec<i64>,
+    /// seconds after which messages of the chat expire
+    #[serde(default)]
+    pub expires_after: Option<u64>,
 }
 
-#[derive(Debug, Clone, FromRow, Serialize, ToSchema, Deserialize, PartialEq, IntoParams)]
+#[derive(
+    Debug, Clone, Default, FromRow, Serialize, ToSchema, Deserialize, PartialEq, IntoParams,
+)]
 pub struct UpdateChat {
+    /// null clears the name, the chat keeps its name if not given
+    #[serde(default, deserialize_with = "double_option")]
     pub name: Option<Option<String>>,
-    pub public: Option<bool>,
     pub members: Option<Vec<i64>>,
+    /// seconds after which messages of the chat expire, 0 to stop expiring messages
+    pub expires_after: Option<u64>,
+    /// an empty string clears the topic
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// an empty string clears the description
+    #[serde(default)]
+    pub description: Option<String>,
+    /// url of an uploaded file, an empty string clears the avatar
+    #[serde(default)]
+    pub avatar: Option<String>,
+    /// archive the chat, or restore it with false
+    #[serde(default)]
+    pub archived: Option<bool>,
+    /// only owners and admins can post when on
+    #[serde(default)]
+    pub announcement: Option<bool>,
+    /// seconds a member waits between two messages, 0 to turn slow mode off
+    #[serde(default)]
+    pub slow_mode: Option<u64>,
+    /// no longer supported, the chat type is changed by the convert api
+    #[serde(default)]
+    pub public: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct ConvertChat {
+    /// a group converts to a private channel, channels between private and public
+    #[serde(rename = "type")]
+    pub r#type: ChatType,
+    /// name of the channel, required if the chat has none
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+// topic, description and avatar of a chat
+#[derive(Debug, PartialEq)]
+struct ChatDetails {
+    topic: Option<String>,
+    description: Option<String>,
+    avatar: Option<String>,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
 pub struct ChatUser {
+    pub id: i64,
+    pub ws_id: i64,
     pub name: String,
     pub email: String,
 }
 
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct UserChat {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub chat: Chat,
+    /// notification preference of the user, none for public channels not joined
+    pub notify: Option<NotifyLevel>,
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
 #[allow(unused)]
 impl AppState {
-    pub async fn create_chat(&self, input: &CreateChat, ws_id: i64) -> Result<Chat, AppError> {
+    /// Create a chat, its creator becomes the owner. Two users have a single direct chat,
+    /// creating it again returns the existing one
+    pub async fn create_chat(
+        &self,
+        input: &CreateChat,
+        ws_id: i64,
+        user_id: i64,
+    ) -> Result<Chat, AppError> {
+        let (chat, _) = self.create_or_open_chat(input, ws_id, user_id).await?;
+        Ok(chat)
+    }
+
+    /// Same as `create_chat`, also tells if the chat was created
+    pub async fn create_or_open_chat(
+        &self,
+        input: &CreateChat,
+        ws_id: i64,
+        user_id: i64,
+    ) -> Result<(Chat, bool), AppError> {
         Self::validate_chat(&input.members, &input.name)?;
+        let expires_after = match input.expires_after {
+            Some(0) => {
+                return Err(AppError::ChatValidateError(
+                    "Expires after should be greater than 0".into(),
+                ))
+            }
+            v => Self::expires_after_secs(v)?,
+        };
+
+        if !input.members.contains(&user_id) {
+            return Err(AppError::ChatValidateError(
+                "The creator should be a member of the chat".into(),
+            ));
+        }
 
         let users = ChatUser::fetch_by_ids(&input.members, &self.pool).await?;
-        Self::validate_chat_users(&users, &input.members)?;
+        Self::validate_chat_users(&users, &input.members, ws_id)?;
 
         let chat_type = Self::determine_chat_type(input.name.as_ref(), users.len(), input.public);
+        let dm_pair = Self::dm_pair(chat_type, &input.members);
+        if let Some(pair) = &dm_pair {
+            if let Some(chat) = self.find_direct_chat(ws_id, pair).await? {
+                return Ok((chat, false));
+            }
+        }
 
-        let chat = sqlx::query_as(
+        let mut tx = self.pool.begin().await?;
+        let id: Option<i64> = sqlx::query_scalar(
             r#"
-            INSERT INTO chats (ws_id, name, type, members)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, name, type, members, created_at
+            INSERT INTO chats (ws_id, name, type, expires_after, dm_pair)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (ws_id, dm_pair) WHERE dm_pair IS NOT NULL DO NOTHING
+            RETURNING id
             "#,
         )
         .bind(ws_id)
         .bind(&input.name)
         .bind(chat_type)
+        .bind(expires_after)
+        .bind(&dm_pair)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(id) = id else {
+            // the direct chat was created by a concurrent request
+            tx.rollback().await?;
+            let pair = dm_pair.unwrap_or_default();
+            let chat = self
+                .find_direct_chat(ws_id, &pair)
+                .await?
+                .ok_or_else(|| AppError::NotFountError("Chat not found".into()))?;
+            return Ok((chat, false));
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO chat_members (chat_id, user_id, role)
+            SELECT $1, m, CASE WHEN m = $3 THEN 'owner'::chat_role ELSE 'member'::chat_role END
+            FROM UNNEST($2::bigint[]) m
+            "#,
+        )
+        .bind(id)
         .bind(&input.members)
-        .fetch_one(&self.pool)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        let chat = Self::fetch_chat(&mut *tx, id).await?;
+        tx.commit().await?;
+
+        Ok((chat, true))
+    }
+
+    /// Open the direct chat of the user with another user of the workspace, created if needed
+    pub async fn open_direct_chat(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+        other_id: i64,
+    ) -> Result<(Chat, bool), AppError> {
+        if user_id == other_id {
+            return Err(AppError::ChatValidateError(
+                "A direct chat needs another user".into(),
+            ));
+        }
+        let input = CreateChat {
+            name: None,
+            public: false,
+            members: vec![user_id, other_id],
+            expires_after: None,
+        };
+        self.create_or_open_chat(&input, ws_id, user_id).await
+    }
+
+    pub async fn find_direct_chat(
+        &self,
+        ws_id: i64,
+        pair: &[i64],
+    ) -> Result<Option<Chat>, AppError> {
+        let chat = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, chat_member_ids(id) AS members, topic, description, avatar,
+              archived, announcement, slow_mode, expires_after, created_at
+            FROM chats
+            WHERE ws_id = $1 AND dm_pair = $2
+            "#,
+        )
+        .bind(ws_id)
+        .bind(pair)
+        .fetch_optional(&self.pool)
         .await?;
 
         Ok(chat)
     }
 
+    // the avatar is a file uploaded in the workspace of the chat
+    fn verify_chat_avatar(&self, url: &str, ws_id: i64) -> Result<(), AppError> {
+        let file = ChatFile::from_str(url)?;
+        if file.ws_id != ws_id as u64 {
+            return Err(AppError::ChatValidateError(format!(
+                "Avatar {} is not a file of workspace {}",
+                url, ws_id
+            )));
+        }
+        if !file.path(&self.config.server.base_url).exists() {
+            return Err(AppError::ChatValidateError(format!(
+                "Avatar {} doesn't exist",
+                url
+            )));
+        }
+        Ok(())
+    }
+
+    // the ordered members of a direct chat, identifying it in the workspace
+    fn dm_pair(chat_type: ChatType, members: &[i64]) -> Option<Vec<i64>> {
+        if chat_type != ChatType::Single || members.len() != 2 {
+            return None;
+        }
+        let mut pair = members.to_vec();
+        pair.sort_unstable();
+        Some(pair)
+    }
+
     fn validate_chat(members: &[i64], name: &Option<String>) -> Result<(), AppError> {
         if members.len() < 2 {
             return Err(AppError::ChatValidateError(
@@ -66,47 +265,420 @@ impl AppState {
         Ok(())
     }
 
-    fn validate_chat_users(users: &[ChatUser], members: &[i64]) -> Result<(), AppError> {
+    fn validate_chat_users(
+        users: &[ChatUser],
+        members: &[i64],
+        ws_id: i64,
+    ) -> Result<(), AppError> {
         if users.len() != members.len() {
             return Err(AppError::ChatValidateError(
                 "Some members do not exist".into(),
             ));
         }
 
+        let mut foreign: Vec<_> = users
+            .iter()
+            .filter(|u| u.ws_id != ws_id)
+            .map(|u| u.id)
+            .collect();
+        if !foreign.is_empty() {
+            foreign.sort_unstable();
+            return Err(AppError::ChatValidateError(format!(
+                "Members {:?} are not in workspace {}",
+                foreign, ws_id
+            )));
+        }
+
         Ok(())
     }
 
-    pub async fn update_chat_by_id(&self, input: &UpdateChat, id: i64) -> Result<Chat, AppError> {
+    fn expires_after_secs(expires_after: Option<u64>) -> Result<Option<i64>, AppError> {
+        expires_after
+            .map(|v| {
+                i64::try_from(v).map_err(|_| {
+                    AppError::ChatValidateError(format!("Invalid expires after: {}", v))
+                })
+            })
+            .transpose()
+    }
+
+    /// Update the chat, the changes are checked against the role of the user
+    pub async fn update_chat_by_id(
+        &self,
+        input: &UpdateChat,
+        id: i64,
+        user_id: i64,
+    ) -> Result<Chat, AppError> {
         let chat = self
             .fetch_chat_by_id(id)
             .await?
             .ok_or_else(|| AppError::NotFountError("Chat not found".into()))?;
+        // archived chats are read-only, restoring them is the only allowed change
+        if chat.archived && input.archived != Some(false) {
+            return Err(AppError::ChatArchived(id as _));
+        }
+        if input.public.is_some() {
+            return Err(AppError::ChatConversionError(
+                "Use the convert api to change the chat type".into(),
+            ));
+        }
+
+        // only the given fields change, the type changes through a conversion only
+        let name = input.name.clone().unwrap_or_else(|| chat.name.clone());
+        if name.is_none() && Self::is_channel(chat.r#type) {
+            return Err(AppError::ChatValidateError(
+                "A channel should have a name".into(),
+            ));
+        }
 
+        // the chat stays in its workspace, new members have to belong to it
         if let Some(members) = &input.members {
-            Self::validate_chat(members, &chat.name)?;
-            let users = ChatUser::fetch_by_ids(members, &self.pool).await?;
-            Self::validate_chat_users(&users, members)?;
+            if chat.r#type == ChatType::Single && members.len() != 2 {
+                return Err(AppError::ChatValidateError(
+                    "A direct chat should have 2 members".into(),
+                ));
+            }
+            Self::validate_chat(members, &name)?;
+            let added: Vec<_> = members
+                .iter()
+                .copied()
+                .filter(|m| !chat.members.contains(m))
+                .collect();
+            let users = ChatUser::fetch_by_ids(&added, &self.pool).await?;
+            Self::validate_chat_users(&users, &added, chat.ws_id)?;
         }
 
-        let chat_type = Self::determine_updated_chat_type(input, &chat);
+        // keep the current setting if not given, 0 stops expiring messages
+        let expires_after = match input.expires_after {
+            None => chat.expires_after,
+            Some(0) => None,
+            v => Self::expires_after_secs(v)?,
+        };
+        let slow_mode = match input.slow_mode {
+            None => chat.slow_mode,
+            Some(0) => None,
+            Some(v) if v <= MAX_SLOW_MODE => Some(v as i64),
+            Some(v) => {
+                return Err(AppError::ChatValidateError(format!(
+                    "Invalid slow mode: {}",
+                    v
+                )))
+            }
+        };
+        let announcement = input.announcement.unwrap_or(chat.announcement);
 
-        let updated_chat = sqlx::query_as(
-            r#"
-            UPDATE chats SET name = $1, type = $2, members = $3
-            WHERE id = $4
-            RETURNING id, ws_id, name, type, members, created_at
-            "#,
-        )
-        .bind(&input.name)
-        .bind(chat_type)
-        .bind(&input.members)
-        .bind(id)
-        .fetch_one(&self.pool)
-        .await?;
+        let details = ChatDetails::updated(input, &chat);
+        if let Some(avatar) = details
+            .avatar
+            .as_ref()
+            .filter(|a| chat.avatar.as_ref() != Some(*a))
+        {
+            self.verify_chat_avatar(avatar, chat.ws_id)?;
+        }
+        let archived = input.archived.unwrap_or(chat.archived);
+
+        let actions = Self::chat_update_actions(
+            input,
+            &chat,
+            &name,
+            (expires_after, slow_mode, announcement),
+            user_id,
+        );
+        let mut tx = self.pool.begin().await?;
+        Self::authorize_chat(&mut tx, id as _, user_id as _, &actions).await?;
+        if let Some(members) = &input.members {
+            let owner = self
+                .list_chat_members(id as _)
+                .await?
+                .into_iter()
+                .find(|m| m.role == ChatRole::Owner);
+            if let Some(owner) =
+                owner.filter(|o| o.user_id != user_id && !members.contains(&o.user_id))
+            {
+                return Err(AppError::PermissionDenied(format!(
+                    "The owner {} can't be removed",
+                    owner.user_id
+                )));
+            }
+        }
+
+        let dm_pair = Self::dm_pair(chat.r#type, input.members.as_ref().unwrap_or(&chat.members));
+        if let Some(pair) = &dm_pair {
+            if let Some(dm) = self
+                .find_direct_chat(chat.ws_id, pair)
+                .await?
+                .filter(|dm| dm.id != id)
+            {
+                return Err(AppError::ChatValidateError(format!(
+                    "Users {:?} already have the direct chat {}",
+                    pair, dm.id
+                )));
+            }
+        }
+
+        let mut bodies = vec![];
+        for event in Self::chat_update_events(input, &chat, &name, &details, user_id) {
+            bodies.push(self.system_message_body(event, user_id as _).await?);
+        }
+
+        // write the given fields only, a concurrent update of the others is kept
+        let mut query = QueryBuilder::<Postgres>::new("UPDATE chats SET ");
+        let mut fields = query.separated(", ");
+        let mut written = 0;
+        if input.name.is_some() {
+            fields.push("name = ").push_bind_unseparated(&name);
+            written += 1;
+        }
+        if input.members.is_some() {
+            fields.push("dm_pair = ").push_bind_unseparated(&dm_pair);
+            written += 1;
+        }
+        if input.expires_after.is_some() {
+            fields
+                .push("expires_after = ")
+                .push_bind_unseparated(expires_after);
+            written += 1;
+        }
+        if input.topic.is_some() {
+            fields
+                .push("topic = ")
+                .push_bind_unseparated(&details.topic);
+            written += 1;
+        }
+        if input.description.is_some() {
+            fields
+                .push("description = ")
+                .push_bind_unseparated(&details.description);
+            written += 1;
+        }
+        if input.avatar.is_some() {
+            fields
+                .push("avatar = ")
+                .push_bind_unseparated(&details.avatar);
+            written += 1;
+        }
+        if input.archived.is_some() {
+            fields.push("archived = ").push_bind_unseparated(archived);
+            written += 1;
+        }
+        if input.announcement.is_some() {
+            fields
+                .push("announcement = ")
+                .push_bind_unseparated(announcement);
+            written += 1;
+        }
+        if input.slow_mode.is_some() {
+            fields.push("slow_mode = ").push_bind_unseparated(slow_mode);
+            written += 1;
+        }
+
+        // an update without fields writes nothing
+        if written > 0 {
+            query.push(" WHERE id = ").push_bind(id);
+            query.build().execute(&mut *tx).await?;
+        }
+        if let Some(members) = &input.members {
+            // removed members lose their role and settings, new members join as members
+            sqlx::query("DELETE FROM chat_members WHERE chat_id = $1 AND user_id <> ALL($2)")
+                .bind(id)
+                .bind(members)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(
+                r#"
+                INSERT INTO chat_members (chat_id, user_id)
+                SELECT $1, m FROM UNNEST($2::bigint[]) m
+                ON CONFLICT (chat_id, user_id) DO NOTHING
+                "#,
+            )
+            .bind(id)
+            .bind(members)
+            .execute(&mut *tx)
+            .await?;
+        }
+        for body in bodies {
+            Self::insert_system_message(&mut *tx, body, id as _, user_id as _).await?;
+        }
+        let updated_chat = Self::fetch_chat(&mut *tx, id).await?;
+        tx.commit().await?;
 
         Ok(updated_chat)
     }
 
+    /// Convert the chat to another type: a group becomes a private channel, channels switch
+    /// between private and public. Direct chats keep their type
+    pub async fn convert_chat(
+        &self,
+        input: &ConvertChat,
+        id: i64,
+        user_id: i64,
+    ) -> Result<Chat, AppError> {
+        let chat = self
+            .fetch_chat_by_id(id)
+            .await?
+            .ok_or_else(|| AppError::NotFountError("Chat not found".into()))?;
+        if chat.archived {
+            return Err(AppError::ChatArchived(id as _));
+        }
+        if chat.r#type == input.r#type {
+            return Ok(chat);
+        }
+        if !Self::can_convert(chat.r#type, input.r#type) {
+            return Err(AppError::ChatConversionError(format!(
+                "A {} can't be converted to a {}",
+                chat.r#type, input.r#type
+            )));
+        }
+        let name = input
+            .name
+            .clone()
+            .filter(|n| !n.is_empty())
+            .or_else(|| chat.name.clone())
+            .ok_or_else(|| AppError::ChatConversionError("A channel should have a name".into()))?;
+
+        let mut actions = vec![ChatAction::ChangeType];
+        if chat.name.as_ref() != Some(&name) {
+            actions.push(ChatAction::Rename);
+        }
+        let mut tx = self.pool.begin().await?;
+        Self::authorize_chat(&mut tx, id as _, user_id as _, &actions).await?;
+
+        let mut events = vec![];
+        if chat.name.as_ref() != Some(&name) {
+            events.push(SystemEvent::ChatRenamed {
+                name: Some(name.clone()),
+            });
+        }
+        events.push(SystemEvent::ChatConverted {
+            chat_type: input.r#type,
+        });
+        let mut bodies = vec![];
+        for event in events {
+            bodies.push(self.system_message_body(event, user_id as _).await?);
+        }
+
+        // a concurrent conversion changed the type first
+        let res = sqlx::query("UPDATE chats SET type = $1, name = $2 WHERE id = $3 AND type = $4")
+            .bind(input.r#type)
+            .bind(&name)
+            .bind(id)
+            .bind(chat.r#type)
+            .execute(&mut *tx)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Err(AppError::ChatConversionError(format!(
+                "Chat {} is no longer a {}",
+                id, chat.r#type
+            )));
+        }
+        for body in bodies {
+            Self::insert_system_message(&mut *tx, body, id as _, user_id as _).await?;
+        }
+        let converted = Self::fetch_chat(&mut *tx, id).await?;
+        tx.commit().await?;
+
+        Ok(converted)
+    }
+
+    // the allowed conversions, chats never become direct chats or groups again
+    fn can_convert(from: ChatType, to: ChatType) -> bool {
+        matches!(
+            (from, to),
+            (ChatType::Group, ChatType::PrivateChannel)
+                | (ChatType::PrivateChannel, ChatType::PublicChannel)
+                | (ChatType::PublicChannel, ChatType::PrivateChannel)
+        )
+    }
+
+    // events of the update shown in the chat timeline as system messages
+    fn chat_update_events(
+        input: &UpdateChat,
+        chat: &Chat,
+        name: &Option<String>,
+        details: &ChatDetails,
+        user_id: i64,
+    ) -> Vec<SystemEvent> {
+        let mut events = vec![];
+        if *name != chat.name {
+            events.push(SystemEvent::ChatRenamed { name: name.clone() });
+        }
+        if details.topic != chat.topic {
+            events.push(SystemEvent::TopicChanged {
+                topic: details.topic.clone(),
+            });
+        }
+        if let Some(members) = &input.members {
+            let added: Vec<_> = members
+                .iter()
+                .copied()
+                .filter(|m| !chat.members.contains(m))
+                .collect();
+            if !added.is_empty() {
+                events.push(SystemEvent::MembersAdded { user_ids: added });
+            }
+            let removed: Vec<_> = chat
+                .members
+                .iter()
+                .copied()
+                .filter(|m| !members.contains(m) && *m != user_id)
+                .collect();
+            if !removed.is_empty() {
+                events.push(SystemEvent::MembersRemoved { user_ids: removed });
+            }
+            if chat.members.contains(&user_id) && !members.contains(&user_id) {
+                events.push(SystemEvent::MemberLeft);
+            }
+        }
+        events
+    }
+
+    // actions done by the update, members removing only themselves leave the chat
+    fn chat_update_actions(
+        input: &UpdateChat,
+        chat: &Chat,
+        name: &Option<String>,
+        settings: (Option<i64>, Option<i64>, bool),
+        user_id: i64,
+    ) -> Vec<ChatAction> {
+        let mut actions = vec![];
+        if *name != chat.name {
+            actions.push(ChatAction::Rename);
+        }
+        if settings != (chat.expires_after, chat.slow_mode, chat.announcement) {
+            actions.push(ChatAction::ChangeSettings);
+        }
+        if ChatDetails::updated(input, chat) != ChatDetails::of(chat) {
+            actions.push(ChatAction::EditDetails);
+        }
+        if input.archived.is_some_and(|a| a != chat.archived) {
+            actions.push(ChatAction::Archive);
+        }
+        if let Some(members) = &input.members {
+            if members.iter().any(|m| !chat.members.contains(m)) {
+                actions.push(ChatAction::AddMembers);
+            }
+            let removed: Vec<_> = chat
+                .members
+                .iter()
+                .filter(|m| !members.contains(m))
+                .collect();
+            if removed.iter().any(|m| **m != user_id) {
+                actions.push(ChatAction::RemoveMembers);
+            }
+            if removed.contains(&&user_id) {
+                actions.push(ChatAction::Leave);
+            }
+        }
+        actions
+    }
+
+    fn is_channel(chat_type: ChatType) -> bool {
+        matches!(
+            chat_type,
+            ChatType::PrivateChannel | ChatType::PublicChannel
+        )
+    }
+
     fn determine_chat_type(name: Option<&String>, user_count: usize, public: bool) -> ChatType {
         match (name, user_count) {
             (None, 2) => ChatType::Single,
@@ -121,44 +693,14 @@ impl AppState {
         }
     }
 
-    fn determine_updated_chat_type(input: &UpdateChat, chat: &Chat) -> ChatType {
-        match (&input.members, &input.public) {
-            (Some(members), _) => match (input.name.as_ref(), members.len()) {
-                (None, 2) => ChatType::Single,
-                (None, _) => ChatType::Group,
-                (Some(_), _) => {
-                    if let Some(public) = input.public {
-                        if public {
-                            ChatType::PublicChannel
-                        } else {
-                            ChatType::PrivateChannel
-                        }
-                    } else {
-                        chat.r#type
-                    }
-                }
-            },
-            (None, _) => match (input.name.as_ref(), chat.members.len()) {
-                (None, 2) => ChatType::Single,
-                (None, _) => ChatType::Group,
-                (Some(_), _) => {
-                    if let Some(public) = input.public {
-                        if public {
-                            ChatType::PublicChannel
-                        } else {
-                            ChatType::PrivateChannel
-                        }
-                    } else {
-                        chat.r#type
-                    }
-                }
-            },
-        }
-    }
-
     pub async fn fetch_chat_by_id(&self, id: i64) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as::<_, Chat>(
-            "SELECT id, ws_id, name, type, members, created_at FROM chats WHERE id = $1",
+            r#"
+            SELECT id, ws_id, name, type, chat_member_ids(id) AS members, topic, description, avatar,
+              archived, announcement, slow_mode, expires_after, created_at
+            FROM chats
+            WHERE id = $1
+            "#,
         )
         .bind(id)
         .fetch_optional(&self.pool)
@@ -167,27 +709,66 @@ impl AppState {
         Ok(chat)
     }
 
-    pub async fn fetch_chats_by_ws_id(&self, ws_id: i64) -> Result<Vec<Chat>, AppError> {
-        let chats = sqlx::query_as::<_, Chat>(
-            "SELECT id, ws_id, name, type, members, created_at FROM chats WHERE ws_id = $1",
+    // the chat as seen by the transaction which changed it
+    async fn fetch_chat<'e>(executor: impl PgExecutor<'e>, id: i64) -> Result<Chat, AppError> {
+        let chat = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, chat_member_ids(id) AS members, topic, description, avatar,
+              archived, announcement, slow_mode, expires_after, created_at
+            FROM chats
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(executor)
+        .await?;
+
+        Ok(chat)
+    }
+
+    /// Chats of the workspace visible to the user: the chats the user is a member of, and
+    /// every public channel
+    pub async fn fetch_chats_by_ws_id(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+    ) -> Result<Vec<UserChat>, AppError> {
+        let chats = sqlx::query_as::<_, UserChat>(
+            r#"
+            SELECT c.id, c.ws_id, c.name, c.type, chat_member_ids(c.id) AS members, c.topic,
+              c.description, c.avatar, c.archived, c.announcement, c.slow_mode,
+              c.expires_after, c.created_at,
+              cm.notify, cm.muted_until
+            FROM chats c
+            LEFT JOIN chat_members cm ON cm.chat_id = c.id AND cm.user_id = $2
+            WHERE c.ws_id = $1 AND (cm.user_id IS NOT NULL OR c.type = 'public_channel')
+            ORDER BY c.id
+            "#,
         )
         .bind(ws_id)
+        .bind(user_id)
         .fetch_all(&self.pool)
         .await?;
 
         Ok(chats)
     }
 
-    //TODO: delete chat should also delete messages
-    pub async fn delete_chat_by_id(&self, id: i64) -> Result<(), AppError> {
+    /// Delete the chat for every member, only owners and admins can do it
+    pub async fn delete_chat_by_id(&self, id: i64, user_id: i64) -> Result<(), AppError> {
+        if self.fetch_chat_by_id(id).await?.is_none() {
+            return Err(AppError::NotFountError("Chat not found".into()));
+        }
+        let mut tx = self.pool.begin().await?;
+        Self::authorize_chat(&mut tx, id as _, user_id as _, &[ChatAction::Delete]).await?;
         let res = sqlx::query("DELETE FROM chats WHERE id = $1")
             .bind(id)
-            .execute(&self.pool)
+            .execute(&mut *tx)
             .await?;
 
         if res.rows_affected() == 0 {
             return Err(AppError::NotFountError("Chat not found".into()));
         }
+        tx.commit().await?;
 
         Ok(())
     }
@@ -196,8 +777,8 @@ impl AppState {
         let is_member = sqlx::query(
             r#"
             SELECT 1
-            FROM chats
-            WHERE id = $1 AND $2 = ANY(members)
+            FROM chat_members
+            WHERE chat_id = $1 AND user_id = $2
             "#,
         )
         .bind(chat_id as i64)
@@ -209,14 +790,48 @@ impl AppState {
     }
 }
 
+// a null field is Some(None) while a missing one is None
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl ChatDetails {
+    fn of(chat: &Chat) -> Self {
+        Self {
+            topic: chat.topic.clone(),
+            description: chat.description.clone(),
+            avatar: chat.avatar.clone(),
+        }
+    }
+
+    // details not given are kept, empty ones are cleared
+    fn updated(input: &UpdateChat, chat: &Chat) -> Self {
+        let update = |new: &Option<String>, old: &Option<String>| match new {
+            None => old.clone(),
+            Some(v) if v.is_empty() => None,
+            Some(v) => Some(v.clone()),
+        };
+        Self {
+            topic: update(&input.topic, &chat.topic),
+            description: update(&input.description, &chat.description),
+            avatar: update(&input.avatar, &chat.avatar),
+        }
+    }
+}
+
 #[allow(unused)]
 impl ChatUser {
     pub async fn fetch_by_ids(ids: &[i64], pool: &sqlx::PgPool) -> Result<Vec<Self>, AppError> {
-        let users =
-            sqlx::query_as::<_, ChatUser>("SELECT id, name, email FROM users WHERE id = ANY($1)")
-                .bind(ids)
-                .fetch_all(pool)
-                .await?;
+        let users = sqlx::query_as::<_, ChatUser>(
+            "SELECT id, ws_id, name, email FROM users WHERE id = ANY($1)",
+        )
+        .bind(ids)
+        .fetch_all(pool)
+        .await?;
 
         Ok(users)
     }
@@ -238,6 +853,7 @@ mod tests {
                 name,
                 members: members.to_vec(),
                 public,
+                expires_after: None,
             }
         }
     }
@@ -246,35 +862,155 @@ mod tests {
     async fn create_chat_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         //group chat
-        let create_chat = CreateChat::new("", &[1, 2, 3], false);
-        let chat = state.create_chat(&create_chat, 1).await?;
+        let create_chat = CreateChat::new("", &[1, 2, 5], false);
+        let chat = state.create_chat(&create_chat, 0, 1).await?;
         assert_eq!(chat.name, None);
         assert_eq!(chat.members, create_chat.members);
         assert_eq!(chat.r#type, ChatType::Group);
 
         //single chat
-        let create_chat = CreateChat::new("", &[1, 2], false);
-        let chat = state.create_chat(&create_chat, 1).await?;
+        let create_chat = CreateChat::new("", &[1, 5], false);
+        let chat = state.create_chat(&create_chat, 0, 1).await?;
         assert_eq!(chat.name, None);
         assert_eq!(chat.members, create_chat.members);
         assert_eq!(chat.r#type, ChatType::Single);
 
         //private channel
-        let create_chat = CreateChat::new("test chat", &[1, 2, 3], false);
-        let chat = state.create_chat(&create_chat, 1).await?;
+        let create_chat = CreateChat::new("test chat", &[1, 2, 5], false);
+        let chat = state.create_chat(&create_chat, 0, 1).await?;
         assert_eq!(chat.name, create_chat.name);
         assert_eq!(chat.members, create_chat.members);
         assert_eq!(chat.r#type, ChatType::PrivateChannel);
 
         //public channel
-        let create_chat = CreateChat::new("test chat", &[1, 2, 3], true);
-        let chat = state.create_chat(&create_chat, 1).await?;
+        let create_chat = CreateChat::new("test chat", &[1, 2, 5], true);
+        let chat = state.create_chat(&create_chat, 0, 1).await?;
         assert_eq!(chat.name, create_chat.name);
         assert_eq!(chat.members, create_chat.members);
         assert_eq!(chat.r#type, ChatType::PublicChannel);
         Ok(())
     }
 
+    #[tokio::test]
+    async fn create_chat_should_stay_in_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // user 3 is in workspace 1
+        let err = state
+            .create_chat(&CreateChat::new("", &[1, 2, 3], false), 0, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create chat error: Members [3] are not in workspace 0"
+        );
+
+        let err = state
+            .create_chat(&CreateChat::new("", &[2, 5], false), 0, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create chat error: The creator should be a member of the chat"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_by_id_should_stay_in_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let chat = state
+            .create_chat(&CreateChat::new("", &[1, 2, 5], false), 0, 1)
+            .await?;
+        let update_chat = UpdateChat {
+            members: Some(vec![1, 2, 3, 4, 5]),
+            ..Default::default()
+        };
+        let err = state
+            .update_chat_by_id(&update_chat, chat.id, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create chat error: Members [3, 4] are not in workspace 0"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_chat_should_return_existing_direct_chat() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // chat 1 is the direct chat of users 1 and 2
+        let (chat, created) = state
+            .create_or_open_chat(&CreateChat::new("", &[2, 1], false), 0, 2)
+            .await?;
+        assert_eq!(chat.id, 1);
+        assert!(!created);
+
+        let (chat, created) = state
+            .create_or_open_chat(&CreateChat::new("", &[1, 5], false), 0, 1)
+            .await?;
+        assert!(created);
+        let (dm, created) = state.open_direct_chat(0, 5, 1).await?;
+        assert_eq!(dm, chat);
+        assert!(!created);
+
+        let err = state.open_direct_chat(0, 5, 5).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create chat error: A direct chat needs another user"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_direct_chats_should_be_deduplicated() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (a, b) = tokio::join!(
+            state.open_direct_chat(0, 2, 5),
+            state.open_direct_chat(0, 5, 2)
+        );
+        let ((a, a_created), (b, b_created)) = (a?, b?);
+        assert_eq!(a.id, b.id);
+        assert!(a_created ^ b_created);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_by_id_should_not_duplicate_direct_chat() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // chat 1 is the direct chat of users 1 and 2
+        let (dm, _) = state.open_direct_chat(0, 1, 5).await?;
+        let update_chat = UpdateChat {
+            members: Some(vec![1, 5]),
+            ..Default::default()
+        };
+        let err = state
+            .update_chat_by_id(&update_chat, 1, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "create chat error: Users [1, 5] already have the direct chat {}",
+                dm.id
+            )
+        );
+        // a group of two is not a direct chat
+        let update_chat = UpdateChat {
+            members: Some(vec![1, 2]),
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(&update_chat, 6, 1).await?;
+        assert_eq!(chat.r#type, ChatType::Group);
+
+        // a direct chat left by a member can be opened again
+        state.leave_chat(1, 2).await?;
+        let (chat, created) = state.open_direct_chat(0, 2, 1).await?;
+        assert_ne!(chat.id, 1);
+        assert!(created);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn fetch_chat_by_id_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -289,9 +1025,10 @@ mod tests {
     #[tokio::test]
     async fn fetch_all_chats_by_ws_id_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let fetched_chats = state.fetch_chats_by_ws_id(0).await?;
+        let fetched_chats = state.fetch_chats_by_ws_id(0, 1).await?;
         assert_eq!(fetched_chats.len(), 6);
-        let chat = &fetched_chats[0];
+        assert_eq!(fetched_chats[0].notify, Some(NotifyLevel::All));
+        let chat = &fetched_chats[0].chat;
         assert_eq!(chat.id, 1);
         assert_eq!(chat.name, Some("single_chat".into()));
         assert_eq!(chat.r#type, ChatType::Single);
@@ -299,29 +1036,547 @@ mod tests {
         Ok(())
     }
 
+    fn chat_ids(chats: &[UserChat]) -> Vec<i64> {
+        chats.iter().map(|c| c.chat.id).collect()
+    }
+
+    #[tokio::test]
+    async fn fetch_chats_by_ws_id_should_hide_single_chats_of_others() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let chat = state
+            .create_chat(&CreateChat::new("", &[2, 5], false), 0, 2)
+            .await?;
+        assert_eq!(chat.r#type, ChatType::Single);
+
+        let chats = state.fetch_chats_by_ws_id(0, 5).await?;
+        assert!(chat_ids(&chats).contains(&chat.id));
+        // user 3 is in none of the single chats
+        let chats = state.fetch_chats_by_ws_id(0, 3).await?;
+        assert_eq!(chat_ids(&chats), vec![2, 3, 4, 6]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_chats_by_ws_id_should_hide_groups_of_others() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let chat = state
+            .create_chat(&CreateChat::new("", &[1, 2, 5], false), 0, 1)
+            .await?;
+        assert_eq!(chat.r#type, ChatType::Group);
+
+        let chats = state.fetch_chats_by_ws_id(0, 5).await?;
+        assert!(chat_ids(&chats).contains(&chat.id));
+        let chats = state.fetch_chats_by_ws_id(0, 3).await?;
+        assert!(!chat_ids(&chats).contains(&chat.id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_chats_by_ws_id_should_hide_private_channels_of_others() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let chat = state
+            .create_chat(&CreateChat::new("secret", &[1, 2, 5], false), 0, 1)
+            .await?;
+        assert_eq!(chat.r#type, ChatType::PrivateChannel);
+
+        let chats = state.fetch_chats_by_ws_id(0, 5).await?;
+        assert!(chat_ids(&chats).contains(&chat.id));
+        let chats = state.fetch_chats_by_ws_id(0, 3).await?;
+        assert!(!chat_ids(&chats).contains(&chat.id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_chats_by_ws_id_should_show_public_channels_to_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let chat = state
+            .create_chat(&CreateChat::new("town square", &[1, 2, 5], true), 0, 1)
+            .await?;
+        assert_eq!(chat.r#type, ChatType::PublicChannel);
+
+        let chats = state.fetch_chats_by_ws_id(0, 5).await?;
+        assert!(chat_ids(&chats).contains(&chat.id));
+        // the user didn't join the channel
+        let chats = state.fetch_chats_by_ws_id(0, 0).await?;
+        let channel = chats.iter().find(|c| c.chat.id == chat.id).unwrap();
+        assert_eq!(channel.notify, None);
+        // but not to other workspaces
+        let chats = state.fetch_chats_by_ws_id(1, 4).await?;
+        assert!(chats.is_empty());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn update_chat_by_id_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
+        // chat 6 is an unnamed group of users 1 to 5
         let update_chat = UpdateChat {
             name: Some(Some("new_name".into())),
-            public: Some(true),
-            members: Some(vec![1, 2, 3]),
+            members: Some(vec![1, 2, 5]),
+            expires_after: Some(3600),
+            ..Default::default()
         };
-        let chat = state.update_chat_by_id(&update_chat, 1).await?;
+        let chat = state.update_chat_by_id(&update_chat, 6, 1).await?;
         assert_eq!(chat.name, update_chat.name.unwrap());
         assert_eq!(chat.members, update_chat.members.unwrap());
-        assert_eq!(chat.r#type, ChatType::PublicChannel);
+        assert_eq!(chat.r#type, ChatType::Group);
+        assert_eq!(chat.expires_after, Some(3600));
 
+        // fields not given are kept, the type doesn't follow the member count
         let update_chat = UpdateChat {
-            name: None,
-            public: Some(true),
             members: Some(vec![1, 2]),
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(&update_chat, 6, 1).await?;
+        assert_eq!(chat.name.as_deref(), Some("new_name"));
+        assert_eq!(chat.members, vec![1, 2]);
+        assert_eq!(chat.r#type, ChatType::Group);
+        assert_eq!(chat.expires_after, Some(3600));
+
+        let update_chat = UpdateChat {
+            name: Some(None),
+            expires_after: Some(0),
+            ..Default::default()
         };
-        let chat = state.update_chat_by_id(&update_chat, 1).await?;
+        let chat = state.update_chat_by_id(&update_chat, 6, 1).await?;
         assert_eq!(chat.name, None);
-        assert_eq!(chat.members, update_chat.members.unwrap());
-        assert_eq!(chat.r#type, ChatType::Single);
+        assert_eq!(chat.members, vec![1, 2]);
+        assert_eq!(chat.expires_after, None);
+
+        // an empty update changes nothing
+        let same = state
+            .update_chat_by_id(&UpdateChat::default(), 6, 1)
+            .await?;
+        assert_eq!(same, chat);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_should_keep_type_constraints() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // chat 1 is the direct chat of users 1 and 2
+        let update_chat = UpdateChat {
+            members: Some(vec![1, 2, 5]),
+            ..Default::default()
+        };
+        let err = state
+            .update_chat_by_id(&update_chat, 1, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create chat error: A direct chat should have 2 members"
+        );
+        // chat 4 is a public channel
+        let update_chat = UpdateChat {
+            name: Some(None),
+            ..Default::default()
+        };
+        let err = state
+            .update_chat_by_id(&update_chat, 4, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create chat error: A channel should have a name"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn update_chat_should_tell_null_name_from_missing_one() -> Result<()> {
+        let input: UpdateChat = serde_json::from_str(r#"{"topic": "weekly sync"}"#)?;
+        assert_eq!(input.name, None);
+        let input: UpdateChat = serde_json::from_str(r#"{"name": null}"#)?;
+        assert_eq!(input.name, Some(None));
+        let input: UpdateChat = serde_json::from_str(r#"{"name": "town square"}"#)?;
+        assert_eq!(input.name, Some(Some("town square".to_string())));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_should_reject_public() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input: UpdateChat = serde_json::from_str(r#"{"public": true}"#)?;
+        let err = state.update_chat_by_id(&input, 3, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "chat conversion error: Use the convert api to change the chat type"
+        );
+        // nothing is written by an empty update
+        let chat = state
+            .update_chat_by_id(&UpdateChat::default(), 3, 1)
+            .await?;
+        assert_eq!(chat.r#type, ChatType::PrivateChannel);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn convert_chat_should_follow_allowed_transitions() -> Result<()> {
+        use ChatType::*;
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let types = [Single, Group, PrivateChannel, PublicChannel];
+        let allowed = [
+            (Group, PrivateChannel),
+            (PrivateChannel, PublicChannel),
+            (PublicChannel, PrivateChannel),
+        ];
+        for (i, from) in types.into_iter().enumerate() {
+            for (j, to) in types.into_iter().enumerate() {
+                let name = format!("chat {} {}", i, j);
+                let input = match from {
+                    Single => CreateChat::new("", &[1, 2], false),
+                    Group => CreateChat::new("", &[1, 2, 5], false),
+                    PrivateChannel => CreateChat::new(&name, &[1, 2, 5], false),
+                    PublicChannel => CreateChat::new(&name, &[1, 2, 5], true),
+                };
+                let chat = state.create_chat(&input, 0, 1).await?;
+                assert_eq!(chat.r#type, from);
+
+                let input = ConvertChat {
+                    r#type: to,
+                    name: Some(name),
+                };
+                let ret = state.convert_chat(&input, chat.id, 1).await;
+                if from == to || allowed.contains(&(from, to)) {
+                    let converted = ret?;
+                    assert_eq!(converted.r#type, to, "{:?} to {:?}", from, to);
+                    assert_eq!(converted.members, chat.members);
+                } else {
+                    let err = ret.unwrap_err();
+                    assert_eq!(
+                        err.to_string(),
+                        format!(
+                            "chat conversion error: A {} can't be converted to a {}",
+                            from, to
+                        )
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn convert_chat_should_check_name_and_role() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // chat 6 is an unnamed group of users 1 to 5, user 1 owns it
+        let input = ConvertChat {
+            r#type: ChatType::PrivateChannel,
+            name: None,
+        };
+        let err = state.convert_chat(&input, 6, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "chat conversion error: A channel should have a name"
+        );
+        let input = ConvertChat {
+            r#type: ChatType::PrivateChannel,
+            name: Some("town square".to_string()),
+        };
+        let err = state.convert_chat(&input, 6, 5).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: member can't change the chat type"
+        );
+
+        let chat = state.convert_chat(&input, 6, 1).await?;
+        assert_eq!(chat.r#type, ChatType::PrivateChannel);
+        assert_eq!(chat.name.as_deref(), Some("town square"));
+        let page = state
+            .list_messages(crate::ListMessages::default(), 6)
+            .await?;
+        let contents: Vec<_> = page.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec![
+                "mitsuha converted the chat to a private channel",
+                "mitsuha renamed the chat to town square",
+            ]
+        );
+        Ok(())
+    }
 
+    #[tokio::test]
+    async fn create_chat_should_make_creator_owner() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let chat = state
+            .create_chat(&CreateChat::new("", &[1, 2, 5], false), 0, 2)
+            .await?;
+        assert_eq!(state.get_chat_role(chat.id as _, 2).await?, ChatRole::Owner);
+        assert_eq!(
+            state.get_chat_role(chat.id as _, 1).await?,
+            ChatRole::Member
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_members_should_follow_member_updates() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // user 2 is an admin of chat 2, removing and adding back resets the role
+        let update_chat = UpdateChat {
+            name: Some(Some("group_chat".into())),
+            members: Some(vec![1, 3, 4, 5]),
+            expires_after: None,
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(&update_chat, 2, 1).await?;
+        assert_eq!(chat.members, vec![1, 3, 4, 5]);
+        assert!(!state.is_chat_member(2, 2).await?);
+
+        let update_chat = UpdateChat {
+            members: Some(vec![1, 2, 3, 4, 5]),
+            ..update_chat
+        };
+        let chat = state.update_chat_by_id(&update_chat, 2, 1).await?;
+        assert_eq!(chat.members, vec![1, 2, 3, 4, 5]);
+        assert_eq!(state.get_chat_role(2, 2).await?, ChatRole::Member);
+
+        // deleted users leave their chats
+        sqlx::query("DELETE FROM users WHERE id = 5")
+            .execute(&state.pool)
+            .await?;
+        let chat = state.fetch_chat_by_id(2).await?.unwrap();
+        assert_eq!(chat.members, vec![1, 2, 3, 4]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_should_add_system_messages() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // chat 2 has members 1 to 5, users 3 and 4 are in another workspace
+        let update_chat = UpdateChat {
+            name: Some(Some("town square".into())),
+            topic: Some("weekly sync".into()),
+            members: Some(vec![1, 2, 5]),
+            ..Default::default()
+        };
+        state.update_chat_by_id(&update_chat, 2, 1).await?;
+        let page = state
+            .list_messages(crate::ListMessages::default(), 2)
+            .await?;
+        let contents: Vec<_> = page.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec![
+                "mitsuha removed taki, okudera",
+                "mitsuha changed the topic to weekly sync",
+                "mitsuha renamed the chat to town square",
+            ]
+        );
+        let message = &page.messages[0];
+        assert_eq!(message.kind, chat_core::MessageKind::System);
+        assert_eq!(message.sender_id, 1);
+        let body = message.body.as_ref().expect("body should exist");
+        assert_eq!(
+            body.blocks[0],
+            chat_core::ContentBlock::System {
+                text: "mitsuha removed taki, okudera".to_string(),
+                event: SystemEvent::MembersRemoved {
+                    user_ids: vec![3, 4]
+                },
+                actor_id: 1,
+            }
+        );
+
+        // an admin removing only itself leaves the chat
+        let update_chat = UpdateChat {
+            name: Some(Some("town square".into())),
+            members: Some(vec![1, 5]),
+            ..Default::default()
+        };
+        state.update_chat_by_id(&update_chat, 2, 2).await?;
+        let page = state
+            .list_messages(crate::ListMessages::default(), 2)
+            .await?;
+        assert_eq!(page.messages[0].content, "yotsuha left the chat");
+
+        // system messages can't be edited
+        let ret = sqlx::query("UPDATE messages SET content = 'hacked' WHERE id = $1")
+            .bind(page.messages[0].id)
+            .execute(&state.pool)
+            .await;
+        assert!(ret.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_details_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = ChatFile::new(0, "avatar.png", b"avatar");
+        let path = file.path(&state.config.server.base_url);
+        std::fs::create_dir_all(path.parent().expect("file path parent should exists"))?;
+        std::fs::write(&path, b"avatar")?;
+
+        // user 2 is an admin of chat 2
+        let update_chat = UpdateChat {
+            name: Some(Some("group_chat".into())),
+            topic: Some("weekly sync".into()),
+            description: Some("planning of the week".into()),
+            avatar: Some(file.url()),
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(&update_chat, 2, 2).await?;
+        assert_eq!(chat.topic.as_deref(), Some("weekly sync"));
+        assert_eq!(chat.description.as_deref(), Some("planning of the week"));
+        assert_eq!(chat.avatar, Some(file.url()));
+
+        let update_chat = UpdateChat {
+            topic: Some("daily sync".into()),
+            ..update_chat
+        };
+        let err = state
+            .update_chat_by_id(&update_chat, 2, 3)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: member can't edit the chat details"
+        );
+
+        // details not given are kept, empty ones are cleared
+        let update_chat = UpdateChat {
+            name: Some(Some("group_chat".into())),
+            topic: Some("".into()),
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(&update_chat, 2, 2).await?;
+        assert_eq!(chat.topic, None);
+        assert_eq!(chat.description.as_deref(), Some("planning of the week"));
+
+        let other = ChatFile::new(1, "avatar.png", b"avatar");
+        let update_chat = UpdateChat {
+            name: Some(Some("group_chat".into())),
+            avatar: Some(other.url()),
+            ..Default::default()
+        };
+        let err = state
+            .update_chat_by_id(&update_chat, 2, 2)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "create chat error: Avatar {} is not a file of workspace 0",
+                other.url()
+            )
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archived_chat_should_be_read_only() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let archive = UpdateChat {
+            name: Some(Some("group_chat".into())),
+            archived: Some(true),
+            ..Default::default()
+        };
+        let err = state.update_chat_by_id(&archive, 2, 3).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: member can't archive the chat"
+        );
+        let chat = state.update_chat_by_id(&archive, 2, 2).await?;
+        assert!(chat.archived);
+
+        let message = crate::CreateMessage {
+            content: "hello".into(),
+            ..Default::default()
+        };
+        let err = state
+            .create_message(message.clone(), 2, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "chat 2 is archived");
+        let rename = UpdateChat {
+            name: Some(Some("renamed".into())),
+            ..Default::default()
+        };
+        let err = state.update_chat_by_id(&rename, 2, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "chat 2 is archived");
+
+        // archived chats are only listed on demand
+        let inbox = state.list_inbox(Default::default(), 1).await?;
+        assert!(!inbox.chats.iter().any(|c| c.chat.id == 2));
+        let input = crate::ListInbox {
+            archived: true,
+            ..Default::default()
+        };
+        let inbox = state.list_inbox(input, 1).await?;
+        assert_eq!(inbox.chats.len(), 1);
+        assert_eq!(inbox.chats[0].chat.id, 2);
+
+        let restore = UpdateChat {
+            name: Some(Some("group_chat".into())),
+            archived: Some(false),
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(&restore, 2, 1).await?;
+        assert!(!chat.archived);
+        state.create_message(message, 2, 1).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_by_id_should_follow_chat_policy() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // chat 2 is owned by user 1, user 2 is an admin
+        let mut rename = UpdateChat {
+            name: Some(Some("renamed".into())),
+            members: Some(vec![1, 2, 3, 4, 5]),
+            expires_after: None,
+            ..Default::default()
+        };
+        let err = state.update_chat_by_id(&rename, 2, 3).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: member can't rename the chat"
+        );
+        let chat = state.update_chat_by_id(&rename, 2, 2).await?;
+        assert_eq!(chat.name, Some("renamed".into()));
+
+        // members can only remove themselves
+        rename.members = Some(vec![1, 2, 3, 4]);
+        let err = state.update_chat_by_id(&rename, 2, 3).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: member can't remove members"
+        );
+        let chat = state.update_chat_by_id(&rename, 2, 5).await?;
+        assert_eq!(chat.members, vec![1, 2, 3, 4]);
+
+        // the owner can't be removed, nor leave before transferring the ownership
+        rename.members = Some(vec![2, 3, 4]);
+        let err = state.update_chat_by_id(&rename, 2, 2).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: The owner 1 can't be removed"
+        );
+        let err = state.update_chat_by_id(&rename, 2, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: owner can't leave the chat before transferring the ownership"
+        );
+
+        // removed admins lose their role
+        rename.members = Some(vec![1, 3, 4]);
+        state.update_chat_by_id(&rename, 2, 1).await?;
+        assert_eq!(state.get_chat_role(2, 2).await?, ChatRole::Member);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_chat_by_id_should_need_owner_or_admin() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let err = state.delete_chat_by_id(2, 3).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: member can't delete the chat"
+        );
+        state.delete_chat_by_id(2, 2).await?;
+        assert!(state.fetch_chat_by_id(2).await?.is_none());
         Ok(())
     }
 
@@ -352,9 +1607,43 @@ mod tests {
         let chat_id = 1;
         let fetched_chat = state.fetch_chat_by_id(chat_id).await?;
         assert!(fetched_chat.is_some());
-        state.delete_chat_by_id(chat_id).await?;
+        state.delete_chat_by_id(chat_id, 1).await?;
         let fetched_chat = state.fetch_chat_by_id(chat_id).await?;
         assert!(fetched_chat.is_none());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn chat_updated_notification_should_fit_large_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut listener = sqlx::postgres::PgListener::connect_with(&state.pool).await?;
+        listener.listen("chat_updated").await?;
+
+        // a member joining is sent with the joined member only
+        sqlx::query("INSERT INTO chat_members(chat_id, user_id) VALUES (1, 3)")
+            .execute(&state.pool)
+            .await?;
+        let payload: serde_json::Value = serde_json::from_str(listener.recv().await?.payload())?;
+        assert_eq!(payload["op"], "UPDATE");
+        assert_eq!(payload["chat"]["members"], serde_json::json!([1, 2, 3]));
+        assert_eq!(payload["added"], serde_json::json!([3]));
+        assert_eq!(payload["removed"], serde_json::json!([]));
+
+        // too many members for a notification
+        sqlx::query(
+            r#"
+            SELECT notify_chat_updated('UPDATE', chat_json(c), ARRAY(SELECT generate_series(1, 2000)::bigint), '{}', FALSE)
+            FROM chats c WHERE id = 1
+            "#,
+        )
+        .execute(&state.pool)
+        .await?;
+        let notification = listener.recv().await?;
+        assert!(notification.payload().len() < 8000);
+        let payload: serde_json::Value = serde_json::from_str(notification.payload())?;
+        assert_eq!(payload["truncated"], true);
+        assert_eq!(payload["chat"]["members"], serde_json::json!([]));
+        assert_eq!(payload["added"], serde_json::json!([]));
+        Ok(())
+    }
 }
@@ -0,0 +1,153 @@
+use crate::{models::payload_size, AppError, AppState};
+use chat_core::{Draft, MessageBody};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct UpdateDraft {
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub files: Vec<String>,
+    pub body: Option<MessageBody>,
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// Create or replace the draft of the user in the chat
+    pub async fn save_draft(
+        &self,
+        input: UpdateDraft,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Draft, AppError> {
+        // the draft is pushed through pg_notify, so its size is limited like a message
+        let size = payload_size(&input.content, input.body.as_ref(), &input.files);
+        let max_size = self.config.chat.max_body_size;
+        if size as u64 > max_size {
+            return Err(AppError::DraftError(format!(
+                "Draft is too large: {} bytes, max {} bytes",
+                size, max_size
+            )));
+        }
+        if let Some(body) = &input.body {
+            self.validate_message_body(body)?;
+        }
+        for s in &input.files {
+            self.verify_chat_file(s)?;
+        }
+
+        let draft = sqlx::query_as(
+            r#"
+            INSERT INTO drafts (user_id, chat_id, content, body, files)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (user_id, chat_id)
+            DO UPDATE SET content = EXCLUDED.content, body = EXCLUDED.body, files = EXCLUDED.files,
+              updated_at = NOW()
+            RETURNING user_id, chat_id, content, body, files, updated_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(chat_id as i64)
+        .bind(&input.content)
+        .bind(&input.body)
+        .bind(&input.files)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(draft)
+    }
+
+    pub async fn get_draft(&self, chat_id: u64, user_id: u64) -> Result<Draft, AppError> {
+        let draft: Option<Draft> = sqlx::query_as(
+            r#"
+            SELECT user_id, chat_id, content, body, files, updated_at
+            FROM drafts
+            WHERE user_id = $1 AND chat_id = $2
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(chat_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        draft.ok_or_else(|| AppError::NotFountError("Draft not found".into()))
+    }
+
+    pub async fn delete_draft(&self, chat_id: u64, user_id: u64) -> Result<(), AppError> {
+        if !self.clear_draft(chat_id, user_id).await? {
+            return Err(AppError::NotFountError("Draft not found".into()));
+        }
+        Ok(())
+    }
+
+    /// Remove the draft if any, returns whether a draft was removed
+    pub async fn clear_draft(&self, chat_id: u64, user_id: u64) -> Result<bool, AppError> {
+        let res = sqlx::query("DELETE FROM drafts WHERE user_id = $1 AND chat_id = $2")
+            .bind(user_id as i64)
+            .bind(chat_id as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::sync::Arc;
+
+    fn draft(content: &str) -> UpdateDraft {
+        UpdateDraft {
+            content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn save_and_get_draft_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let saved = state.save_draft(draft("hel"), 1, 1).await?;
+        assert_eq!(saved.content, "hel");
+        state.save_draft(draft("other chat"), 2, 1).await?;
+        state.save_draft(draft("other user"), 1, 2).await?;
+
+        // one draft per user and chat
+        let updated = state.save_draft(draft("hello"), 1, 1).await?;
+        assert!(updated.updated_at >= saved.updated_at);
+        let fetched = state.get_draft(1, 1).await?;
+        assert_eq!(fetched, updated);
+
+        state.delete_draft(1, 1).await?;
+        let err = state.get_draft(1, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "not fount error: Draft not found");
+        let err = state.delete_draft(1, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "not fount error: Draft not found");
+        assert_eq!(state.get_draft(2, 1).await?.content, "other chat");
+
+        // clear draft once the message is sent
+        assert!(state.clear_draft(2, 1).await?);
+        assert!(!state.clear_draft(2, 1).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn save_too_large_draft_should_fail() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared")
+            .config
+            .chat
+            .max_body_size = 16;
+
+        // the content, body and files are counted as encoded in the notification
+        let err = state.save_draft(draft("hello"), 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "draft error: Draft is too large: 17 bytes, max 16 bytes"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{AppError, AppState, CreateMessage};
+use chat_core::{ContentBlock, Message, MessageBody, MessageKind};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use utoipa::ToSchema;
+
+// max number of messages forwarded at once
+const MAX_FORWARD_MESSAGES: usize = 100;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct ForwardMessages {
+    /// chat the messages are forwarded from
+    pub chat_id: u64,
+    pub message_ids: Vec<u64>,
+    /// quote the messages with a comment, sent before the forwarded messages
+    pub comment: Option<String>,
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// Copy messages of the source chat to the chat, the copies keep a reference to the originals
+    /// and reuse their files. Returns the created messages, oldest first.
+    pub async fn forward_messages(
+        &self,
+        input: ForwardMessages,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Vec<Message>, AppError> {
+        let ids: BTreeSet<i64> = input.message_ids.iter().map(|id| *id as i64).collect();
+        if ids.is_empty() || ids.len() > MAX_FORWARD_MESSAGES {
+            return Err(AppError::ForwardMessageError(format!(
+                "Should forward 1 to {} messages",
+                MAX_FORWARD_MESSAGES
+            )));
+        }
+        let source_id = input.chat_id;
+        if !self.is_chat_member(source_id, user_id).await? {
+            return Err(AppError::ForwardMessageError(format!(
+                "User {} are not a member of chat {}",
+                user_id, source_id
+            )));
+        }
+        let ids: Vec<i64> = ids.into_iter().collect();
+
+        let mut tx = self.pool.begin().await?;
+        self.verify_chat_postable(&mut tx, chat_id, user_id).await?;
+        let found: Vec<(i64, MessageKind, Option<MessageBody>)> = sqlx::query_as(
+            r#"
+            SELECT id, kind, body FROM messages
+            WHERE chat_id = $1 AND id = ANY($2) AND (expires_at IS NULL OR expires_at > NOW())
+            "#,
+        )
+        .bind(source_id as i64)
+        .bind(&ids)
+        .fetch_all(&mut *tx)
+        .await?;
+        if let Some(id) = ids.iter().find(|id| !found.iter().any(|(f, ..)| f == *id)) {
+            return Err(AppError::NotFountError(format!(
+                "Message {id} not found in chat {source_id}"
+            )));
+        }
+        // system messages only make sense in the timeline of their chat
+        if let Some((id, ..)) = found.iter().find(|(_, k, _)| *k == MessageKind::System) {
+            return Err(AppError::ForwardMessageError(format!(
+                "System message {id} can't be forwarded"
+            )));
+        }
+        // a copied poll block would have no poll to vote in
+        let is_poll = |body: &MessageBody| {
+            body.blocks
+                .iter()
+                .any(|b| matches!(b, ContentBlock::Poll { .. }))
+        };
+        if let Some((id, ..)) = found
+            .iter()
+            .find(|(_, _, body)| body.as_ref().is_some_and(is_poll))
+        {
+            return Err(AppError::ForwardMessageError(format!(
+                "Poll message {id} can't be forwarded"
+            )));
+        }
+
+        let mut messages = vec![];
+        if let Some(comment) = input.comment.filter(|c| !c.is_empty()) {
+            let comment = CreateMessage {
+                content: comment,
+                ..Default::default()
+            };
+            let content = self.validate_message(&comment)?;
+            messages
+                .push(Self::insert_message(&mut *tx, &comment, content, chat_id, user_id).await?);
+        }
+
+        // a forwarded message keeps the reference to the very first original
+        let mut forwarded: Vec<Message> = sqlx::query_as(
+            r#"
+            INSERT INTO messages (chat_id, sender_id, content, body, files, forwarded_from, expires_at)
+            SELECT $1, $2, content, body, files,
+              COALESCE(forwarded_from, jsonb_build_object(
+                'message_id', id, 'chat_id', chat_id, 'sender_id', sender_id, 'created_at', created_at)),
+              NOW() + (SELECT expires_after FROM chats WHERE id = $1) * INTERVAL '1 second'
+            FROM messages
+            WHERE chat_id = $3 AND id = ANY($4)
+            ORDER BY id
+            RETURNING id, chat_id, seq, sender_id, content, body, files, forwarded_from, kind, created_at, expires_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(source_id as i64)
+        .bind(&ids)
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        forwarded.sort_by_key(|m| m.id);
+        messages.extend(forwarded);
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn forward_messages_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = ForwardMessages {
+            chat_id: 1,
+            message_ids: vec![3, 2, 3],
+            comment: Some("look at this".to_string()),
+        };
+        let messages = state.forward_messages(input, 2, 1).await?;
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].content, "look at this");
+        assert_eq!(messages[0].forwarded_from, None);
+
+        let originals = state
+            .list_messages(crate::ListMessages::default(), 1)
+            .await?
+            .messages;
+        for (forwarded, id) in messages[1..].iter().zip([2, 3]) {
+            let original = originals.iter().find(|m| m.id == id).unwrap();
+            assert_eq!(forwarded.chat_id, 2);
+            assert_eq!(forwarded.sender_id, 1);
+            assert_eq!(forwarded.content, original.content);
+            assert_eq!(forwarded.files, original.files);
+            let from = forwarded.forwarded_from.as_ref().unwrap();
+            assert_eq!(from.message_id, original.id);
+            assert_eq!(from.chat_id, 1);
+            assert_eq!(from.sender_id, original.sender_id);
+            assert_eq!(from.created_at, original.created_at);
+        }
+
+        // forward again keeps the reference to the original message
+        let input = ForwardMessages {
+            chat_id: 2,
+            message_ids: vec![messages[1].id as _],
+            comment: None,
+        };
+        let again = state.forward_messages(input, 1, 2).await?;
+        assert_eq!(again.len(), 1);
+        assert_eq!(again[0].forwarded_from, messages[1].forwarded_from);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forward_messages_with_invalid_input_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // user 3 is not a member of chat 1
+        let input = ForwardMessages {
+            chat_id: 1,
+            message_ids: vec![1],
+            comment: None,
+        };
+        let err = state.forward_messages(input, 2, 3).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "forward message error: User 3 are not a member of chat 1"
+        );
+
+        // message 1 belongs to chat 1
+        let input = ForwardMessages {
+            chat_id: 2,
+            message_ids: vec![1],
+            comment: None,
+        };
+        let err = state.forward_messages(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "not fount error: Message 1 not found in chat 2"
+        );
+
+        // yotsuha left chat 2 with a system message
+        state.leave_chat(2, 2).await?;
+        let page = state
+            .list_messages(crate::ListMessages::default(), 2)
+            .await?;
+        let input = ForwardMessages {
+            chat_id: 2,
+            message_ids: vec![page.messages[0].id as _],
+            comment: None,
+        };
+        let err = state.forward_messages(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "forward message error: System message {} can't be forwarded",
+                page.messages[0].id
+            )
+        );
+
+        let poll = crate::CreatePoll {
+            question: "lunch?".to_string(),
+            options: vec!["ramen".to_string(), "soba".to_string()],
+            ..Default::default()
+        };
+        let poll = state.create_poll(poll, 1, 1).await?;
+        let input = ForwardMessages {
+            chat_id: 1,
+            message_ids: vec![poll.poll.id as _],
+            comment: None,
+        };
+        let err = state.forward_messages(input, 2, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "forward message error: Poll message {} can't be forwarded",
+                poll.poll.id
+            )
+        );
+
+        let err = state
+            .forward_messages(ForwardMessages::default(), 2, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "forward message error: Should forward 1 to 100 messages"
+        );
+        Ok(())
+    }
+}
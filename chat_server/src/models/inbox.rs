@@ -0,0 +1,404 @@
+use crate::{AppError, AppState, NotifyLevel};
+use chat_core::{Chat, ChatType, Message};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::collections::HashMap;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct ListInbox {
+    /// cursor returned by the previous page
+    pub cursor: Option<String>,
+    /// page size, capped by the server
+    pub limit: Option<u64>,
+    /// include the public channels the user joined
+    #[serde(default)]
+    pub channels: bool,
+    /// list the archived chats instead of the active ones
+    #[serde(default)]
+    pub archived: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct InboxChat {
+    #[serde(flatten)]
+    pub chat: Chat,
+    /// chat name, the other participant's name for single chats
+    pub display_name: String,
+    pub last_message: Option<Message>,
+    /// messages of other users after the last read one
+    pub unread_count: i64,
+    /// new messages of the chat don't alert the user for now
+    pub muted: bool,
+    pub starred: bool,
+    /// time of the last message, or of the chat creation if it has no message
+    pub last_activity_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct InboxPage {
+    /// chats of the page, the most recently active first
+    pub chats: Vec<InboxChat>,
+    /// pass as `cursor` to fetch the next page, none if there are no more chats
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct ChatSettings {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub notify: NotifyLevel,
+    /// the chat is muted until then whatever the notify level
+    pub muted_until: Option<DateTime<Utc>>,
+    pub starred: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct UpdateChatSettings {
+    /// changing the level ends a mute until unless given too
+    pub notify: Option<NotifyLevel>,
+    pub muted_until: Option<DateTime<Utc>>,
+    pub starred: Option<bool>,
+}
+
+#[derive(Debug, FromRow)]
+struct InboxRow {
+    #[sqlx(flatten)]
+    chat: Chat,
+    last_message_id: Option<i64>,
+    last_activity_at: DateTime<Utc>,
+    unread_count: i64,
+    muted: bool,
+    starred: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct InboxCursor {
+    last_activity_at: DateTime<Utc>,
+    id: i64,
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// Chats of the user sorted by last activity
+    pub async fn list_inbox(&self, input: ListInbox, user_id: u64) -> Result<InboxPage, AppError> {
+        let limit = self.page_size(input.limit)?;
+        let cursor = input
+            .cursor
+            .as_deref()
+            .map(|s| {
+                InboxCursor::decode(s)
+                    .ok_or_else(|| AppError::InboxError(format!("Invalid cursor: {}", s)))
+            })
+            .transpose()?;
+
+        let mut rows: Vec<InboxRow> = sqlx::query_as(
+            r#"
+            SELECT c.id, c.ws_id, c.name, c.type, chat_member_ids(c.id) AS members, c.topic,
+              c.description, c.avatar, c.archived, c.announcement, c.slow_mode,
+              c.expires_after, c.created_at,
+              m.id AS last_message_id,
+              COALESCE(m.created_at, c.created_at) AS last_activity_at,
+              (SELECT COUNT(*) FROM messages u
+                WHERE u.chat_id = c.id AND u.seq > cm.last_read_seq AND u.sender_id <> $1
+                AND (u.expires_at IS NULL OR u.expires_at > NOW())) AS unread_count,
+              (cm.notify = 'muted' OR COALESCE(cm.muted_until > NOW(), FALSE)) AS muted, cm.starred
+            FROM chats c
+            JOIN chat_members cm ON cm.chat_id = c.id AND cm.user_id = $1
+            LEFT JOIN LATERAL (
+              SELECT id, created_at FROM messages
+              WHERE chat_id = c.id AND (expires_at IS NULL OR expires_at > NOW())
+              ORDER BY seq DESC
+              LIMIT 1
+            ) m ON TRUE
+            WHERE ($2 OR c.type <> 'public_channel') AND c.archived = $6
+            AND ($3::timestamptz IS NULL OR (COALESCE(m.created_at, c.created_at), c.id) < ($3, $4))
+            ORDER BY last_activity_at DESC, c.id DESC
+            LIMIT $5
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(input.channels)
+        .bind(cursor.map(|c| c.last_activity_at))
+        .bind(cursor.map(|c| c.id))
+        .bind(limit + 1)
+        .bind(input.archived)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let has_more = rows.len() as i64 > limit;
+        rows.truncate(limit as usize);
+        let cursor = match rows.last() {
+            Some(row) if has_more => Some(
+                InboxCursor {
+                    last_activity_at: row.last_activity_at,
+                    id: row.chat.id,
+                }
+                .encode(),
+            ),
+            _ => None,
+        };
+
+        let message_ids: Vec<i64> = rows.iter().filter_map(|r| r.last_message_id).collect();
+        let messages: Vec<Message> = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, seq, sender_id, content, body, files, forwarded_from, kind, created_at, expires_at
+            FROM messages
+            WHERE id = ANY($1)
+            "#,
+        )
+        .bind(&message_ids)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut messages: HashMap<i64, Message> = messages.into_iter().map(|m| (m.id, m)).collect();
+
+        let mut member_ids: Vec<i64> = rows
+            .iter()
+            .filter(|r| r.chat.name.is_none() || r.chat.r#type == ChatType::Single)
+            .flat_map(|r| r.chat.members.iter().copied())
+            .collect();
+        member_ids.sort_unstable();
+        member_ids.dedup();
+        let names: Vec<(i64, String)> =
+            sqlx::query_as("SELECT id, name FROM users WHERE id = ANY($1)")
+                .bind(&member_ids)
+                .fetch_all(&self.pool)
+                .await?;
+        let names: HashMap<i64, String> = names.into_iter().collect();
+
+        let chats = rows
+            .into_iter()
+            .map(|row| InboxChat {
+                display_name: display_name(&row.chat, user_id as i64, &names),
+                last_message: row.last_message_id.and_then(|id| messages.remove(&id)),
+                unread_count: row.unread_count,
+                muted: row.muted,
+                starred: row.starred,
+                last_activity_at: row.last_activity_at,
+                chat: row.chat,
+            })
+            .collect();
+
+        Ok(InboxPage { chats, cursor })
+    }
+
+    /// Update the inbox settings of the chat for the user, settings not given are kept
+    pub async fn update_chat_settings(
+        &self,
+        input: UpdateChatSettings,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<ChatSettings, AppError> {
+        let settings: Option<ChatSettings> = sqlx::query_as(
+            r#"
+            UPDATE chat_members SET notify = COALESCE($3, notify),
+              muted_until = CASE WHEN $4::timestamptz IS NOT NULL THEN $4
+                WHEN $3 IS NOT NULL THEN NULL ELSE muted_until END,
+              starred = COALESCE($5, starred), updated_at = NOW()
+            WHERE user_id = $1 AND chat_id = $2
+            RETURNING user_id, chat_id, notify, muted_until, starred, updated_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(chat_id as i64)
+        .bind(input.notify)
+        .bind(input.muted_until)
+        .bind(input.starred)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        settings.ok_or_else(|| {
+            AppError::NotFountError(format!(
+                "User {} is not a member of chat {}",
+                user_id, chat_id
+            ))
+        })
+    }
+}
+
+// single chats are named after the other participant, unnamed groups after their members
+fn display_name(chat: &Chat, user_id: i64, names: &HashMap<i64, String>) -> String {
+    if let (Some(name), false) = (&chat.name, chat.r#type == ChatType::Single) {
+        return name.clone();
+    }
+    let others: Vec<&str> = chat
+        .members
+        .iter()
+        .filter(|id| **id != user_id)
+        .filter_map(|id| names.get(id).map(|s| s.as_str()))
+        .collect();
+    match (others.is_empty(), &chat.name) {
+        (false, _) => others.join(", "),
+        (true, Some(name)) => name.clone(),
+        (true, None) => names.get(&user_id).cloned().unwrap_or_default(),
+    }
+}
+
+impl InboxCursor {
+    fn encode(&self) -> String {
+        hex::encode(format!(
+            "{}.{}",
+            self.last_activity_at.timestamp_micros(),
+            self.id
+        ))
+    }
+
+    fn decode(s: &str) -> Option<Self> {
+        let s = String::from_utf8(hex::decode(s).ok()?).ok()?;
+        let (at, id) = s.split_once('.')?;
+        Some(Self {
+            last_activity_at: DateTime::from_timestamp_micros(at.parse().ok()?)?,
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateMessage, MarkRead};
+    use anyhow::Result;
+
+    fn chat_ids(page: &InboxPage) -> Vec<i64> {
+        page.chats.iter().map(|c| c.chat.id).collect()
+    }
+
+    #[tokio::test]
+    async fn list_inbox_should_sort_chats_by_activity() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            ..Default::default()
+        };
+        let message = state.create_message(input, 3, 2).await?;
+
+        let page = state.list_inbox(ListInbox::default(), 1).await?;
+        // the public channel is only listed on demand, chats of the same activity by id
+        assert_eq!(chat_ids(&page), vec![3, 6, 5, 2, 1]);
+        assert_eq!(page.cursor, None);
+        let chat = &page.chats[0];
+        assert_eq!(chat.last_message, Some(message.clone()));
+        assert_eq!(chat.last_activity_at, message.created_at);
+        assert_eq!(chat.unread_count, 1);
+        assert!(page.chats[1].last_message.is_none());
+
+        let input = ListInbox {
+            channels: true,
+            ..Default::default()
+        };
+        let page = state.list_inbox(input, 1).await?;
+        assert_eq!(chat_ids(&page), vec![3, 6, 5, 4, 2, 1]);
+
+        // user 3 is not a member of the single chats
+        let page = state.list_inbox(ListInbox::default(), 3).await?;
+        assert_eq!(chat_ids(&page), vec![3, 6, 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_inbox_should_return_display_name_and_unread_count() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let page = state.list_inbox(ListInbox::default(), 1).await?;
+        let chats: HashMap<i64, &InboxChat> = page.chats.iter().map(|c| (c.chat.id, c)).collect();
+        assert_eq!(chats[&1].display_name, "yotsuha");
+        assert_eq!(chats[&2].display_name, "group_chat");
+        assert_eq!(chats[&5].display_name, "yotsuha");
+        assert_eq!(chats[&6].display_name, "yotsuha, taki, okudera, sayaka");
+        // 6 of the 10 messages of chat 1 are sent by other users
+        assert_eq!(chats[&1].unread_count, 6);
+        assert_eq!(chats[&1].last_message.as_ref().map(|m| m.seq), Some(10));
+
+        state.mark_chat_read(MarkRead { seq: 5 }, 1, 1).await?;
+        let page = state.list_inbox(ListInbox::default(), 1).await?;
+        let chat = page.chats.iter().find(|c| c.chat.id == 1).unwrap();
+        assert_eq!(chat.unread_count, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_inbox_should_paginate() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut cursor = None;
+        let mut ids = vec![];
+        loop {
+            let input = ListInbox {
+                cursor,
+                limit: Some(2),
+                channels: true,
+                ..Default::default()
+            };
+            let page = state.list_inbox(input, 1).await?;
+            assert!(page.chats.len() <= 2);
+            ids.extend(chat_ids(&page));
+            match page.cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(ids, vec![6, 5, 4, 3, 2, 1]);
+
+        let input = ListInbox {
+            cursor: Some("invalid".to_string()),
+            ..Default::default()
+        };
+        let err = state.list_inbox(input, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "inbox error: Invalid cursor: invalid");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_settings_should_keep_missing_flags() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateChatSettings {
+            notify: Some(NotifyLevel::Muted),
+            ..Default::default()
+        };
+        let settings = state.update_chat_settings(input, 2, 1).await?;
+        assert!(settings.notify == NotifyLevel::Muted && !settings.starred);
+
+        let input = UpdateChatSettings {
+            starred: Some(true),
+            ..Default::default()
+        };
+        let settings = state.update_chat_settings(input, 2, 1).await?;
+        assert!(settings.notify == NotifyLevel::Muted && settings.starred);
+
+        let page = state.list_inbox(ListInbox::default(), 1).await?;
+        let chat = page.chats.iter().find(|c| c.chat.id == 2).unwrap();
+        assert!(chat.muted && chat.starred);
+        // flags are per user
+        let page = state.list_inbox(ListInbox::default(), 2).await?;
+        let chat = page.chats.iter().find(|c| c.chat.id == 2).unwrap();
+        assert!(!chat.muted && !chat.starred);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mute_until_should_end_with_level_change() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let until = Utc::now() + chrono::Duration::hours(1);
+        let input = UpdateChatSettings {
+            muted_until: Some(until),
+            ..Default::default()
+        };
+        let settings = state.update_chat_settings(input, 2, 1).await?;
+        assert_eq!(settings.notify, NotifyLevel::All);
+        assert!(settings.muted_until.is_some());
+        let page = state.list_inbox(ListInbox::default(), 1).await?;
+        let chat = page.chats.iter().find(|c| c.chat.id == 2).unwrap();
+        assert!(chat.muted);
+
+        let input = UpdateChatSettings {
+            notify: Some(NotifyLevel::Mentions),
+            ..Default::default()
+        };
+        let settings = state.update_chat_settings(input, 2, 1).await?;
+        assert_eq!(settings.muted_until, None);
+        let page = state.list_inbox(ListInbox::default(), 1).await?;
+        let chat = page.chats.iter().find(|c| c.chat.id == 2).unwrap();
+        assert!(!chat.muted);
+        Ok(())
+    }
+}
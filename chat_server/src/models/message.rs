@@ -1,19 +1,78 @@
-use crate::{AppError, AppState, ChatFile};
-use chat_core::Message;
+use crate::{models::mentioned_user_names, AppError, AppState, ChatFile, MessageFormat};
+use chat_core::{ChatRole, ContentBlock, Message, MessageBody, SystemEvent};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
+use sqlx::{PgConnection, PgExecutor};
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    str::FromStr,
+};
+use tokio::fs;
+use tracing::warn;
 use utoipa::{IntoParams, ToSchema};
 
-#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, IntoParams)]
+const MAX_BODY_BLOCKS: usize = 50;
+const MAX_BLOCK_BUTTONS: usize = 5;
+const MAX_BLOCK_FIELDS: usize = 10;
+const MAX_CLIENT_MSG_ID_LEN: usize = 64;
+// max number of expired messages deleted in one run of the sweeper
+const SWEEP_BATCH_SIZE: i64 = 500;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, IntoParams)]
 pub struct CreateMessage {
+    /// plain text of the message, derived from body if empty
+    #[serde(default)]
     pub content: String,
     pub files: Vec<String>,
+    pub body: Option<MessageBody>,
+    /// deliver the message later instead of sending it now
+    pub send_at: Option<DateTime<Utc>>,
+    /// delete the message this many seconds after it is sent, defaults to the chat setting
+    pub expires_after: Option<u64>,
+    /// client generated id, a retried send with the same id returns the original message
+    pub client_msg_id: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, IntoParams)]
+/// Messages are fetched by at most one anchor, without an anchor the latest messages are returned.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, IntoParams)]
 pub struct ListMessages {
-    pub last_id: Option<u64>,
-    pub limit: u64,
+    /// messages older than this id
+    #[serde(alias = "last_id")]
+    pub before: Option<u64>,
+    /// messages newer than this id
+    pub after: Option<u64>,
+    /// messages around this id, the anchor message included
+    pub around: Option<u64>,
+    /// messages with seq from this one, used with `to_seq` to fill a gap
+    pub from_seq: Option<u64>,
+    /// messages with seq up to this one, inclusive
+    pub to_seq: Option<u64>,
+    /// page size, capped by the server
+    pub limit: Option<u64>,
+    /// how the messages are rendered
+    #[serde(default)]
+    pub format: MessageFormat,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct MessagePage {
+    /// messages of the page, newest first
+    pub messages: Vec<Message>,
+    pub has_more_before: bool,
+    pub has_more_after: bool,
+    /// pass as `before` to fetch the older page
+    pub before: Option<u64>,
+    /// pass as `after` to fetch the newer page
+    pub after: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MessageAnchor {
+    Latest,
+    Before(i64),
+    After(i64),
+    Around(i64),
 }
 
 #[allow(dead_code)]
@@ -24,73 +83,783 @@ impl AppState {
         chat_id: u64,
         user_id: u64,
     ) -> Result<Message, AppError> {
-        let base_dir = &self.config.server.base_url;
+        let (message, _) = self.send_message(input, chat_id, user_id).await?;
+        Ok(message)
+    }
+
+    /// Create the message, the flag is false if a retry got the message of the first send
+    pub(crate) async fn send_message(
+        &self,
+        input: CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<(Message, bool), AppError> {
+        let content = self.validate_message(&input)?;
+        let mut tx = self.pool.begin().await?;
+        if let Err(e) = self.verify_chat_postable(&mut tx, chat_id, user_id).await {
+            // a retry returns the message of the first send, which slow mode would reject
+            if let Some(key) = &input.client_msg_id {
+                if let Some(message) = self.find_sent_message(key, chat_id, user_id).await? {
+                    return Ok((message, false));
+                }
+            }
+            return Err(e);
+        }
+        let Some(key) = &input.client_msg_id else {
+            let message = Self::insert_message(&mut *tx, &input, content, chat_id, user_id).await?;
+            tx.commit().await?;
+            return Ok((message, true));
+        };
+
+        // the key can be reused once it is out of the window
+        sqlx::query(
+            r#"
+            DELETE FROM idempotency_keys
+            WHERE chat_id = $1 AND sender_id = $2 AND key = $3
+            AND created_at <= NOW() - $4 * INTERVAL '1 second'
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(key)
+        .bind(self.config.chat.idempotency_window as i64)
+        .execute(&mut *tx)
+        .await?;
+        let message = Self::insert_message(&mut *tx, &input, content, chat_id, user_id).await?;
+        // concurrent sends with the same key wait here until the first one is committed
+        if Self::insert_idempotency_key(&mut *tx, key, chat_id, user_id, message.id).await? {
+            tx.commit().await?;
+            return Ok((message, true));
+        }
+
+        // already sent, drop the new message and return the original one
+        tx.rollback().await?;
+        let message: Option<Message> = sqlx::query_as(
+            r#"
+            SELECT m.id, m.chat_id, m.seq, m.sender_id, m.content, m.body, m.files, m.forwarded_from, m.kind, m.created_at, m.expires_at
+            FROM idempotency_keys k
+            JOIN messages m ON m.id = k.message_id
+            WHERE k.chat_id = $1 AND k.sender_id = $2 AND k.key = $3
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(key)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let message = message.ok_or_else(|| {
+            AppError::CreateMessageError(format!(
+                "Message with client message id {} was deleted",
+                key
+            ))
+        })?;
+        Ok((message, false))
+    }
+
+    /// Delete idempotency keys out of the window, returns the number of deleted keys
+    pub async fn purge_idempotency_keys(&self) -> Result<u64, AppError> {
+        let res = sqlx::query(
+            "DELETE FROM idempotency_keys WHERE created_at <= NOW() - $1 * INTERVAL '1 second'",
+        )
+        .bind(self.config.chat.idempotency_window as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(res.rows_affected())
+    }
+
+    /// Verify the message to create and return its plain text content
+    pub(crate) fn validate_message(&self, input: &CreateMessage) -> Result<String, AppError> {
+        // verify body and use its plain text if no content is given
+        let content = match &input.body {
+            Some(body) => {
+                self.validate_message_body(body)?;
+                if input.content.is_empty() {
+                    body.to_plain_text()
+                } else {
+                    input.content.clone()
+                }
+            }
+            None => input.content.clone(),
+        };
         // verify content - not empty
-        if input.content.is_empty() {
+        if content.is_empty() {
             return Err(AppError::CreateMessageError(
                 "Content cannot be empty".to_string(),
             ));
         }
-        // verify files exist
-        for s in &input.files {
-            let file = ChatFile::from_str(s)?;
-            if !file.path(base_dir).exists() {
+        // verify size - the message is sent through pg_notify along with the member ids
+        let size = payload_size(&content, input.body.as_ref(), &input.files);
+        let max_size = self.config.chat.max_body_size;
+        if size as u64 > max_size {
+            return Err(AppError::CreateMessageError(format!(
+                "Message is too large: {} bytes, max {} bytes",
+                size, max_size
+            )));
+        }
+        // verify expires after - a positive number of seconds
+        if let Some(expires_after) = input.expires_after {
+            if expires_after == 0 || i64::try_from(expires_after).is_err() {
                 return Err(AppError::CreateMessageError(format!(
-                    "File {} doesn't exist",
-                    s
+                    "Invalid expires after: {}",
+                    expires_after
                 )));
             }
         }
+        // verify client message id - not empty and fits the column
+        if let Some(key) = &input.client_msg_id {
+            if key.is_empty() || key.len() > MAX_CLIENT_MSG_ID_LEN {
+                return Err(AppError::CreateMessageError(format!(
+                    "Client message id should be 1 to {} bytes",
+                    MAX_CLIENT_MSG_ID_LEN
+                )));
+            }
+        }
+        // verify files exist
+        for s in &input.files {
+            self.verify_chat_file(s)?;
+        }
+        Ok(content)
+    }
 
-        // create message
+    pub(crate) async fn insert_message<'e>(
+        executor: impl PgExecutor<'e>,
+        input: &CreateMessage,
+        content: String,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        // members mentioned outside code are alerted in mentions only chats
+        let mentioned = mentioned_user_names(input.body.as_ref(), &content);
         let message: Message = sqlx::query_as(
             r#"
-          INSERT INTO messages (chat_id, sender_id, content, files)
-          VALUES ($1, $2, $3, $4)
-          RETURNING id, chat_id, sender_id, content, files, created_at
+          INSERT INTO messages (chat_id, sender_id, content, body, files, expires_at, mentions)
+          VALUES ($1, $2, $3, $4, $5,
+            NOW() + COALESCE($6, (SELECT expires_after FROM chats WHERE id = $1)) * INTERVAL '1 second',
+            (SELECT COALESCE(array_agg(u.id ORDER BY u.id), '{}') FROM chat_members cm
+              JOIN users u ON u.id = cm.user_id
+              WHERE cm.chat_id = $1 AND u.name = ANY($7)))
+          RETURNING id, chat_id, seq, sender_id, content, body, files, forwarded_from, kind, created_at, expires_at
           "#,
         )
         .bind(chat_id as i64)
         .bind(user_id as i64)
-        .bind(input.content)
+        .bind(content)
+        .bind(&input.body)
         .bind(&input.files)
-        .fetch_one(&self.pool)
+        .bind(input.expires_after.map(|v| v as i64))
+        .bind(mentioned)
+        .fetch_one(executor)
+        .await?;
+
+        Ok(message)
+    }
+
+    /// Render a chat event done by the user into the body of a system message
+    pub(crate) async fn system_message_body(
+        &self,
+        event: SystemEvent,
+        user_id: u64,
+    ) -> Result<MessageBody, AppError> {
+        let mut ids = event.user_ids().to_vec();
+        ids.push(user_id as i64);
+        let names: HashMap<i64, String> =
+            sqlx::query_as::<_, (i64, String)>("SELECT id, name FROM users WHERE id = ANY($1)")
+                .bind(&ids)
+                .fetch_all(&self.pool)
+                .await?
+                .into_iter()
+                .collect();
+        let actor = names
+            .get(&(user_id as i64))
+            .cloned()
+            .unwrap_or_else(|| format!("user {}", user_id));
+        let text = event.render(&actor, &names);
+
+        Ok(MessageBody::new(vec![ContentBlock::System {
+            text,
+            event,
+            actor_id: user_id as i64,
+        }]))
+    }
+
+    /// Record the message sent with the client message id, returns false if the key is taken
+    pub(crate) async fn insert_idempotency_key<'e>(
+        executor: impl PgExecutor<'e>,
+        key: &str,
+        chat_id: u64,
+        user_id: u64,
+        message_id: i64,
+    ) -> Result<bool, AppError> {
+        let res = sqlx::query(
+            r#"
+            INSERT INTO idempotency_keys (chat_id, sender_id, key, message_id)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(key)
+        .bind(message_id)
+        .execute(executor)
+        .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+
+    // message sent with the client message id within the idempotency window
+    pub(crate) async fn find_sent_message(
+        &self,
+        key: &str,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Option<Message>, AppError> {
+        let message = sqlx::query_as(
+            r#"
+            SELECT m.id, m.chat_id, m.seq, m.sender_id, m.content, m.body, m.files, m.forwarded_from, m.kind, m.created_at, m.expires_at
+            FROM idempotency_keys k
+            JOIN messages m ON m.id = k.message_id
+            WHERE k.chat_id = $1 AND k.sender_id = $2 AND k.key = $3
+            AND k.created_at > NOW() - $4 * INTERVAL '1 second'
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(key)
+        .bind(self.config.chat.idempotency_window as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(message)
+    }
+
+    /// Insert a message generated by the server for a chat event done by the user
+    pub(crate) async fn insert_system_message<'e>(
+        executor: impl PgExecutor<'e>,
+        body: MessageBody,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        let message: Message = sqlx::query_as(
+            r#"
+          INSERT INTO messages (chat_id, sender_id, content, body, kind)
+          VALUES ($1, $2, $3, $4, 'system')
+          RETURNING id, chat_id, seq, sender_id, content, body, files, forwarded_from, kind, created_at, expires_at
+          "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(body.to_plain_text())
+        .bind(body)
+        .fetch_one(executor)
         .await?;
 
         Ok(message)
     }
 
+    // archived chats are read-only until restored
+    pub(crate) async fn verify_chat_writable(&self, chat_id: u64) -> Result<(), AppError> {
+        let archived: Option<bool> = sqlx::query_scalar("SELECT archived FROM chats WHERE id = $1")
+            .bind(chat_id as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+        match archived {
+            Some(false) => Ok(()),
+            Some(true) => Err(AppError::ChatArchived(chat_id)),
+            None => Err(AppError::NotFountError(format!(
+                "Chat {} not found",
+                chat_id
+            ))),
+        }
+    }
+
+    // besides being writable, announcement chats and slow mode restrict members posting.
+    // The member row stays locked until the transaction of the post ends, so concurrent
+    // posts of a member are checked against slow mode one after another
+    pub(crate) async fn verify_chat_postable(
+        &self,
+        conn: &mut PgConnection,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<(), AppError> {
+        self.verify_posting_rules(conn, chat_id, user_id, true)
+            .await
+    }
+
+    // scheduled messages are delivered without waiting for slow mode, the schedule was
+    // checked against it when the messages were scheduled
+    pub(crate) async fn verify_posting_rules(
+        &self,
+        conn: &mut PgConnection,
+        chat_id: u64,
+        user_id: u64,
+        slow_mode: bool,
+    ) -> Result<(), AppError> {
+        self.verify_chat_writable(chat_id).await?;
+        let role: Option<ChatRole> = sqlx::query_scalar(
+            "SELECT role FROM chat_members WHERE chat_id = $1 AND user_id = $2 FOR UPDATE",
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&mut *conn)
+        .await?;
+        if matches!(role, Some(ChatRole::Owner | ChatRole::Admin)) {
+            return Ok(());
+        }
+        let (announcement, wait): (bool, Option<i64>) = sqlx::query_as(
+            r#"
+            SELECT c.announcement,
+              (SELECT CEIL(EXTRACT(EPOCH FROM MAX(m.created_at) + c.slow_mode * INTERVAL '1 second' - NOW()))::bigint
+                FROM messages m
+                WHERE m.chat_id = c.id AND m.sender_id = $2 AND m.kind = 'user') AS wait
+            FROM chats c
+            WHERE c.id = $1
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_one(&mut *conn)
+        .await?;
+        if announcement {
+            return Err(AppError::AnnouncementOnly(chat_id));
+        }
+        match wait {
+            Some(wait) if slow_mode && wait > 0 => Err(AppError::SlowMode(chat_id, wait as u64)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn verify_chat_file(&self, url: &str) -> Result<(), AppError> {
+        let file = ChatFile::from_str(url)?;
+        if !file.path(&self.config.server.base_url).exists() {
+            return Err(AppError::CreateMessageError(format!(
+                "File {} doesn't exist",
+                url
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn validate_message_body(&self, body: &MessageBody) -> Result<(), AppError> {
+        let invalid = |msg: String| Err(AppError::CreateMessageError(msg));
+        if body.blocks.is_empty() {
+            return invalid("Body should have at least one block".to_string());
+        }
+        if body.blocks.len() > MAX_BODY_BLOCKS {
+            return invalid(format!(
+                "Body cannot have more than {} blocks",
+                MAX_BODY_BLOCKS
+            ));
+        }
+        let size = serde_json::to_vec(body)
+            .map_err(|e| AppError::CreateMessageError(e.to_string()))?
+            .len();
+        let max_body_size = self.config.chat.max_body_size;
+        if size as u64 > max_body_size {
+            return invalid(format!(
+                "Body is too large: {} bytes, max {} bytes",
+                size, max_body_size
+            ));
+        }
+
+        for block in &body.blocks {
+            match block {
+                ContentBlock::Markdown { text } | ContentBlock::Quote { text, .. } => {
+                    if text.trim().is_empty() {
+                        return invalid("Text block cannot be empty".to_string());
+                    }
+                }
+                ContentBlock::Code { code, .. } => {
+                    if code.is_empty() {
+                        return invalid("Code block cannot be empty".to_string());
+                    }
+                }
+                ContentBlock::Attachment { url, name, .. } => {
+                    if name.is_empty() {
+                        return invalid("Attachment name cannot be empty".to_string());
+                    }
+                    self.verify_chat_file(url)?;
+                }
+                ContentBlock::Buttons { buttons } => {
+                    if buttons.is_empty() || buttons.len() > MAX_BLOCK_BUTTONS {
+                        return invalid(format!(
+                            "Buttons block should have 1 to {} buttons",
+                            MAX_BLOCK_BUTTONS
+                        ));
+                    }
+                    if buttons
+                        .iter()
+                        .any(|b| b.label.is_empty() || b.action_id.is_empty())
+                    {
+                        return invalid("Button label and action id cannot be empty".to_string());
+                    }
+                }
+                ContentBlock::Poll { .. } => {
+                    return invalid("Poll should be created with the poll api".to_string());
+                }
+                ContentBlock::System { .. } => {
+                    return invalid("System blocks are created by the server".to_string());
+                }
+                ContentBlock::Fields { fields } => {
+                    if fields.is_empty() || fields.len() > MAX_BLOCK_FIELDS {
+                        return invalid(format!(
+                            "Fields block should have 1 to {} fields",
+                            MAX_BLOCK_FIELDS
+                        ));
+                    }
+                    if fields.iter().any(|f| f.title.is_empty()) {
+                        return invalid("Field title cannot be empty".to_string());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub async fn list_messages(
         &self,
         input: ListMessages,
         chat_id: u64,
-    ) -> Result<Vec<Message>, AppError> {
-        let last_id = input.last_id.unwrap_or(i64::MAX as _);
+    ) -> Result<MessagePage, AppError> {
+        let anchor = input.anchor()?;
+        let limit = self.page_size(input.limit)?;
+        let chat_id = chat_id as i64;
+        if let Some((from, to)) = input.seq_range()? {
+            return self.list_messages_by_seq(chat_id, from, to, limit).await;
+        }
+
+        // fetch one more message on each side to know if there are more
+        let (newer_limit, older_limit) = match anchor {
+            MessageAnchor::Latest | MessageAnchor::Before(_) => (0, limit),
+            MessageAnchor::After(_) => (limit, 0),
+            MessageAnchor::Around(_) => (limit / 2, limit - limit / 2),
+        };
+        let mut newer = match anchor {
+            MessageAnchor::After(id) | MessageAnchor::Around(id) if newer_limit > 0 => {
+                self.fetch_newer_messages(chat_id, id, newer_limit + 1)
+                    .await?
+            }
+            _ => vec![],
+        };
+        let mut older = match anchor {
+            MessageAnchor::Latest => {
+                self.fetch_older_messages(chat_id, i64::MAX, older_limit + 1)
+                    .await?
+            }
+            MessageAnchor::Before(id) => {
+                self.fetch_older_messages(chat_id, id, older_limit + 1)
+                    .await?
+            }
+            // the anchor itself belongs to the older half
+            MessageAnchor::Around(id) => {
+                self.fetch_older_messages(chat_id, id.saturating_add(1), older_limit + 1)
+                    .await?
+            }
+            MessageAnchor::After(_) => vec![],
+        };
+
+        let has_more_after = match anchor {
+            MessageAnchor::Latest => false,
+            MessageAnchor::Before(id) => self.has_messages_since(chat_id, id).await?,
+            // a page of one message only holds the anchor, nothing newer was fetched
+            MessageAnchor::Around(id) if newer_limit == 0 => {
+                self.has_messages_since(chat_id, id.saturating_add(1))
+                    .await?
+            }
+            _ => newer.len() as i64 > newer_limit,
+        };
+        let has_more_before = match anchor {
+            MessageAnchor::After(id) => self.has_messages_until(chat_id, id).await?,
+            _ => older.len() as i64 > older_limit,
+        };
+        newer.truncate(newer_limit as usize);
+        older.truncate(older_limit as usize);
+
+        // bounds of the scanned range, used as cursors if the page is empty
+        let (lower, upper) = match anchor {
+            MessageAnchor::Latest => (i64::MAX, i64::MAX),
+            MessageAnchor::Before(id) => (id, id.saturating_sub(1)),
+            MessageAnchor::After(id) | MessageAnchor::Around(id) => (id.saturating_add(1), id),
+        };
+        let messages: Vec<Message> = newer.into_iter().rev().chain(older).collect();
+        let before = has_more_before.then(|| messages.last().map_or(lower, |m| m.id) as u64);
+        let after = has_more_after.then(|| messages.first().map_or(upper, |m| m.id) as u64);
+
+        Ok(MessagePage {
+            messages,
+            has_more_before,
+            has_more_after,
+            before,
+            after,
+        })
+    }
+
+    // the oldest messages of the seq range, a partial page continues from the newest seq + 1
+    async fn list_messages_by_seq(
+        &self,
+        chat_id: i64,
+        from: i64,
+        to: i64,
+        limit: i64,
+    ) -> Result<MessagePage, AppError> {
+        let mut messages: Vec<Message> = sqlx::query_as(
+            r#"
+        SELECT id, chat_id, seq, sender_id, content, body, files, forwarded_from, kind, created_at, expires_at
+        FROM messages
+        WHERE chat_id = $1
+        AND seq BETWEEN $2 AND $3
+        AND (expires_at IS NULL OR expires_at > NOW())
+        ORDER BY seq ASC
+        LIMIT $4
+        "#,
+        )
+        .bind(chat_id)
+        .bind(from)
+        .bind(to)
+        .bind(limit + 1)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut has_more_after = messages.len() as i64 > limit;
+        messages.truncate(limit as usize);
+        messages.reverse();
+        let newest = messages.first().map(|m| m.id);
+        let oldest = messages.last().map(|m| m.id);
+        if let Some(id) = newest {
+            has_more_after = has_more_after || self.has_messages_since(chat_id, id + 1).await?;
+        }
+        let has_more_before = match oldest {
+            Some(id) => self.has_messages_until(chat_id, id - 1).await?,
+            None => false,
+        };
+
+        Ok(MessagePage {
+            messages,
+            has_more_before,
+            has_more_after,
+            before: oldest.filter(|_| has_more_before).map(|id| id as u64),
+            after: newest.filter(|_| has_more_after).map(|id| id as u64),
+        })
+    }
+
+    pub(crate) fn page_size(&self, limit: Option<u64>) -> Result<i64, AppError> {
+        let max_page_size = self.config.chat.max_page_size;
+        match limit {
+            Some(0) => Err(AppError::ListMessageError(
+                "Limit should be greater than 0".to_string(),
+            )),
+            Some(limit) => Ok(limit.min(max_page_size) as i64),
+            None => Ok(max_page_size as i64),
+        }
+    }
 
-        let messages: Vec<Message> = sqlx::query_as(
+    // messages with id < before, newest first
+    async fn fetch_older_messages(
+        &self,
+        chat_id: i64,
+        before: i64,
+        limit: i64,
+    ) -> Result<Vec<Message>, AppError> {
+        let messages = sqlx::query_as(
             r#"
-        SELECT id, chat_id, sender_id, content, files, created_at
+        SELECT id, chat_id, seq, sender_id, content, body, files, forwarded_from, kind, created_at, expires_at
         FROM messages
         WHERE chat_id = $1
         AND id < $2
+        AND (expires_at IS NULL OR expires_at > NOW())
         ORDER BY id DESC
         LIMIT $3
         "#,
         )
-        .bind(chat_id as i64)
-        .bind(last_id as i64)
-        .bind(input.limit as i64)
+        .bind(chat_id)
+        .bind(before)
+        .bind(limit)
         .fetch_all(&self.pool)
         .await?;
 
         Ok(messages)
     }
+
+    // messages with id > after, oldest first
+    async fn fetch_newer_messages(
+        &self,
+        chat_id: i64,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<Message>, AppError> {
+        let messages = sqlx::query_as(
+            r#"
+        SELECT id, chat_id, seq, sender_id, content, body, files, forwarded_from, kind, created_at, expires_at
+        FROM messages
+        WHERE chat_id = $1
+        AND id > $2
+        AND (expires_at IS NULL OR expires_at > NOW())
+        ORDER BY id ASC
+        LIMIT $3
+        "#,
+        )
+        .bind(chat_id)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(messages)
+    }
+
+    async fn has_messages_since(&self, chat_id: i64, id: i64) -> Result<bool, AppError> {
+        let ret = sqlx::query(
+            r#"
+            SELECT 1 FROM messages
+            WHERE chat_id = $1 AND id >= $2 AND (expires_at IS NULL OR expires_at > NOW())
+            LIMIT 1
+            "#,
+        )
+        .bind(chat_id)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(ret.is_some())
+    }
+
+    async fn has_messages_until(&self, chat_id: i64, id: i64) -> Result<bool, AppError> {
+        let ret = sqlx::query(
+            r#"
+            SELECT 1 FROM messages
+            WHERE chat_id = $1 AND id <= $2 AND (expires_at IS NULL OR expires_at > NOW())
+            LIMIT 1
+            "#,
+        )
+        .bind(chat_id)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(ret.is_some())
+    }
+
+    /// Delete expired messages and their files which no other message refers to,
+    /// returns the number of deleted messages
+    pub async fn sweep_expired_messages(&self) -> Result<usize, AppError> {
+        let expired: Vec<(Vec<String>, Option<MessageBody>)> = sqlx::query_as(
+            r#"
+            DELETE FROM messages
+            WHERE id IN (
+                SELECT id FROM messages
+                WHERE expires_at <= NOW()
+                ORDER BY expires_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING files, body
+            "#,
+        )
+        .bind(SWEEP_BATCH_SIZE)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let urls: HashSet<&str> = expired
+            .iter()
+            .flat_map(|(files, body)| {
+                files
+                    .iter()
+                    .map(String::as_str)
+                    .chain(body.iter().flat_map(MessageBody::file_urls))
+            })
+            .collect();
+        // the messages are gone already, a file failing to be removed doesn't stop the others
+        for url in urls {
+            if let Err(e) = self.remove_unreferenced_file(url).await {
+                warn!("failed to remove file {}: {}", url, e);
+            }
+        }
+
+        Ok(expired.len())
+    }
+
+    async fn remove_unreferenced_file(&self, url: &str) -> Result<(), AppError> {
+        let attachment = serde_json::json!({ "blocks": [{ "url": url }] }).to_string();
+        let referenced = sqlx::query(
+            r#"
+            SELECT 1 FROM messages WHERE $1 = ANY(files) OR body @> $2::jsonb
+            UNION ALL
+            SELECT 1 FROM scheduled_messages WHERE $1 = ANY(files) OR body @> $2::jsonb
+            UNION ALL
+            SELECT 1 FROM drafts WHERE $1 = ANY(files) OR body @> $2::jsonb
+            UNION ALL
+            SELECT 1 FROM chats WHERE avatar = $1
+            LIMIT 1
+            "#,
+        )
+        .bind(url)
+        .bind(attachment)
+        .fetch_optional(&self.pool)
+        .await?;
+        if referenced.is_some() {
+            return Ok(());
+        }
+
+        let path = match ChatFile::from_str(url) {
+            Ok(file) => file.path(&self.config.server.base_url),
+            Err(e) => {
+                warn!("skip removing file {}: {}", url, e);
+                return Ok(());
+            }
+        };
+        match fs::remove_file(&path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ListMessages {
+    fn anchor(&self) -> Result<MessageAnchor, AppError> {
+        let seq = self.seq_range()?;
+        match (self.before, self.after, self.around, seq) {
+            // listed by seq range instead
+            (None, None, None, _) => Ok(MessageAnchor::Latest),
+            (Some(id), None, None, None) => Ok(MessageAnchor::Before(to_i64(id)?)),
+            (None, Some(id), None, None) => Ok(MessageAnchor::After(to_i64(id)?)),
+            (None, None, Some(id), None) => Ok(MessageAnchor::Around(to_i64(id)?)),
+            _ => Err(AppError::ListMessageError(
+                "Only one of before, after, around and seq range can be specified".to_string(),
+            )),
+        }
+    }
+
+    // inclusive seq range, open ends default to the first and the last message
+    fn seq_range(&self) -> Result<Option<(i64, i64)>, AppError> {
+        if self.from_seq.is_none() && self.to_seq.is_none() {
+            return Ok(None);
+        }
+        let from = to_i64(self.from_seq.unwrap_or(1))?;
+        let to = to_i64(self.to_seq.unwrap_or(i64::MAX as u64))?;
+        if from > to {
+            return Err(AppError::ListMessageError(format!(
+                "Invalid seq range: {} to {}",
+                from, to
+            )));
+        }
+        Ok(Some((from, to)))
+    }
+}
+
+/// Encoded size of the content, body and files, which are sent through pg_notify
+pub(crate) fn payload_size(content: &str, body: Option<&MessageBody>, files: &[String]) -> usize {
+    serde_json::to_vec(&(content, body, files)).map_or(usize::MAX, |v| v.len())
+}
+
+fn to_i64(id: u64) -> Result<i64, AppError> {
+    i64::try_from(id).map_err(|_| AppError::ListMessageError(format!("Invalid message id: {}", id)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use anyhow::Result;
+    use std::{path::PathBuf, sync::Arc};
 
     #[tokio::test]
     async fn create_message_should_work() -> Result<()> {
@@ -98,6 +867,7 @@ mod tests {
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec![],
+            ..Default::default()
         };
         let message = state
             .create_message(input, 1, 1)
@@ -109,6 +879,7 @@ mod tests {
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec!["1".to_string()],
+            ..Default::default()
         };
 
         let err = state.create_message(input, 1, 1).await.unwrap_err();
@@ -119,6 +890,7 @@ mod tests {
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec![url],
+            ..Default::default()
         };
         let message = state
             .create_message(input, 1, 1)
@@ -130,30 +902,660 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn create_message_with_body_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let url = upload_dummy_file(&state)?;
+        let body = MessageBody::new(vec![
+            ContentBlock::Markdown {
+                text: "see the *log*".to_string(),
+            },
+            ContentBlock::Attachment {
+                url,
+                name: "test.txt".to_string(),
+                mime: Some("text/plain".to_string()),
+                size: Some(11),
+            },
+        ]);
+        let input = CreateMessage {
+            content: "".to_string(),
+            files: vec![],
+            body: Some(body.clone()),
+            ..Default::default()
+        };
+        let message = state.create_message(input, 1, 1).await?;
+        assert_eq!(message.content, "see the *log*\n[test.txt]");
+        assert_eq!(message.body, Some(body));
+
+        // the body should be loaded back from the database
+        let page = state.list_messages(ListMessages::default(), 1).await?;
+        assert_eq!(page.messages[0], message);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_with_invalid_body_should_fail() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        std::sync::Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared")
+            .config
+            .chat
+            .max_body_size = 200;
+
+        let create = |blocks| CreateMessage {
+            content: "".to_string(),
+            files: vec![],
+            body: Some(MessageBody::new(blocks)),
+            ..Default::default()
+        };
+
+        let err = state
+            .create_message(create(vec![]), 1, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create message error: Body should have at least one block"
+        );
+
+        let input = create(vec![ContentBlock::Markdown {
+            text: "a".repeat(200),
+        }]);
+        let err = state.create_message(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create message error: Body is too large: 242 bytes, max 200 bytes"
+        );
+        // content and body together are limited too
+        let input = CreateMessage {
+            content: "b".repeat(150),
+            ..create(vec![ContentBlock::Markdown {
+                text: "a".repeat(50),
+            }])
+        };
+        let err = state.create_message(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create message error: Message is too large: 250 bytes, max 200 bytes"
+        );
+
+        let input = create(vec![ContentBlock::Buttons { buttons: vec![] }]);
+        let err = state.create_message(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create message error: Buttons block should have 1 to 5 buttons"
+        );
+
+        let input = create(vec![ContentBlock::Attachment {
+            url: "/files/1/abc/def/none.txt".to_string(),
+            name: "none.txt".to_string(),
+            mime: None,
+            size: None,
+        }]);
+        let err = state.create_message(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create message error: File /files/1/abc/def/none.txt doesn't exist"
+        );
+        Ok(())
+    }
+
+    fn ids(page: &MessagePage) -> Vec<i64> {
+        page.messages.iter().map(|m| m.id).collect()
+    }
+
     #[tokio::test]
     async fn list_messages_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         let input = ListMessages {
-            last_id: None,
-            limit: 6,
+            limit: Some(6),
+            ..Default::default()
+        };
+
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(ids(&page), vec![10, 9, 8, 7, 6, 5]);
+        assert!(page.has_more_before);
+        assert!(!page.has_more_after);
+        assert_eq!(page.before, Some(5));
+        assert_eq!(page.after, None);
+
+        let input = ListMessages {
+            before: page.before,
+            limit: Some(6),
+            ..Default::default()
+        };
+
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(ids(&page), vec![4, 3, 2, 1]);
+        assert!(!page.has_more_before);
+        assert!(page.has_more_after);
+        assert_eq!(page.before, None);
+        assert_eq!(page.after, Some(4));
+
+        let input = ListMessages {
+            after: page.after,
+            limit: Some(3),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(ids(&page), vec![7, 6, 5]);
+        assert!(page.has_more_before);
+        assert!(page.has_more_after);
+        assert_eq!(page.before, Some(5));
+        assert_eq!(page.after, Some(7));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn message_seq_should_be_per_chat() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            ..Default::default()
+        };
+        let message = state.create_message(input.clone(), 1, 1).await?;
+        assert_eq!(message.seq, 11);
+        let message = state.create_message(input.clone(), 2, 1).await?;
+        assert_eq!(message.seq, 1);
+        let message = state.create_message(input, 2, 2).await?;
+        assert_eq!(message.seq, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_by_seq_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = ListMessages {
+            from_seq: Some(3),
+            to_seq: Some(6),
+            limit: Some(3),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        let seqs: Vec<_> = page.messages.iter().map(|m| m.seq).collect();
+        assert_eq!(seqs, vec![5, 4, 3]);
+        assert!(page.has_more_before);
+        assert!(page.has_more_after);
+
+        let input = ListMessages {
+            from_seq: Some(6),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        let seqs: Vec<_> = page.messages.iter().map(|m| m.seq).collect();
+        assert_eq!(seqs, vec![10, 9, 8, 7, 6]);
+        assert!(!page.has_more_after);
+
+        let input = ListMessages {
+            from_seq: Some(6),
+            to_seq: Some(5),
+            ..Default::default()
+        };
+        let err = state.list_messages(input, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "list message error: Invalid seq range: 6 to 5"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_around_anchor_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = ListMessages {
+            around: Some(5),
+            limit: Some(4),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(ids(&page), vec![7, 6, 5, 4]);
+        assert!(page.has_more_before);
+        assert!(page.has_more_after);
+
+        // anchor near the end of the chat
+        let input = ListMessages {
+            around: Some(9),
+            limit: Some(6),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(ids(&page), vec![10, 9, 8, 7]);
+        assert!(page.has_more_before);
+        assert!(!page.has_more_after);
+        assert_eq!(page.after, None);
+
+        // a page of the anchor alone still reports newer messages
+        let input = ListMessages {
+            around: Some(5),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(ids(&page), vec![5]);
+        assert!(page.has_more_before);
+        assert!(page.has_more_after);
+        assert_eq!(page.after, Some(5));
+
+        // anchors at the end of the id range
+        let input = ListMessages {
+            after: Some(i64::MAX as u64),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert!(page.messages.is_empty());
+        assert!(page.has_more_before);
+        assert_eq!(page.before, Some(i64::MAX as u64));
+        let input = ListMessages {
+            around: Some(i64::MAX as u64),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(page.messages.len(), 10);
+        assert!(!page.has_more_after);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_should_cap_page_size() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        std::sync::Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared")
+            .config
+            .chat
+            .max_page_size = 3;
+
+        let input = ListMessages {
+            limit: Some(1000000),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(ids(&page), vec![10, 9, 8]);
+
+        let page = state.list_messages(ListMessages::default(), 1).await?;
+        assert_eq!(ids(&page), vec![10, 9, 8]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_with_invalid_input_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = ListMessages {
+            before: Some(5),
+            after: Some(2),
+            ..Default::default()
+        };
+        let err = state.list_messages(input, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "list message error: Only one of before, after, around and seq range can be specified"
+        );
+
+        let input = ListMessages {
+            limit: Some(0),
+            ..Default::default()
+        };
+        let err = state.list_messages(input, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "list message error: Limit should be greater than 0"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_with_client_msg_id_should_be_idempotent() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            client_msg_id: Some("4c0a7d".to_string()),
+            ..Default::default()
         };
+        // concurrent retries create only one message
+        let (first, second) = tokio::join!(
+            state.create_message(input.clone(), 1, 1),
+            state.create_message(input.clone(), 1, 1)
+        );
+        let message = first?;
+        assert_eq!(second?, message);
+        let (retried, created) = state.send_message(input.clone(), 1, 1).await?;
+        assert_eq!(retried, message);
+        assert!(!created);
+        let page = state.list_messages(ListMessages::default(), 1).await?;
+        assert_eq!(page.messages.len(), 11);
 
-        let messages = state.list_messages(input, 1).await?;
-        assert_eq!(messages.len(), 6);
+        // the key is scoped to the chat and the sender
+        let other = state.create_message(input.clone(), 1, 2).await?;
+        assert_ne!(other.id, message.id);
+        let other = state.create_message(input.clone(), 2, 1).await?;
+        assert_ne!(other.id, message.id);
+
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            client_msg_id: Some("".to_string()),
+            ..Default::default()
+        };
+        let err = state.create_message(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create message error: Client message id should be 1 to 64 bytes"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_msg_id_should_be_reusable_out_of_window() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared")
+            .config
+            .chat
+            .idempotency_window = 0;
+
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            client_msg_id: Some("4c0a7d".to_string()),
+            ..Default::default()
+        };
+        let first = state.create_message(input.clone(), 1, 1).await?;
+        let second = state.create_message(input, 1, 1).await?;
+        assert_ne!(first.id, second.id);
+        assert_eq!(state.purge_idempotency_keys().await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_messages_should_not_be_listed() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateMessage {
+            content: "self destruct".to_string(),
+            expires_after: Some(60),
+            ..Default::default()
+        };
+        let message = state.create_message(input, 1, 1).await?;
+        let expires_at = message.expires_at.expect("message should expire");
+        assert!(expires_at > message.created_at);
 
-        let last_id = messages.last().expect("last message should exists").id;
+        let page = state.list_messages(ListMessages::default(), 1).await?;
+        assert_eq!(page.messages[0].id, message.id);
 
+        // expired message is hidden even if the sweeper hasn't deleted it yet
+        expire_message(&state, message.id).await?;
+        let page = state.list_messages(ListMessages::default(), 1).await?;
+        assert_eq!(page.messages.len(), 10);
+        assert!(page.messages.iter().all(|m| m.id != message.id));
         let input = ListMessages {
-            last_id: Some(last_id as _),
-            limit: 6,
+            before: Some(11),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert!(!page.has_more_after);
+
+        // invalid expires after should fail
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            expires_after: Some(0),
+            ..Default::default()
+        };
+        let err = state.create_message(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create message error: Invalid expires after: 0"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_expires_after_should_apply_to_messages() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("UPDATE chats SET expires_after = 3600 WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            ..Default::default()
+        };
+        let message = state.create_message(input, 1, 1).await?;
+        let ttl = message.expires_at.expect("message should expire") - message.created_at;
+        assert_eq!(ttl.num_seconds(), 3600);
+
+        // message setting takes precedence
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            expires_after: Some(10),
+            ..Default::default()
+        };
+        let message = state.create_message(input, 1, 1).await?;
+        let ttl = message.expires_at.expect("message should expire") - message.created_at;
+        assert_eq!(ttl.num_seconds(), 10);
+
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            ..Default::default()
+        };
+        let message = state.create_message(input, 2, 1).await?;
+        assert_eq!(message.expires_at, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn announcement_chat_should_restrict_members() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // user 1 owns chat 2, user 2 is an admin, the others are members
+        let input = crate::UpdateChat {
+            name: Some(Some("group_chat".into())),
+            announcement: Some(true),
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(&input, 2, 2).await?;
+        assert!(chat.announcement);
+
+        let message = |content: &str| CreateMessage {
+            content: content.to_string(),
+            ..Default::default()
+        };
+        let err = state
+            .create_message(message("hello"), 2, 5)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "only owners and admins can post in announcement chat 2"
+        );
+        // polls are messages too
+        let poll = crate::CreatePoll {
+            question: "lunch?".to_string(),
+            options: vec!["yes".to_string(), "no".to_string()],
+            ..Default::default()
+        };
+        let err = state.create_poll(poll, 2, 5).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "only owners and admins can post in announcement chat 2"
+        );
+        state.create_message(message("hello"), 2, 2).await?;
+        state.create_message(message("hello"), 2, 1).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_mode_should_limit_members() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = crate::UpdateChat {
+            name: Some(Some("group_chat".into())),
+            slow_mode: Some(60),
+            ..Default::default()
+        };
+        let err = state.update_chat_by_id(&input, 2, 5).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: member can't change the chat settings"
+        );
+        let chat = state.update_chat_by_id(&input, 2, 1).await?;
+        assert_eq!(chat.slow_mode, Some(60));
+
+        let message = |key: &str| CreateMessage {
+            content: "hello".to_string(),
+            client_msg_id: Some(key.to_string()),
+            ..Default::default()
+        };
+        let sent = state.create_message(message("a"), 2, 5).await?;
+        // a retry is not a new message
+        assert_eq!(state.create_message(message("a"), 2, 5).await?, sent);
+        let err = state.create_message(message("b"), 2, 5).await.unwrap_err();
+        assert!(matches!(err, AppError::SlowMode(2, 1..=60)));
+        // concurrent sends are checked one after another
+        let (a, b) = tokio::join!(
+            state.create_message(message("e"), 2, 4),
+            state.create_message(message("f"), 2, 4)
+        );
+        assert!(a.is_ok() != b.is_ok());
+        // admins are not limited
+        state.create_message(message("c"), 2, 2).await?;
+        state.create_message(message("d"), 2, 2).await?;
+
+        let input = crate::UpdateChat {
+            name: Some(Some("group_chat".into())),
+            slow_mode: Some(0),
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(&input, 2, 2).await?;
+        assert_eq!(chat.slow_mode, None);
+        state.create_message(message("b"), 2, 5).await?;
+
+        let input = crate::UpdateChat {
+            slow_mode: Some(100_000),
+            ..input
+        };
+        let err = state.update_chat_by_id(&input, 2, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create chat error: Invalid slow mode: 100000"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sweep_expired_messages_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // files with unique content so that other tests don't share them
+        let nonce = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let (file, path) = write_chat_file(&state, &format!("ephemeral {}", nonce))?;
+        let (shared, shared_path) = write_chat_file(&state, &format!("shared {}", nonce))?;
+
+        let input = CreateMessage {
+            content: "self destruct".to_string(),
+            files: vec![file.url(), shared.url()],
+            expires_after: Some(60),
+            ..Default::default()
         };
+        let expired = state.create_message(input, 1, 1).await?;
+        let input = CreateMessage {
+            content: "keep".to_string(),
+            files: vec![shared.url()],
+            ..Default::default()
+        };
+        state.create_message(input, 2, 1).await?;
 
-        let messages = state.list_messages(input, 1).await?;
-        assert_eq!(messages.len(), 4);
+        // not expired yet
+        assert_eq!(state.sweep_expired_messages().await?, 0);
+        expire_message(&state, expired.id).await?;
+        assert_eq!(state.sweep_expired_messages().await?, 1);
+        assert_eq!(state.sweep_expired_messages().await?, 0);
+
+        let ret = sqlx::query("SELECT 1 FROM messages WHERE id = $1")
+            .bind(expired.id)
+            .fetch_optional(&state.pool)
+            .await?;
+        assert!(ret.is_none());
+        assert!(!path.exists());
+        // still used by another message
+        assert!(shared_path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sweep_expired_messages_should_skip_failed_files() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let nonce = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let (broken, broken_path) = write_chat_file(&state, &format!("broken {}", nonce))?;
+        let (file, path) = write_chat_file(&state, &format!("removed {}", nonce))?;
+        // a directory in place of the file can't be removed as a file
+        std::fs::remove_file(&broken_path)?;
+        std::fs::create_dir_all(broken_path.join("sub"))?;
 
+        let input = CreateMessage {
+            content: "self destruct".to_string(),
+            files: vec![broken.url(), file.url()],
+            expires_after: Some(60),
+            ..Default::default()
+        };
+        let expired = state.create_message(input, 1, 1).await?;
+        expire_message(&state, expired.id).await?;
+        assert_eq!(state.sweep_expired_messages().await?, 1);
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&broken_path)?;
         Ok(())
     }
 
+    #[tokio::test]
+    async fn sweep_expired_messages_should_keep_avatars_and_drafts() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let nonce = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        // avatars are files of the workspace of the chat
+        let data = format!("avatar {}", nonce);
+        let avatar = ChatFile::new(0, "avatar.png", data.as_bytes());
+        let avatar_path = avatar.path(&state.config.server.base_url);
+        std::fs::create_dir_all(
+            avatar_path
+                .parent()
+                .expect("file path parent should exists"),
+        )?;
+        std::fs::write(&avatar_path, data)?;
+        let (drafted, drafted_path) = write_chat_file(&state, &format!("drafted {}", nonce))?;
+        let input = crate::UpdateChat {
+            avatar: Some(avatar.url()),
+            ..Default::default()
+        };
+        state.update_chat_by_id(&input, 2, 1).await?;
+        let draft = crate::UpdateDraft {
+            content: "later".to_string(),
+            files: vec![drafted.url()],
+            body: None,
+        };
+        state.save_draft(draft, 2, 2).await?;
+
+        let input = CreateMessage {
+            content: "self destruct".to_string(),
+            files: vec![avatar.url(), drafted.url()],
+            expires_after: Some(60),
+            ..Default::default()
+        };
+        let expired = state.create_message(input, 1, 1).await?;
+        expire_message(&state, expired.id).await?;
+        assert_eq!(state.sweep_expired_messages().await?, 1);
+        assert!(avatar_path.exists());
+        assert!(drafted_path.exists());
+        Ok(())
+    }
+
+    async fn expire_message(state: &AppState, id: i64) -> Result<()> {
+        sqlx::query("UPDATE messages SET expires_at = NOW() - INTERVAL '1 second' WHERE id = $1")
+            .bind(id)
+            .execute(&state.pool)
+            .await?;
+        Ok(())
+    }
+
+    fn write_chat_file(state: &AppState, data: &str) -> Result<(ChatFile, PathBuf)> {
+        let file = ChatFile::new(1, "test.txt", data.as_bytes());
+        let path = file.path(&state.config.server.base_url);
+        std::fs::create_dir_all(path.parent().expect("file path parent should exists"))?;
+        std::fs::write(&path, data)?;
+        Ok((file, path))
+    }
+
     fn upload_dummy_file(state: &AppState) -> Result<String> {
         let file = ChatFile::new(1, "test.txt", b"hello world");
         let path = file.path(&state.config.server.base_url);
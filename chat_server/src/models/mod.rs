@@ -1,10 +1,36 @@
+mod channel;
 mod chat;
+mod draft;
 mod file;
+mod forward;
+mod inbox;
 mod message;
+mod notification;
+mod pin;
+mod poll;
+mod read_state;
+mod render;
+mod role;
+mod saved;
+mod scheduled;
+mod sync;
 mod user;
 mod workspace;
 
+pub use channel::*;
 pub use chat::*;
+pub use draft::*;
 pub use file::*;
+pub use forward::*;
+pub use inbox::*;
 pub use message::*;
+pub use notification::*;
+pub use pin::*;
+pub use poll::*;
+pub use read_state::*;
+pub use render::*;
+pub use role::*;
+pub use saved::*;
+pub use scheduled::*;
+pub use sync::*;
 pub use user::*;
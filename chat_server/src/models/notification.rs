@@ -0,0 +1,225 @@
+use crate::{AppError, AppState};
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+// utc offsets in use range from -12:00 to +14:00
+const MAX_UTC_OFFSET: i32 = 14 * 60;
+
+/// Which new messages of a chat alert the member, others are delivered silently
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema,
+)]
+#[sqlx(type_name = "notify_level", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyLevel {
+    #[default]
+    All,
+    Mentions,
+    Muted,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct DndSchedule {
+    pub user_id: i64,
+    /// local time the schedule starts, it ends the next day if not after the start
+    #[schema(value_type = String, example = "22:00:00")]
+    pub starts_at: NaiveTime,
+    #[schema(value_type = String, example = "07:00:00")]
+    pub ends_at: NaiveTime,
+    /// minutes east of utc of the local time
+    pub utc_offset: i32,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct UpdateDndSchedule {
+    #[schema(value_type = String, example = "22:00:00")]
+    pub starts_at: NaiveTime,
+    #[schema(value_type = String, example = "07:00:00")]
+    pub ends_at: NaiveTime,
+    #[serde(default)]
+    pub utc_offset: i32,
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// Do not disturb schedule of the user, applied to all chats of its workspace
+    pub async fn get_dnd_schedule(&self, user_id: u64) -> Result<Option<DndSchedule>, AppError> {
+        let schedule = sqlx::query_as(
+            r#"
+            SELECT user_id, starts_at, ends_at, utc_offset, updated_at
+            FROM dnd_schedules
+            WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(schedule)
+    }
+
+    /// Set the do not disturb schedule of the user, replacing the current one
+    pub async fn set_dnd_schedule(
+        &self,
+        input: UpdateDndSchedule,
+        user_id: u64,
+    ) -> Result<DndSchedule, AppError> {
+        if input.starts_at == input.ends_at {
+            return Err(AppError::NotificationError(
+                "Do not disturb should start and end at different times".to_string(),
+            ));
+        }
+        if input.utc_offset.abs() > MAX_UTC_OFFSET {
+            return Err(AppError::NotificationError(format!(
+                "Invalid utc offset {}",
+                input.utc_offset
+            )));
+        }
+
+        let schedule = sqlx::query_as(
+            r#"
+            INSERT INTO dnd_schedules (user_id, starts_at, ends_at, utc_offset)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id) DO UPDATE SET starts_at = EXCLUDED.starts_at,
+              ends_at = EXCLUDED.ends_at, utc_offset = EXCLUDED.utc_offset, updated_at = NOW()
+            RETURNING user_id, starts_at, ends_at, utc_offset, updated_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(input.starts_at)
+        .bind(input.ends_at)
+        .bind(input.utc_offset)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(schedule)
+    }
+
+    /// Remove the do not disturb schedule of the user, removing none does nothing
+    pub async fn clear_dnd_schedule(&self, user_id: u64) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM dnd_schedules WHERE user_id = $1")
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateMessage, UpdateChatSettings};
+    use anyhow::Result;
+    use chrono::Timelike;
+
+    async fn silent_member_ids(state: &AppState, content: &str) -> Result<Vec<i64>> {
+        let input = CreateMessage {
+            content: content.to_string(),
+            ..Default::default()
+        };
+        let message = state.create_message(input, 2, 1).await?;
+        let (ids,): (Vec<i64>,) =
+            sqlx::query_as("SELECT silent_member_ids(m) FROM messages m WHERE id = $1")
+                .bind(message.id)
+                .fetch_one(&state.pool)
+                .await?;
+        Ok(ids)
+    }
+
+    #[tokio::test]
+    async fn dnd_schedule_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        assert!(state.get_dnd_schedule(1).await?.is_none());
+
+        let input = UpdateDndSchedule {
+            starts_at: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            ends_at: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+            utc_offset: 540,
+        };
+        let schedule = state.set_dnd_schedule(input.clone(), 1).await?;
+        assert_eq!(schedule.utc_offset, 540);
+        assert_eq!(state.get_dnd_schedule(1).await?, Some(schedule));
+
+        let err = state
+            .set_dnd_schedule(
+                UpdateDndSchedule {
+                    utc_offset: 900,
+                    ..input.clone()
+                },
+                1,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "notification error: Invalid utc offset 900"
+        );
+        let err = state
+            .set_dnd_schedule(
+                UpdateDndSchedule {
+                    ends_at: input.starts_at,
+                    ..input
+                },
+                1,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "notification error: Do not disturb should start and end at different times"
+        );
+
+        state.clear_dnd_schedule(1).await?;
+        assert!(state.get_dnd_schedule(1).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn silent_members_should_follow_preferences() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        assert!(silent_member_ids(&state, "hello").await?.is_empty());
+
+        // chat 2 has members 1 to 5
+        let muted = UpdateChatSettings {
+            notify: Some(NotifyLevel::Muted),
+            ..Default::default()
+        };
+        state.update_chat_settings(muted, 2, 2).await?;
+        let mentions = UpdateChatSettings {
+            notify: Some(NotifyLevel::Mentions),
+            ..Default::default()
+        };
+        state.update_chat_settings(mentions, 2, 3).await?;
+        let muted_for_a_while = UpdateChatSettings {
+            muted_until: Some(Utc::now() + chrono::Duration::hours(1)),
+            ..Default::default()
+        };
+        state.update_chat_settings(muted_for_a_while, 2, 4).await?;
+        assert_eq!(silent_member_ids(&state, "hello").await?, vec![2, 3, 4]);
+        // mentioned users are alerted in mentions only chats, not in muted ones
+        assert_eq!(
+            silent_member_ids(&state, "hi @taki, @yotsuha").await?,
+            vec![2, 4]
+        );
+        // mentions in code don't alert
+        assert_eq!(
+            silent_member_ids(&state, "run `@taki` or\n\n```\n@taki\n```").await?,
+            vec![2, 3, 4]
+        );
+
+        // user 5 is in its do not disturb schedule, which spans the current hour
+        let hour = Utc::now().hour();
+        let input = UpdateDndSchedule {
+            starts_at: NaiveTime::from_hms_opt(hour, 0, 0).unwrap(),
+            ends_at: NaiveTime::from_hms_opt((hour + 2) % 24, 0, 0).unwrap(),
+            utc_offset: 0,
+        };
+        state.set_dnd_schedule(input, 5).await?;
+        assert_eq!(silent_member_ids(&state, "hi @taki").await?, vec![2, 4, 5]);
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{AppError, AppState};
+use chat_core::{ChatPin, Message};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct PinnedMessage {
+    pub pinned_by: i64,
+    pub pinned_at: DateTime<Utc>,
+    #[sqlx(flatten)]
+    pub message: Message,
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// Pin a message of the chat, pinning an already pinned message returns the existing pin
+    pub async fn pin_message(
+        &self,
+        chat_id: u64,
+        message_id: u64,
+        user_id: u64,
+    ) -> Result<ChatPin, AppError> {
+        let mut tx = self.pool.begin().await?;
+        // lock the chat so concurrent pins cannot exceed the limit
+        sqlx::query("SELECT 1 FROM chats WHERE id = $1 FOR UPDATE")
+            .bind(chat_id as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        let message = sqlx::query(
+            r#"
+            SELECT 1 FROM messages
+            WHERE id = $1 AND chat_id = $2 AND (expires_at IS NULL OR expires_at > NOW())
+            "#,
+        )
+        .bind(message_id as i64)
+        .bind(chat_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if message.is_none() {
+            return Err(AppError::NotFountError(format!(
+                "Message {message_id} not found in chat {chat_id}"
+            )));
+        }
+
+        let pin: Option<ChatPin> = sqlx::query_as(
+            r#"
+            SELECT chat_id, message_id, pinned_by, pinned_at
+            FROM chat_pins
+            WHERE chat_id = $1 AND message_id = $2
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(message_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(pin) = pin {
+            return Ok(pin);
+        }
+
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM chat_pins WHERE chat_id = $1")
+            .bind(chat_id as i64)
+            .fetch_one(&mut *tx)
+            .await?;
+        let max_pins = self.config.chat.max_pins;
+        if count as u64 >= max_pins {
+            return Err(AppError::PinMessageError(format!(
+                "Chat {chat_id} cannot have more than {max_pins} pinned messages"
+            )));
+        }
+
+        let pin = sqlx::query_as(
+            r#"
+            INSERT INTO chat_pins (chat_id, message_id, pinned_by)
+            VALUES ($1, $2, $3)
+            RETURNING chat_id, message_id, pinned_by, pinned_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(message_id as i64)
+        .bind(user_id as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(pin)
+    }
+
+    pub async fn unpin_message(&self, chat_id: u64, message_id: u64) -> Result<(), AppError> {
+        let res = sqlx::query("DELETE FROM chat_pins WHERE chat_id = $1 AND message_id = $2")
+            .bind(chat_id as i64)
+            .bind(message_id as i64)
+            .execute(&self.pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(AppError::NotFountError("Pinned message not found".into()));
+        }
+
+        Ok(())
+    }
+
+    /// List all pinned messages of the chat, most recently pinned first
+    pub async fn list_pinned_messages(&self, chat_id: u64) -> Result<Vec<PinnedMessage>, AppError> {
+        let pins = sqlx::query_as(
+            r#"
+            SELECT p.pinned_by, p.pinned_at, m.id, m.chat_id, m.seq, m.sender_id, m.content, m.body, m.files, m.forwarded_from, m.kind, m.created_at, m.expires_at
+            FROM chat_pins p
+            JOIN messages m ON m.id = p.message_id
+            WHERE p.chat_id = $1 AND (m.expires_at IS NULL OR m.expires_at > NOW())
+            ORDER BY p.pinned_at DESC, p.message_id DESC
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(pins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn pin_and_unpin_message_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let pin = state.pin_message(1, 3, 1).await?;
+        assert_eq!(pin.chat_id, 1);
+        assert_eq!(pin.message_id, 3);
+        assert_eq!(pin.pinned_by, 1);
+
+        // pin again should return the existing pin
+        let pinned = state.pin_message(1, 3, 2).await?;
+        assert_eq!(pinned, pin);
+
+        state.pin_message(1, 5, 2).await?;
+        let pins = state.list_pinned_messages(1).await?;
+        assert_eq!(pins.len(), 2);
+        assert_eq!(pins[0].message.id, 5);
+        assert_eq!(pins[0].pinned_by, 2);
+        assert_eq!(pins[1].message.id, 3);
+
+        state.unpin_message(1, 3).await?;
+        let pins = state.list_pinned_messages(1).await?;
+        assert_eq!(pins.len(), 1);
+        assert_eq!(pins[0].message.id, 5);
+
+        // unpin a message which is not pinned should fail
+        let err = state.unpin_message(1, 3).await.unwrap_err();
+        assert_eq!(err.to_string(), "not fount error: Pinned message not found");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pin_message_from_other_chat_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let err = state.pin_message(2, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "not fount error: Message 1 not found in chat 2"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pin_message_over_limit_should_fail() -> Result<()> {
+        let (_tdb, mut state) = AppState::new_for_test().await?;
+        Arc::get_mut(&mut state.inner)
+            .expect("state should not be shared")
+            .config
+            .chat
+            .max_pins = 2;
+
+        state.pin_message(1, 1, 1).await?;
+        state.pin_message(1, 2, 1).await?;
+        let err = state.pin_message(1, 3, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "pin message error: Chat 1 cannot have more than 2 pinned messages"
+        );
+
+        // repin an existing message is still allowed
+        state.pin_message(1, 2, 1).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,403 @@
+use crate::{AppError, AppState, CreateMessage};
+use chat_core::{ContentBlock, MessageBody};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::collections::BTreeSet;
+use utoipa::ToSchema;
+
+const MAX_POLL_OPTIONS: usize = 10;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct CreatePoll {
+    pub question: String,
+    pub options: Vec<String>,
+    /// allow voting for more than one option
+    #[serde(default)]
+    pub multiple: bool,
+    /// hide who voted for what
+    #[serde(default)]
+    pub anonymous: bool,
+    /// no more votes are accepted after this time
+    pub closes_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct CreateVote {
+    /// indexes of the chosen options, replacing previous votes of the user, empty to retract
+    pub options: Vec<u32>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct Poll {
+    /// id of the poll message
+    pub id: i64,
+    pub chat_id: i64,
+    pub creator_id: i64,
+    pub question: String,
+    pub options: Vec<String>,
+    pub multiple: bool,
+    pub anonymous: bool,
+    pub closes_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct PollResult {
+    #[serde(flatten)]
+    pub poll: Poll,
+    pub closed: bool,
+    pub total_voters: i64,
+    /// results of each option, in the order of the options
+    pub results: Vec<PollOptionResult>,
+    /// options chosen by the current user
+    pub voted: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct PollOptionResult {
+    pub votes: i64,
+    /// empty if the poll is anonymous
+    pub voters: Vec<i64>,
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// Create a poll with its message in the chat, the poll shares the id of the message
+    pub async fn create_poll(
+        &self,
+        input: CreatePoll,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<PollResult, AppError> {
+        let question = input.question.trim();
+        if question.is_empty() {
+            return Err(AppError::PollError("Question cannot be empty".to_string()));
+        }
+        if input.options.len() < 2 || input.options.len() > MAX_POLL_OPTIONS {
+            return Err(AppError::PollError(format!(
+                "Poll should have 2 to {} options",
+                MAX_POLL_OPTIONS
+            )));
+        }
+        let options: Vec<String> = input.options.iter().map(|o| o.trim().to_string()).collect();
+        let unique: BTreeSet<&str> = options.iter().map(String::as_str).collect();
+        if unique.contains("") || unique.len() != options.len() {
+            return Err(AppError::PollError(
+                "Options should be unique and not empty".to_string(),
+            ));
+        }
+        if input.closes_at.is_some_and(|t| t <= Utc::now()) {
+            return Err(AppError::PollError(
+                "Close time should be in the future".to_string(),
+            ));
+        }
+
+        let body = MessageBody::new(vec![ContentBlock::Poll {
+            question: question.to_string(),
+            options: options.clone(),
+        }]);
+        let message = CreateMessage {
+            content: body.to_plain_text(),
+            body: Some(body),
+            ..Default::default()
+        };
+        let mut tx = self.pool.begin().await?;
+        self.verify_chat_postable(&mut tx, chat_id, user_id).await?;
+        let content = message.content.clone();
+        let message = Self::insert_message(&mut *tx, &message, content, chat_id, user_id).await?;
+        let poll: Poll = sqlx::query_as(
+            r#"
+            INSERT INTO polls (id, chat_id, creator_id, question, options, multiple, anonymous, closes_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id, chat_id, creator_id, question, options, multiple, anonymous, closes_at, created_at
+            "#,
+        )
+        .bind(message.id)
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(question)
+        .bind(&options)
+        .bind(input.multiple)
+        .bind(input.anonymous)
+        .bind(input.closes_at)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        self.poll_result(poll, user_id).await
+    }
+
+    pub async fn get_poll(
+        &self,
+        chat_id: u64,
+        poll_id: u64,
+        user_id: u64,
+    ) -> Result<PollResult, AppError> {
+        let poll = self.fetch_poll(&self.pool, chat_id, poll_id, false).await?;
+        self.poll_result(poll, user_id).await
+    }
+
+    /// Vote in the poll, the chosen options replace previous votes of the user
+    pub async fn vote_poll(
+        &self,
+        input: CreateVote,
+        chat_id: u64,
+        poll_id: u64,
+        user_id: u64,
+    ) -> Result<PollResult, AppError> {
+        let mut tx = self.pool.begin().await?;
+        // lock the poll so that the pushed results are in order of the votes
+        let poll = self.fetch_poll(&mut *tx, chat_id, poll_id, true).await?;
+        if is_closed(&poll) {
+            return Err(AppError::PollError(format!("Poll {} is closed", poll.id)));
+        }
+        let options: BTreeSet<u32> = input.options.iter().copied().collect();
+        if !poll.multiple && options.len() > 1 {
+            return Err(AppError::PollError(
+                "Only one option can be chosen".to_string(),
+            ));
+        }
+        if let Some(option) = options.iter().find(|o| **o as usize >= poll.options.len()) {
+            return Err(AppError::PollError(format!("Invalid option: {}", option)));
+        }
+        let options: Vec<i32> = options.into_iter().map(|o| o as i32).collect();
+
+        sqlx::query("DELETE FROM poll_votes WHERE poll_id = $1 AND user_id = $2")
+            .bind(poll.id)
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO poll_votes (poll_id, user_id, option_index)
+            SELECT $1, $2, UNNEST($3::int[])
+            "#,
+        )
+        .bind(poll.id)
+        .bind(user_id as i64)
+        .bind(&options)
+        .execute(&mut *tx)
+        .await?;
+        // push the new results to chat members
+        sqlx::query("UPDATE polls SET updated_at = NOW() WHERE id = $1")
+            .bind(poll.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        self.poll_result(poll, user_id).await
+    }
+
+    async fn fetch_poll<'e>(
+        &self,
+        executor: impl sqlx::PgExecutor<'e>,
+        chat_id: u64,
+        poll_id: u64,
+        lock: bool,
+    ) -> Result<Poll, AppError> {
+        let sql = format!(
+            r#"
+            SELECT id, chat_id, creator_id, question, options, multiple, anonymous, closes_at, created_at
+            FROM polls
+            WHERE id = $1 AND chat_id = $2
+            {}
+            "#,
+            if lock { "FOR UPDATE" } else { "" }
+        );
+        let poll: Option<Poll> = sqlx::query_as(&sql)
+            .bind(poll_id as i64)
+            .bind(chat_id as i64)
+            .fetch_optional(executor)
+            .await?;
+
+        poll.ok_or_else(|| {
+            AppError::NotFountError(format!("Poll {poll_id} not found in chat {chat_id}"))
+        })
+    }
+
+    async fn poll_result(&self, poll: Poll, user_id: u64) -> Result<PollResult, AppError> {
+        let votes: Vec<(i32, i64)> = sqlx::query_as(
+            "SELECT option_index, user_id FROM poll_votes WHERE poll_id = $1 ORDER BY created_at, user_id",
+        )
+        .bind(poll.id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut results = vec![
+            PollOptionResult {
+                votes: 0,
+                voters: vec![],
+            };
+            poll.options.len()
+        ];
+        let mut voters = BTreeSet::new();
+        let mut voted = vec![];
+        for (option, voter) in votes {
+            let Some(result) = results.get_mut(option as usize) else {
+                continue;
+            };
+            result.votes += 1;
+            if !poll.anonymous {
+                result.voters.push(voter);
+            }
+            if voter == user_id as i64 {
+                voted.push(option as u32);
+            }
+            voters.insert(voter);
+        }
+        voted.sort_unstable();
+
+        Ok(PollResult {
+            closed: is_closed(&poll),
+            total_voters: voters.len() as i64,
+            results,
+            voted,
+            poll,
+        })
+    }
+}
+
+fn is_closed(poll: &Poll) -> bool {
+    poll.closes_at.is_some_and(|t| t <= Utc::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn create_poll(multiple: bool, anonymous: bool) -> CreatePoll {
+        CreatePoll {
+            question: "lunch?".to_string(),
+            options: vec![
+                "ramen".to_string(),
+                "sushi".to_string(),
+                "curry".to_string(),
+            ],
+            multiple,
+            anonymous,
+            closes_at: None,
+        }
+    }
+
+    fn vote(options: &[u32]) -> CreateVote {
+        CreateVote {
+            options: options.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn create_poll_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let poll = state.create_poll(create_poll(false, false), 2, 1).await?;
+        assert_eq!(poll.poll.question, "lunch?");
+        assert_eq!(poll.results.len(), 3);
+        assert_eq!(poll.total_voters, 0);
+        assert!(!poll.closed);
+
+        // the poll message is sent to the chat with the same id
+        let page = state
+            .list_messages(crate::ListMessages::default(), 2)
+            .await?;
+        let message = &page.messages[0];
+        assert_eq!(message.id, poll.poll.id);
+        assert_eq!(message.content, "lunch?\n- ramen\n- sushi\n- curry");
+
+        let mut input = create_poll(false, false);
+        input.options = vec!["ramen".to_string(), " ramen ".to_string()];
+        let err = state.create_poll(input, 2, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "poll error: Options should be unique and not empty"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn vote_poll_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let poll = state.create_poll(create_poll(false, false), 2, 1).await?;
+        let id = poll.poll.id as u64;
+
+        let result = state.vote_poll(vote(&[1]), 2, id, 1).await?;
+        assert_eq!(result.voted, vec![1]);
+        state.vote_poll(vote(&[1]), 2, id, 2).await?;
+        // vote again replaces the previous vote
+        state.vote_poll(vote(&[0]), 2, id, 3).await?;
+        let result = state.vote_poll(vote(&[2]), 2, id, 3).await?;
+        assert_eq!(result.total_voters, 3);
+        assert_eq!(
+            result.results,
+            vec![
+                PollOptionResult {
+                    votes: 0,
+                    voters: vec![]
+                },
+                PollOptionResult {
+                    votes: 2,
+                    voters: vec![1, 2]
+                },
+                PollOptionResult {
+                    votes: 1,
+                    voters: vec![3]
+                },
+            ]
+        );
+
+        let err = state.vote_poll(vote(&[0, 1]), 2, id, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "poll error: Only one option can be chosen");
+        let err = state.vote_poll(vote(&[3]), 2, id, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "poll error: Invalid option: 3");
+        let err = state.vote_poll(vote(&[0]), 1, id, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("not fount error: Poll {id} not found in chat 1")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn vote_anonymous_multiple_poll_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let poll = state.create_poll(create_poll(true, true), 2, 1).await?;
+        let id = poll.poll.id as u64;
+
+        state.vote_poll(vote(&[0, 2]), 2, id, 1).await?;
+        let result = state.vote_poll(vote(&[2]), 2, id, 2).await?;
+        assert_eq!(result.total_voters, 2);
+        assert_eq!(result.voted, vec![2]);
+        let votes: Vec<_> = result.results.iter().map(|r| r.votes).collect();
+        assert_eq!(votes, vec![1, 0, 2]);
+        assert!(result.results.iter().all(|r| r.voters.is_empty()));
+
+        let result = state.get_poll(2, id, 1).await?;
+        assert_eq!(result.voted, vec![0, 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn vote_closed_poll_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut input = create_poll(false, false);
+        input.closes_at = Some(Utc::now() + chrono::Duration::hours(1));
+        let poll = state.create_poll(input, 2, 1).await?;
+        let id = poll.poll.id;
+        state.vote_poll(vote(&[0]), 2, id as _, 1).await?;
+
+        sqlx::query("UPDATE polls SET closes_at = NOW() - INTERVAL '1 second' WHERE id = $1")
+            .bind(id)
+            .execute(&state.pool)
+            .await?;
+        let err = state
+            .vote_poll(vote(&[1]), 2, id as _, 2)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), format!("poll error: Poll {id} is closed"));
+
+        let result = state.get_poll(2, id as _, 2).await?;
+        assert!(result.closed);
+        assert_eq!(result.total_voters, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{AppError, AppState};
+use chat_core::ReadState;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct MarkRead {
+    /// seq of the last message read, capped by the last message of the chat
+    pub seq: u64,
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// Mark messages of the chat read up to `seq`, the read state never moves backwards
+    pub async fn mark_chat_read(
+        &self,
+        input: MarkRead,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<ReadState, AppError> {
+        let state = sqlx::query_as(
+            r#"
+            UPDATE chat_members
+            SET last_read_seq = LEAST($3, COALESCE((SELECT last_seq FROM chat_seqs WHERE chat_id = $2), 0)),
+              read_at = NOW()
+            WHERE user_id = $1 AND chat_id = $2
+              AND last_read_seq < LEAST($3, COALESCE((SELECT last_seq FROM chat_seqs WHERE chat_id = $2), 0))
+            RETURNING user_id, chat_id, last_read_seq, read_at AS updated_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(chat_id as i64)
+        .bind(input.seq.min(i64::MAX as u64) as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match state {
+            Some(state) => Ok(state),
+            // nothing new was read, return the current state
+            None => self.get_read_state(chat_id, user_id).await,
+        }
+    }
+
+    pub async fn get_read_state(&self, chat_id: u64, user_id: u64) -> Result<ReadState, AppError> {
+        let state: Option<ReadState> = sqlx::query_as(
+            r#"
+            SELECT user_id, chat_id, last_read_seq, read_at AS updated_at
+            FROM chat_members
+            WHERE user_id = $1 AND chat_id = $2
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(chat_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        state.ok_or_else(|| {
+            AppError::NotFountError(format!(
+                "User {} is not a member of chat {}",
+                user_id, chat_id
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn mark_chat_read_should_only_move_forward() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let read = state.mark_chat_read(MarkRead { seq: 5 }, 1, 1).await?;
+        assert_eq!(read.last_read_seq, 5);
+
+        let read = state.mark_chat_read(MarkRead { seq: 3 }, 1, 1).await?;
+        assert_eq!(read.last_read_seq, 5);
+
+        // chat 1 has 10 messages
+        let read = state.mark_chat_read(MarkRead { seq: 100 }, 1, 1).await?;
+        assert_eq!(read.last_read_seq, 10);
+        assert_eq!(state.get_read_state(1, 1).await?, read);
+
+        // an empty chat can't be read past its first message
+        let read = state.mark_chat_read(MarkRead { seq: 1 }, 2, 1).await?;
+        assert_eq!(read.last_read_seq, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,500 @@
+use crate::{AppError, AppState};
+use ammonia::{Builder, UrlRelative};
+use chat_core::{ContentBlock, Message, MessageBody};
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag, TagEnd, TextMergeStream};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
+use utoipa::ToSchema;
+
+// tags kept by the sanitizer, everything else is stripped
+const ALLOWED_TAGS: &str =
+    "p br hr strong em del code pre blockquote ul ol li a h1 h2 h3 h4 h5 h6 \
+    dl dt dd table thead tbody tr th td";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageFormat {
+    /// content and body as stored
+    #[default]
+    Raw,
+    /// plain text content only
+    Text,
+    /// raw message with sanitized html rendered from markdown
+    Html,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Mention<'a> {
+    User(&'a str),
+    Channel(&'a str),
+}
+
+// names mentioned in messages resolved to user / chat ids of the workspace
+#[derive(Debug, Default)]
+pub(crate) struct MentionTargets {
+    users: HashMap<String, i64>,
+    channels: HashMap<String, i64>,
+}
+
+impl AppState {
+    /// Shape the messages of a chat according to the requested format, mentions are resolved
+    /// for the user viewing them
+    pub async fn format_messages(
+        &self,
+        messages: &mut [Message],
+        format: MessageFormat,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<(), AppError> {
+        match format {
+            MessageFormat::Raw => {}
+            MessageFormat::Text => messages.iter_mut().for_each(|m| m.body = None),
+            MessageFormat::Html => {
+                let targets = self.resolve_mentions(messages, chat_id, user_id).await?;
+                let sanitizer = sanitizer();
+                for message in messages.iter_mut() {
+                    let html = render_message_html(message, &targets);
+                    message.html = Some(sanitizer.clean(&html).to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // private channels are only resolved for their members, others can't learn about them
+    async fn resolve_mentions(
+        &self,
+        messages: &[Message],
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<MentionTargets, AppError> {
+        let mut users = HashSet::new();
+        let mut channels = HashSet::new();
+        for message in messages {
+            for text in markdown_texts(message) {
+                for (_, mention) in find_mentions(text) {
+                    match mention {
+                        Mention::User(name) => users.insert(name.to_string()),
+                        Mention::Channel(name) => channels.insert(name.to_string()),
+                    };
+                }
+            }
+        }
+
+        let mut targets = MentionTargets::default();
+        if !users.is_empty() {
+            let recs: Vec<(i64, String)> = sqlx::query_as(
+                r#"
+                SELECT u.id, u.name
+                FROM users u
+                JOIN chats c ON c.ws_id = u.ws_id
+                WHERE c.id = $1 AND u.name = ANY($2)
+                ORDER BY u.id
+                "#,
+            )
+            .bind(chat_id as i64)
+            .bind(users.into_iter().collect::<Vec<_>>())
+            .fetch_all(&self.pool)
+            .await?;
+            for (id, name) in recs {
+                targets.users.entry(name).or_insert(id);
+            }
+        }
+        if !channels.is_empty() {
+            let recs: Vec<(i64, String)> = sqlx::query_as(
+                r#"
+                SELECT ch.id, ch.name
+                FROM chats ch
+                JOIN chats c ON c.ws_id = ch.ws_id
+                WHERE c.id = $1 AND ch.name = ANY($2)
+                AND (ch.type = 'public_channel' OR (ch.type = 'private_channel' AND EXISTS(
+                  SELECT 1 FROM chat_members m WHERE m.chat_id = ch.id AND m.user_id = $3)))
+                ORDER BY ch.id
+                "#,
+            )
+            .bind(chat_id as i64)
+            .bind(channels.into_iter().collect::<Vec<_>>())
+            .bind(user_id as i64)
+            .fetch_all(&self.pool)
+            .await?;
+            for (id, name) in recs {
+                targets.channels.entry(name).or_insert(id);
+            }
+        }
+        Ok(targets)
+    }
+}
+
+/// Names of the users mentioned by a message, mentions in code are skipped as when rendered
+pub(crate) fn mentioned_user_names(body: Option<&MessageBody>, content: &str) -> Vec<String> {
+    let mut names = HashSet::new();
+    for text in markdown_sources(body, content) {
+        let mut in_code_block = false;
+        for event in TextMergeStream::new(Parser::new_ext(text, markdown_options())) {
+            match event {
+                Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+                Event::End(TagEnd::CodeBlock) => in_code_block = false,
+                Event::Text(text) if !in_code_block => {
+                    for (_, mention) in find_mentions(&text) {
+                        if let Mention::User(name) = mention {
+                            names.insert(name.to_string());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    names.into_iter().collect()
+}
+
+// markdown sources of the message which may contain mentions
+fn markdown_texts(message: &Message) -> Vec<&str> {
+    markdown_sources(message.body.as_ref(), &message.content)
+}
+
+fn markdown_sources<'a>(body: Option<&'a MessageBody>, content: &'a str) -> Vec<&'a str> {
+    match body {
+        Some(body) => body
+            .blocks
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::Markdown { text } | ContentBlock::Quote { text, .. } => {
+                    Some(text.as_str())
+                }
+                _ => None,
+            })
+            .collect(),
+        None => vec![content],
+    }
+}
+
+fn render_message_html(message: &Message, targets: &MentionTargets) -> String {
+    match &message.body {
+        Some(body) => render_body_html(body, targets),
+        None => render_markdown(&message.content, targets),
+    }
+}
+
+fn render_body_html(body: &MessageBody, targets: &MentionTargets) -> String {
+    let mut out = String::new();
+    for block in &body.blocks {
+        match block {
+            ContentBlock::Markdown { text } => out.push_str(&render_markdown(text, targets)),
+            ContentBlock::Code { language, code } => {
+                let class = match language {
+                    Some(lang) if is_language(lang) => format!(" class=\"language-{}\"", lang),
+                    _ => String::new(),
+                };
+                out.push_str(&format!(
+                    "<pre><code{}>{}</code></pre>",
+                    class,
+                    ammonia::clean_text(code)
+                ));
+            }
+            ContentBlock::Quote { text, author } => {
+                out.push_str("<blockquote>");
+                out.push_str(&render_markdown(text, targets));
+                if let Some(author) = author {
+                    out.push_str(&format!("<p>-- {}</p>", ammonia::clean_text(author)));
+                }
+                out.push_str("</blockquote>");
+            }
+            ContentBlock::Attachment { url, name, .. } => {
+                out.push_str(&format!(
+                    "<p><a href=\"{}\">{}</a></p>",
+                    ammonia::clean_text(url),
+                    ammonia::clean_text(name)
+                ));
+            }
+            // interactive blocks are rendered by clients from the body
+            ContentBlock::Buttons { .. } | ContentBlock::Poll { .. } => {}
+            ContentBlock::System { text, .. } => {
+                out.push_str(&format!("<p>{}</p>", ammonia::clean_text(text)));
+            }
+            ContentBlock::Fields { fields } => {
+                out.push_str("<dl>");
+                for field in fields {
+                    out.push_str(&format!(
+                        "<dt>{}</dt><dd>{}</dd>",
+                        ammonia::clean_text(&field.title),
+                        ammonia::clean_text(&field.value)
+                    ));
+                }
+                out.push_str("</dl>");
+            }
+        }
+    }
+    out
+}
+
+/// Render markdown to html with mentions linked, the output still needs to be sanitized
+fn render_markdown(text: &str, targets: &MentionTargets) -> String {
+    let mut in_code_block = false;
+    let events =
+        TextMergeStream::new(Parser::new_ext(text, markdown_options())).flat_map(|event| {
+            match event {
+                Event::Start(Tag::CodeBlock(_)) => {
+                    in_code_block = true;
+                    vec![event]
+                }
+                Event::End(TagEnd::CodeBlock) => {
+                    in_code_block = false;
+                    vec![event]
+                }
+                Event::Text(text) if !in_code_block => link_mentions(text, targets),
+                // raw html written by users is displayed as text, never rendered
+                Event::Html(html) | Event::InlineHtml(html) => vec![Event::Text(html)],
+                _ => vec![event],
+            }
+        });
+
+    let mut out = String::new();
+    html::push_html(&mut out, events);
+    out
+}
+
+fn link_mentions<'a>(text: CowStr<'a>, targets: &MentionTargets) -> Vec<Event<'a>> {
+    let mentions = find_mentions(&text);
+    if mentions.is_empty() {
+        return vec![Event::Text(text)];
+    }
+
+    let mut events = vec![];
+    let mut last = 0;
+    for ((start, end), mention) in mentions {
+        let link = match mention {
+            Mention::User(name) => targets.users.get(name).map(|id| {
+                format!(
+                    "<a class=\"mention\" data-user-id=\"{id}\" href=\"/users/{id}\">@{name}</a>"
+                )
+            }),
+            Mention::Channel(name) => targets.channels.get(name).map(|id| {
+                format!(
+                    "<a class=\"channel\" data-chat-id=\"{id}\" href=\"/chats/{id}\">#{name}</a>"
+                )
+            }),
+        };
+        // unresolved mentions stay as plain text
+        if let Some(link) = link {
+            if start > last {
+                events.push(Event::Text(text[last..start].to_string().into()));
+            }
+            events.push(Event::InlineHtml(link.into()));
+            last = end;
+        }
+    }
+    if last < text.len() {
+        events.push(Event::Text(text[last..].to_string().into()));
+    }
+    events
+}
+
+fn markdown_options() -> Options {
+    Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES
+}
+
+// find `@user` and `#channel` mentions, returns the byte range of each mention
+fn find_mentions(text: &str) -> Vec<((usize, usize), Mention<'_>)> {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.');
+    let mut mentions = vec![];
+    let mut prev = None;
+    for (start, c) in text.char_indices() {
+        let at_boundary = prev.is_none_or(|p: char| !p.is_alphanumeric() && p != '_');
+        prev = Some(c);
+        if !at_boundary || !matches!(c, '@' | '#') {
+            continue;
+        }
+        let rest = &text[start + 1..];
+        let len = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+        // trailing punctuation belongs to the sentence
+        let name = rest[..len].trim_end_matches(['.', '-']);
+        if name.is_empty() {
+            continue;
+        }
+        let mention = match c {
+            '@' => Mention::User(name),
+            _ => Mention::Channel(name),
+        };
+        mentions.push(((start, start + 1 + name.len()), mention));
+    }
+    mentions
+}
+
+fn is_language(lang: &str) -> bool {
+    !lang.is_empty()
+        && lang
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '#' | '-'))
+}
+
+fn sanitizer() -> Builder<'static> {
+    let mut builder = Builder::empty();
+    builder
+        .tags(ALLOWED_TAGS.split_whitespace().collect())
+        .tag_attributes(HashMap::from([
+            (
+                "a",
+                HashSet::from(["href", "class", "data-user-id", "data-chat-id"]),
+            ),
+            ("code", HashSet::from(["class"])),
+            ("ol", HashSet::from(["start"])),
+        ]))
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .url_relative(UrlRelative::PassThrough)
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .attribute_filter(|element, attribute, value| {
+            let allowed = match (element, attribute) {
+                ("a", "class") => matches!(value, "mention" | "channel"),
+                ("a", "data-user-id") | ("a", "data-chat-id") => {
+                    value.chars().all(|c| c.is_ascii_digit())
+                }
+                ("code", "class") => value.strip_prefix("language-").is_some_and(is_language),
+                _ => true,
+            };
+            allowed.then_some(Cow::Borrowed(value))
+        });
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn render(text: &str) -> String {
+        let targets = MentionTargets {
+            users: HashMap::from([("taki".to_string(), 3)]),
+            channels: HashMap::from([("public_chat".to_string(), 4)]),
+        };
+        sanitizer()
+            .clean(&render_markdown(text, &targets))
+            .to_string()
+    }
+
+    #[test]
+    fn find_mentions_should_work() {
+        let mentions = find_mentions("hi @taki, join #public_chat. mail a@b.c @");
+        assert_eq!(
+            mentions,
+            vec![
+                ((3, 8), Mention::User("taki")),
+                ((15, 27), Mention::Channel("public_chat")),
+            ]
+        );
+    }
+
+    #[test]
+    fn mentioned_user_names_should_skip_code() {
+        let mut names = mentioned_user_names(None, "hi @taki and @mitsuha, see `@yotsuha`");
+        names.sort();
+        assert_eq!(names, vec!["mitsuha", "taki"]);
+        let body = MessageBody::new(vec![
+            ContentBlock::Markdown {
+                text: "```\n@taki\n```\n@okudera".to_string(),
+            },
+            ContentBlock::Code {
+                language: None,
+                code: "@sayaka".to_string(),
+            },
+        ]);
+        assert_eq!(
+            mentioned_user_names(Some(&body), "ignored @taki"),
+            vec!["okudera"]
+        );
+    }
+
+    #[test]
+    fn render_markdown_should_link_mentions() {
+        assert_eq!(
+            render("hi **@taki** see #public_chat and @nobody"),
+            "<p>hi <strong><a class=\"mention\" data-user-id=\"3\" href=\"/users/3\" rel=\"noopener noreferrer nofollow\">@taki</a></strong> see <a class=\"channel\" data-chat-id=\"4\" href=\"/chats/4\" rel=\"noopener noreferrer nofollow\">#public_chat</a> and @nobody</p>\n"
+        );
+        // mentions in code are not linked
+        assert_eq!(
+            render("`@taki`\n\n```\n@taki\n```"),
+            "<p><code>@taki</code></p>\n<pre><code>@taki\n</code></pre>\n"
+        );
+    }
+
+    #[test]
+    fn render_markdown_should_sanitize_html() {
+        assert_eq!(
+            render("<script>alert(1)</script><b onclick=\"x()\">hi</b>"),
+            "&lt;script&gt;alert(1)&lt;/script&gt;&lt;b onclick=\"x()\"&gt;hi&lt;/b&gt;"
+        );
+        assert_eq!(
+            render("[click](javascript:alert(1)) [ok](https://example.com)"),
+            "<p><a rel=\"noopener noreferrer nofollow\">click</a> <a href=\"https://example.com\" rel=\"noopener noreferrer nofollow\">ok</a></p>\n"
+        );
+        // images are not allowed
+        assert_eq!(render("![img](https://example.com/x.png)"), "<p></p>\n");
+    }
+
+    #[tokio::test]
+    async fn format_messages_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = crate::CreateMessage {
+            content: "ping @mitsuha in #group_chat and #public_chat".to_string(),
+            ..Default::default()
+        };
+        let message = state.create_message(input, 1, 1).await?;
+
+        let mut messages = vec![message.clone()];
+        state
+            .format_messages(&mut messages, MessageFormat::Html, 1, 1)
+            .await?;
+        let html = messages[0].html.as_ref().expect("html should exist");
+        assert!(html.contains("data-user-id=\"1\""));
+        // group chat is not a channel
+        assert!(html.contains("#group_chat"));
+        assert!(!html.contains("data-chat-id=\"2\""));
+        assert!(html.contains("data-chat-id=\"4\""));
+
+        let mut messages = vec![message];
+        state
+            .format_messages(&mut messages, MessageFormat::Text, 1, 1)
+            .await?;
+        assert_eq!(messages[0].html, None);
+        assert_eq!(messages[0].body, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn private_channel_mentions_should_only_resolve_for_members() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = crate::CreateChat {
+            name: Some("secret".to_string()),
+            public: false,
+            members: vec![1, 5],
+            expires_after: None,
+        };
+        let secret = state.create_chat(&input, 0, 1).await?;
+        let input = crate::CreateMessage {
+            content: "is there a #secret channel?".to_string(),
+            ..Default::default()
+        };
+        let message = state.create_message(input, 1, 2).await?;
+        let link = format!("data-chat-id=\"{}\"", secret.id);
+
+        // yotsuha is not a member of the channel
+        let mut messages = vec![message.clone()];
+        state
+            .format_messages(&mut messages, MessageFormat::Html, 1, 2)
+            .await?;
+        let html = messages[0].html.as_ref().expect("html should exist");
+        assert!(html.contains("#secret"));
+        assert!(!html.contains(&link));
+
+        let mut messages = vec![message];
+        state
+            .format_messages(&mut messages, MessageFormat::Html, 1, 1)
+            .await?;
+        let html = messages[0].html.as_ref().expect("html should exist");
+        assert!(html.contains(&link));
+        Ok(())
+    }
+}
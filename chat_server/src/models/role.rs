@@ -0,0 +1,349 @@
+use crate::{AppError, AppState};
+use chat_core::ChatRole;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+use std::fmt;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct ChatMember {
+    pub user_id: i64,
+    pub role: ChatRole,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UpdateChatRole {
+    /// admin or member, use the transfer api to change the owner
+    pub role: ChatRole,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TransferOwnership {
+    /// member who becomes the owner, the current owner becomes an admin
+    pub user_id: u64,
+}
+
+/// What a member does to a chat, allowed or not by the role of the member.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatAction {
+    Rename,
+    EditDetails,
+    ChangeType,
+    ChangeSettings,
+    AddMembers,
+    RemoveMembers,
+    Delete,
+    Archive,
+    ManageRoles,
+    TransferOwnership,
+    Leave,
+}
+
+impl ChatAction {
+    /// The chat policy: members can only leave, admins manage the chat and its members,
+    /// owners also manage roles. The owner has to transfer the ownership before leaving.
+    pub fn is_allowed(self, role: ChatRole) -> bool {
+        match self {
+            ChatAction::Leave => role != ChatRole::Owner,
+            ChatAction::ManageRoles | ChatAction::TransferOwnership => role == ChatRole::Owner,
+            _ => role != ChatRole::Member,
+        }
+    }
+}
+
+impl fmt::Display for ChatAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ChatAction::Rename => "rename the chat",
+            ChatAction::EditDetails => "edit the chat details",
+            ChatAction::ChangeType => "change the chat type",
+            ChatAction::ChangeSettings => "change the chat settings",
+            ChatAction::AddMembers => "add members",
+            ChatAction::RemoveMembers => "remove members",
+            ChatAction::Delete => "delete the chat",
+            ChatAction::Archive => "archive the chat",
+            ChatAction::ManageRoles => "change member roles",
+            ChatAction::TransferOwnership => "transfer the ownership",
+            ChatAction::Leave => "leave the chat before transferring the ownership",
+        };
+        f.write_str(s)
+    }
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// Members of the chat with their role
+    pub async fn list_chat_members(&self, chat_id: u64) -> Result<Vec<ChatMember>, AppError> {
+        let members = sqlx::query_as(
+            r#"
+            SELECT user_id, role
+            FROM chat_members
+            WHERE chat_id = $1
+            ORDER BY user_id
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(members)
+    }
+
+    /// Role of a member of the chat
+    pub async fn get_chat_role(&self, chat_id: u64, user_id: u64) -> Result<ChatRole, AppError> {
+        let role: Option<ChatRole> =
+            sqlx::query_scalar("SELECT role FROM chat_members WHERE chat_id = $1 AND user_id = $2")
+                .bind(chat_id as i64)
+                .bind(user_id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(role.unwrap_or(ChatRole::Member))
+    }
+
+    /// Check the chat policy for the actions of the user. The member row stays locked until
+    /// the transaction ends, so the role can't change before the authorized write.
+    pub async fn authorize_chat(
+        conn: &mut PgConnection,
+        chat_id: u64,
+        user_id: u64,
+        actions: &[ChatAction],
+    ) -> Result<ChatRole, AppError> {
+        let role: Option<ChatRole> = sqlx::query_scalar(
+            "SELECT role FROM chat_members WHERE chat_id = $1 AND user_id = $2 FOR UPDATE",
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(conn)
+        .await?;
+        let role = role.unwrap_or(ChatRole::Member);
+        if let Some(action) = actions.iter().find(|a| !a.is_allowed(role)) {
+            return Err(AppError::PermissionDenied(format!(
+                "{} can't {}",
+                role_name(role),
+                action
+            )));
+        }
+        Ok(role)
+    }
+
+    /// Make a member an admin, or an admin a member, only the owner can do it
+    pub async fn update_chat_role(
+        &self,
+        input: UpdateChatRole,
+        chat_id: u64,
+        user_id: u64,
+        member_id: u64,
+    ) -> Result<ChatMember, AppError> {
+        let mut tx = self.pool.begin().await?;
+        Self::authorize_chat(&mut tx, chat_id, user_id, &[ChatAction::ManageRoles]).await?;
+        if input.role == ChatRole::Owner {
+            return Err(AppError::PermissionDenied(
+                "The owner can only be changed by transferring the ownership".into(),
+            ));
+        }
+        if member_id == user_id {
+            return Err(AppError::PermissionDenied(
+                "The owner can't change its own role".into(),
+            ));
+        }
+        let member: Option<ChatMember> = sqlx::query_as(
+            r#"
+            UPDATE chat_members SET role = $3
+            WHERE chat_id = $1 AND user_id = $2
+            RETURNING user_id, role
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(member_id as i64)
+        .bind(input.role)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        member.ok_or_else(|| {
+            AppError::NotFountError(format!(
+                "User {} is not a member of chat {}",
+                member_id, chat_id
+            ))
+        })
+    }
+
+    /// Give the ownership of the chat to another member, the current owner becomes an admin
+    pub async fn transfer_chat_ownership(
+        &self,
+        input: TransferOwnership,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Vec<ChatMember>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        Self::authorize_chat(&mut tx, chat_id, user_id, &[ChatAction::TransferOwnership]).await?;
+        if input.user_id == user_id {
+            return Err(AppError::PermissionDenied(
+                "The chat is already owned by the user".into(),
+            ));
+        }
+        if !self.is_chat_member(chat_id, input.user_id).await? {
+            return Err(AppError::NotFountError(format!(
+                "User {} is not a member of chat {}",
+                input.user_id, chat_id
+            )));
+        }
+
+        // the old owner is demoted first, a chat has at most one owner
+        sqlx::query("UPDATE chat_members SET role = 'admin' WHERE chat_id = $1 AND user_id = $2")
+            .bind(chat_id as i64)
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE chat_members SET role = 'owner' WHERE chat_id = $1 AND user_id = $2")
+            .bind(chat_id as i64)
+            .bind(input.user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        self.list_chat_members(chat_id).await
+    }
+}
+
+pub(crate) fn role_name(role: ChatRole) -> &'static str {
+    match role {
+        ChatRole::Owner => "owner",
+        ChatRole::Admin => "admin",
+        ChatRole::Member => "member",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn chat_policy_should_work() {
+        use ChatAction::*;
+        let manage = [
+            Rename,
+            EditDetails,
+            ChangeType,
+            ChangeSettings,
+            AddMembers,
+            RemoveMembers,
+            Delete,
+            Archive,
+        ];
+        for action in manage {
+            assert!(action.is_allowed(ChatRole::Owner));
+            assert!(action.is_allowed(ChatRole::Admin));
+            assert!(!action.is_allowed(ChatRole::Member));
+        }
+        for action in [ManageRoles, TransferOwnership] {
+            assert!(action.is_allowed(ChatRole::Owner));
+            assert!(!action.is_allowed(ChatRole::Admin));
+            assert!(!action.is_allowed(ChatRole::Member));
+        }
+        assert!(!Leave.is_allowed(ChatRole::Owner));
+        assert!(Leave.is_allowed(ChatRole::Admin));
+        assert!(Leave.is_allowed(ChatRole::Member));
+    }
+
+    #[tokio::test]
+    async fn list_chat_members_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let members = state.list_chat_members(2).await?;
+        let roles: Vec<_> = members.iter().map(|m| m.role).collect();
+        assert_eq!(
+            roles,
+            vec![
+                ChatRole::Owner,
+                ChatRole::Admin,
+                ChatRole::Member,
+                ChatRole::Member,
+                ChatRole::Member
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_chat_role_should_only_be_done_by_owner() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateChatRole {
+            role: ChatRole::Admin,
+        };
+        let err = state
+            .update_chat_role(input.clone(), 2, 2, 3)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: admin can't change member roles"
+        );
+
+        let member = state.update_chat_role(input, 2, 1, 3).await?;
+        assert_eq!(member.role, ChatRole::Admin);
+        assert_eq!(state.get_chat_role(2, 3).await?, ChatRole::Admin);
+
+        let input = UpdateChatRole {
+            role: ChatRole::Owner,
+        };
+        assert!(state.update_chat_role(input, 2, 1, 3).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transfer_chat_ownership_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = TransferOwnership { user_id: 3 };
+        let err = state
+            .transfer_chat_ownership(input.clone(), 2, 2)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: admin can't transfer the ownership"
+        );
+
+        state.transfer_chat_ownership(input, 2, 1).await?;
+        assert_eq!(state.get_chat_role(2, 3).await?, ChatRole::Owner);
+        assert_eq!(state.get_chat_role(2, 1).await?, ChatRole::Admin);
+
+        // the new owner must be a member
+        let input = TransferOwnership { user_id: 4 };
+        let err = state
+            .transfer_chat_ownership(input, 1, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "not fount error: User 4 is not a member of chat 1"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn role_changes_should_wait_for_authorized_writes() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // user 2 is an admin of chat 2, checked for a write not committed yet
+        let mut tx = state.pool.begin().await?;
+        AppState::authorize_chat(&mut tx, 2, 2, &[ChatAction::Rename]).await?;
+
+        let demote = tokio::spawn({
+            let state = state.clone();
+            async move {
+                let input = UpdateChatRole {
+                    role: ChatRole::Member,
+                };
+                state.update_chat_role(input, 2, 1, 2).await
+            }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!demote.is_finished());
+        assert_eq!(state.get_chat_role(2, 2).await?, ChatRole::Admin);
+
+        tx.commit().await?;
+        assert_eq!(demote.await??.role, ChatRole::Member);
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{AppError, AppState};
+use chat_core::Message;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct SaveMessage {
+    pub message_id: u64,
+    /// remind the user of the message at this time
+    pub remind_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct SavedMessage {
+    pub remind_at: Option<DateTime<Utc>>,
+    pub reminded_at: Option<DateTime<Utc>>,
+    pub saved_at: DateTime<Utc>,
+    #[sqlx(flatten)]
+    pub message: Message,
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// Bookmark a message of a chat the user belongs to, saving it again updates the reminder
+    pub async fn save_message(&self, input: SaveMessage, user_id: u64) -> Result<(), AppError> {
+        if input.remind_at.is_some_and(|t| t <= Utc::now()) {
+            return Err(AppError::SaveMessageError(
+                "Remind time should be in the future".to_string(),
+            ));
+        }
+        let message_id = input.message_id;
+        let chat: Option<(i64,)> = sqlx::query_as(
+            r#"
+            SELECT chat_id FROM messages
+            WHERE id = $1 AND (expires_at IS NULL OR expires_at > NOW())
+            "#,
+        )
+        .bind(message_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        let chat_id = match chat {
+            Some((chat_id,)) if self.is_chat_member(chat_id as _, user_id).await? => chat_id,
+            _ => {
+                return Err(AppError::NotFountError(format!(
+                    "Message {message_id} not found"
+                )))
+            }
+        };
+
+        sqlx::query(
+            r#"
+            INSERT INTO saved_messages (user_id, message_id, chat_id, remind_at)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, message_id)
+            DO UPDATE SET remind_at = EXCLUDED.remind_at, reminded_at = NULL
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(message_id as i64)
+        .bind(chat_id)
+        .bind(input.remind_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn unsave_message(&self, message_id: u64, user_id: u64) -> Result<(), AppError> {
+        let res = sqlx::query("DELETE FROM saved_messages WHERE user_id = $1 AND message_id = $2")
+            .bind(user_id as i64)
+            .bind(message_id as i64)
+            .execute(&self.pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(AppError::NotFountError("Saved message not found".into()));
+        }
+
+        Ok(())
+    }
+
+    /// List saved messages of the user with the messages, most recently saved first, messages of
+    /// chats the user left are hidden until the user joins again
+    pub async fn list_saved_messages(&self, user_id: u64) -> Result<Vec<SavedMessage>, AppError> {
+        let saved = sqlx::query_as(
+            r#"
+            SELECT s.remind_at, s.reminded_at, s.saved_at, m.id, m.chat_id, m.seq, m.sender_id, m.content, m.body, m.files, m.forwarded_from, m.kind, m.created_at, m.expires_at
+            FROM saved_messages s
+            JOIN messages m ON m.id = s.message_id
+            JOIN chat_members cm ON cm.chat_id = m.chat_id AND cm.user_id = s.user_id
+            WHERE s.user_id = $1 AND (m.expires_at IS NULL OR m.expires_at > NOW())
+            ORDER BY s.saved_at DESC, s.message_id DESC
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(saved)
+    }
+
+    /// Mark due reminders as done, which pushes them to the users, returns the number of
+    /// fired reminders, users are only reminded of messages of their chats
+    pub async fn fire_reminders(&self) -> Result<u64, AppError> {
+        let res = sqlx::query(
+            r#"
+            UPDATE saved_messages s SET reminded_at = NOW()
+            FROM chat_members cm
+            WHERE s.remind_at <= NOW() AND s.reminded_at IS NULL
+            AND cm.chat_id = s.chat_id AND cm.user_id = s.user_id
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(res.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chrono::Duration;
+
+    fn save(message_id: u64, remind_at: Option<DateTime<Utc>>) -> SaveMessage {
+        SaveMessage {
+            message_id,
+            remind_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn save_and_list_messages_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.save_message(save(3, None), 1).await?;
+        let remind_at = Utc::now() + Duration::hours(1);
+        state.save_message(save(5, Some(remind_at)), 1).await?;
+        state.save_message(save(5, None), 2).await?;
+
+        let saved = state.list_saved_messages(1).await?;
+        assert_eq!(saved.len(), 2);
+        assert_eq!(saved[0].message.id, 5);
+        assert_eq!(saved[0].message.chat_id, 1);
+        assert!(saved[0].remind_at.is_some());
+        assert_eq!(saved[1].message.id, 3);
+        assert_eq!(saved[1].remind_at, None);
+
+        state.unsave_message(3, 1).await?;
+        let saved = state.list_saved_messages(1).await?;
+        assert_eq!(saved.len(), 1);
+        let err = state.unsave_message(3, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "not fount error: Saved message not found");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn save_message_with_invalid_input_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // user 3 is not a member of chat 1
+        let err = state.save_message(save(1, None), 3).await.unwrap_err();
+        assert_eq!(err.to_string(), "not fount error: Message 1 not found");
+
+        let err = state
+            .save_message(save(1, Some(Utc::now() - Duration::hours(1))), 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "save message error: Remind time should be in the future"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fire_reminders_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let remind_at = Utc::now() + Duration::hours(1);
+        state.save_message(save(1, Some(remind_at)), 1).await?;
+        state.save_message(save(2, Some(remind_at)), 1).await?;
+        assert_eq!(state.fire_reminders().await?, 0);
+
+        sqlx::query("UPDATE saved_messages SET remind_at = NOW() WHERE message_id = 1")
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(state.fire_reminders().await?, 1);
+        // a reminder is fired only once
+        assert_eq!(state.fire_reminders().await?, 0);
+
+        let saved = state.list_saved_messages(1).await?;
+        let item = saved.iter().find(|s| s.message.id == 1).unwrap();
+        assert!(item.reminded_at.is_some());
+
+        // saving again schedules a new reminder
+        state.save_message(save(1, Some(remind_at)), 1).await?;
+        let saved = state.list_saved_messages(1).await?;
+        let item = saved.iter().find(|s| s.message.id == 1).unwrap();
+        assert_eq!(item.reminded_at, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn saved_messages_should_need_chat_membership() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = crate::CreateMessage {
+            content: "hello".to_string(),
+            ..Default::default()
+        };
+        let message = state.create_message(input, 4, 1).await?;
+        let remind_at = Utc::now() + Duration::hours(1);
+        state
+            .save_message(save(message.id as _, Some(remind_at)), 5)
+            .await?;
+        sqlx::query("UPDATE saved_messages SET remind_at = NOW() WHERE message_id = $1")
+            .bind(message.id)
+            .execute(&state.pool)
+            .await?;
+
+        // sayaka left the chat, the message is neither listed nor reminded
+        state.leave_chat(4, 5).await?;
+        assert!(state.list_saved_messages(5).await?.is_empty());
+        assert_eq!(state.fire_reminders().await?, 0);
+
+        state.join_channel(4, 5).await?;
+        assert_eq!(state.list_saved_messages(5).await?.len(), 1);
+        assert_eq!(state.fire_reminders().await?, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,462 @@
+use crate::{AppError, AppState, CreateMessage};
+use chat_core::{Message, MessageBody};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, FromRow, PgConnection};
+use tracing::warn;
+use utoipa::ToSchema;
+
+// max number of scheduled messages delivered in one run of the scheduler
+const DELIVERY_BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct ScheduledMessage {
+    pub id: i64,
+    pub chat_id: i64,
+    pub sender_id: i64,
+    pub content: String,
+    pub body: Option<MessageBody>,
+    pub files: Vec<String>,
+    pub send_at: DateTime<Utc>,
+    pub expires_after: Option<i64>,
+    /// recorded as the idempotency key of the message once delivered
+    pub client_msg_id: Option<String>,
+    /// why the delivery failed, the message is kept until cancelled
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Outcome of scheduling a message
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scheduled {
+    Created(ScheduledMessage),
+    /// a retry before the message is delivered gets the one scheduled by the first request
+    Pending(ScheduledMessage),
+    /// a retry after the message was delivered gets the sent message
+    Sent(Message),
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// Store the message, it will be sent by the scheduler at `send_at`
+    pub async fn schedule_message(
+        &self,
+        input: CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Scheduled, AppError> {
+        let Some(send_at) = input.send_at else {
+            return Err(AppError::CreateMessageError(
+                "Scheduled message should have a send time".to_string(),
+            ));
+        };
+        let content = self.validate_message(&input)?;
+        if let Some(key) = &input.client_msg_id {
+            if let Some(message) = self.find_sent_message(key, chat_id, user_id).await? {
+                return Ok(Scheduled::Sent(message));
+            }
+        }
+        // checked again when the message is delivered
+        self.verify_chat_postable(&mut *self.pool.acquire().await?, chat_id, user_id)
+            .await?;
+
+        // a retried request returns the message scheduled by the first one
+        let scheduled: Option<ScheduledMessage> = sqlx::query_as(
+            r#"
+            INSERT INTO scheduled_messages (chat_id, sender_id, content, body, files, send_at, expires_after, client_msg_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (chat_id, sender_id, client_msg_id) WHERE client_msg_id IS NOT NULL DO NOTHING
+            RETURNING id, chat_id, sender_id, content, body, files, send_at, expires_after, client_msg_id, error, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(content)
+        .bind(&input.body)
+        .bind(&input.files)
+        .bind(send_at)
+        .bind(input.expires_after.map(|v| v as i64))
+        .bind(&input.client_msg_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(scheduled) = scheduled {
+            return Ok(Scheduled::Created(scheduled));
+        }
+
+        // nothing is inserted only if the client message id is taken
+        let key = input.client_msg_id.unwrap_or_default();
+        let scheduled = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, content, body, files, send_at, expires_after, client_msg_id, error, created_at
+            FROM scheduled_messages
+            WHERE chat_id = $1 AND sender_id = $2 AND client_msg_id = $3
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(&key)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(scheduled) = scheduled {
+            return Ok(Scheduled::Pending(scheduled));
+        }
+        // delivered or cancelled since the insert
+        match self.find_sent_message(&key, chat_id, user_id).await? {
+            Some(message) => Ok(Scheduled::Sent(message)),
+            None => Err(AppError::CreateMessageError(format!(
+                "Scheduled message with client message id {} was cancelled",
+                key
+            ))),
+        }
+    }
+
+    /// List pending and failed scheduled messages of the user, the earliest first
+    pub async fn list_scheduled_messages(
+        &self,
+        user_id: u64,
+    ) -> Result<Vec<ScheduledMessage>, AppError> {
+        let scheduled = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, content, body, files, send_at, expires_after, client_msg_id, error, created_at
+            FROM scheduled_messages
+            WHERE sender_id = $1
+            ORDER BY send_at, id
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(scheduled)
+    }
+
+    /// Cancel a pending scheduled message, only its author can cancel it
+    pub async fn cancel_scheduled_message(&self, id: u64, user_id: u64) -> Result<(), AppError> {
+        let res = sqlx::query("DELETE FROM scheduled_messages WHERE id = $1 AND sender_id = $2")
+            .bind(id as i64)
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(AppError::NotFountError(
+                "Scheduled message not found".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Send due scheduled messages, returns the number of delivered messages.
+    ///
+    /// Each message is inserted and removed from the schedule in its own transaction, and
+    /// the row being delivered is locked, so it is sent exactly once even if the server
+    /// restarts or several servers run the scheduler. A message failing to be delivered is
+    /// kept with the error for its author to see, and is not tried again.
+    pub async fn deliver_scheduled_messages(&self) -> Result<usize, AppError> {
+        let mut delivered = 0;
+        for _ in 0..DELIVERY_BATCH_SIZE {
+            let mut tx = self.pool.begin().await?;
+            let scheduled: Option<ScheduledMessage> = sqlx::query_as(
+                r#"
+                SELECT id, chat_id, sender_id, content, body, files, send_at, expires_after, client_msg_id, error, created_at
+                FROM scheduled_messages
+                WHERE send_at <= NOW() AND error IS NULL
+                ORDER BY send_at, id
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+                "#,
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            let Some(scheduled) = scheduled else {
+                break;
+            };
+
+            let id = scheduled.id;
+            let mut savepoint = tx.begin().await?;
+            match self.deliver_message(&mut savepoint, scheduled).await {
+                Ok(()) => {
+                    savepoint.commit().await?;
+                    sqlx::query("DELETE FROM scheduled_messages WHERE id = $1")
+                        .bind(id)
+                        .execute(&mut *tx)
+                        .await?;
+                    delivered += 1;
+                }
+                Err(e) => {
+                    savepoint.rollback().await?;
+                    warn!("failed to deliver scheduled message {}: {}", id, e);
+                    sqlx::query("UPDATE scheduled_messages SET error = $2 WHERE id = $1")
+                        .bind(id)
+                        .bind(e.to_string())
+                        .execute(&mut *tx)
+                        .await?;
+                }
+            }
+            tx.commit().await?;
+        }
+
+        Ok(delivered)
+    }
+
+    // the sender may have left the chat, or the chat changed since the message was scheduled.
+    // A retried schedule request finds the delivered message by the client message id
+    async fn deliver_message(
+        &self,
+        conn: &mut PgConnection,
+        scheduled: ScheduledMessage,
+    ) -> Result<(), AppError> {
+        let (chat_id, sender_id) = (scheduled.chat_id as u64, scheduled.sender_id as u64);
+        if !self.is_chat_member(chat_id, sender_id).await? {
+            return Err(AppError::CreateMessageError(format!(
+                "User {} is not a member of chat {}",
+                sender_id, chat_id
+            )));
+        }
+        let input = CreateMessage::from(scheduled);
+        let content = self.validate_message(&input)?;
+        self.verify_posting_rules(&mut *conn, chat_id, sender_id, false)
+            .await?;
+
+        let message = Self::insert_message(&mut *conn, &input, content, chat_id, sender_id).await?;
+        if let Some(key) = &input.client_msg_id {
+            Self::insert_idempotency_key(&mut *conn, key, chat_id, sender_id, message.id).await?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ScheduledMessage> for CreateMessage {
+    fn from(scheduled: ScheduledMessage) -> Self {
+        Self {
+            content: scheduled.content,
+            files: scheduled.files,
+            body: scheduled.body,
+            send_at: None,
+            expires_after: scheduled.expires_after.map(|v| v as u64),
+            client_msg_id: scheduled.client_msg_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chrono::Duration;
+
+    fn scheduled_input(content: &str) -> CreateMessage {
+        CreateMessage {
+            content: content.to_string(),
+            send_at: Some(Utc::now() + Duration::hours(1)),
+            ..Default::default()
+        }
+    }
+
+    async fn schedule(
+        state: &AppState,
+        input: CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<ScheduledMessage> {
+        match state.schedule_message(input, chat_id, user_id).await? {
+            Scheduled::Created(scheduled) | Scheduled::Pending(scheduled) => Ok(scheduled),
+            Scheduled::Sent(message) => anyhow::bail!("message {} already sent", message.id),
+        }
+    }
+
+    async fn make_due(state: &AppState, id: i64) -> Result<()> {
+        sqlx::query(
+            "UPDATE scheduled_messages SET send_at = NOW() - INTERVAL '1 second' WHERE id = $1",
+        )
+        .bind(id)
+        .execute(&state.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn schedule_and_list_messages_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let later = schedule(&state, scheduled_input("later"), 1, 1).await?;
+        let mut input = scheduled_input("sooner");
+        input.send_at = Some(Utc::now() + Duration::minutes(5));
+        let sooner = schedule(&state, input, 2, 1).await?;
+        schedule(&state, scheduled_input("other user"), 1, 2).await?;
+
+        let scheduled = state.list_scheduled_messages(1).await?;
+        assert_eq!(scheduled, vec![sooner, later]);
+
+        // invalid message should be rejected when scheduled
+        let err = state
+            .schedule_message(scheduled_input(""), 1, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "create message error: Content cannot be empty"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn schedule_message_with_client_msg_id_should_be_idempotent() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut input = scheduled_input("hello");
+        input.client_msg_id = Some("4c0a7d".to_string());
+        let Scheduled::Created(scheduled) = state.schedule_message(input.clone(), 1, 1).await?
+        else {
+            panic!("scheduled message should be created");
+        };
+        assert_eq!(
+            state.schedule_message(input.clone(), 1, 1).await?,
+            Scheduled::Pending(scheduled.clone())
+        );
+        assert_eq!(state.list_scheduled_messages(1).await?.len(), 1);
+
+        // once delivered, retries get the sent message
+        make_due(&state, scheduled.id).await?;
+        assert_eq!(state.deliver_scheduled_messages().await?, 1);
+        let Scheduled::Sent(message) = state.schedule_message(input.clone(), 1, 1).await? else {
+            panic!("scheduled message should be sent");
+        };
+        assert_eq!(message.content, "hello");
+        input.send_at = None;
+        assert_eq!(state.create_message(input, 1, 1).await?, message);
+        assert!(state.list_scheduled_messages(1).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancel_scheduled_message_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let scheduled = schedule(&state, scheduled_input("hello"), 1, 1).await?;
+
+        // only the author can cancel it
+        let err = state
+            .cancel_scheduled_message(scheduled.id as _, 2)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "not fount error: Scheduled message not found"
+        );
+
+        state.cancel_scheduled_message(scheduled.id as _, 1).await?;
+        assert!(state.list_scheduled_messages(1).await?.is_empty());
+
+        make_due(&state, scheduled.id).await?;
+        assert_eq!(state.deliver_scheduled_messages().await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deliver_scheduled_messages_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let due = schedule(&state, scheduled_input("due"), 1, 1).await?;
+        let pending = schedule(&state, scheduled_input("pending"), 1, 1).await?;
+        // user 4 is not a member of chat 1 any more when the message is due
+        let failed = schedule(&state, scheduled_input("failed"), 1, 4).await?;
+        make_due(&state, due.id).await?;
+        make_due(&state, failed.id).await?;
+
+        assert_eq!(state.deliver_scheduled_messages().await?, 1);
+        let page = state
+            .list_messages(crate::ListMessages::default(), 1)
+            .await?;
+        let message = &page.messages[0];
+        assert_eq!(message.content, "due");
+        assert_eq!(message.sender_id, 1);
+
+        // delivered messages are never sent again
+        assert_eq!(state.deliver_scheduled_messages().await?, 0);
+        assert_eq!(state.list_scheduled_messages(1).await?, vec![pending]);
+        // failed messages are kept for the author until cancelled
+        let failed = state.list_scheduled_messages(4).await?;
+        assert_eq!(
+            failed[0].error.as_deref(),
+            Some("create message error: User 4 is not a member of chat 1")
+        );
+        state.cancel_scheduled_message(failed[0].id as _, 4).await?;
+        assert!(state.list_scheduled_messages(4).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_should_not_hold_back_others() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query(
+            r#"
+            CREATE FUNCTION reject_broken() RETURNS TRIGGER AS $$
+            BEGIN
+              IF NEW.content = 'broken' THEN
+                RAISE EXCEPTION 'broken message';
+              END IF;
+              RETURN NEW;
+            END;
+            $$ LANGUAGE plpgsql
+            "#,
+        )
+        .execute(&state.pool)
+        .await?;
+        sqlx::query(
+            "CREATE TRIGGER reject_broken BEFORE INSERT ON messages FOR EACH ROW EXECUTE FUNCTION reject_broken()",
+        )
+        .execute(&state.pool)
+        .await?;
+        let broken = schedule(&state, scheduled_input("broken"), 1, 1).await?;
+        let due = schedule(&state, scheduled_input("due"), 1, 1).await?;
+        make_due(&state, broken.id).await?;
+        make_due(&state, due.id).await?;
+
+        assert_eq!(state.deliver_scheduled_messages().await?, 1);
+        let page = state
+            .list_messages(crate::ListMessages::default(), 1)
+            .await?;
+        assert_eq!(page.messages[0].content, "due");
+        // the failed message is kept with the error
+        let failed = state.list_scheduled_messages(1).await?;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].content, "broken");
+        assert!(failed[0].error.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delivery_should_follow_posting_rules() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // user 5 is a member of chat 2, user 2 an admin
+        let first = schedule(&state, scheduled_input("first"), 2, 5).await?;
+        let second = schedule(&state, scheduled_input("second"), 2, 5).await?;
+        let admin = schedule(&state, scheduled_input("admin"), 2, 2).await?;
+        let input = crate::UpdateChat {
+            slow_mode: Some(60),
+            ..Default::default()
+        };
+        state.update_chat_by_id(&input, 2, 1).await?;
+        for id in [first.id, second.id, admin.id] {
+            make_due(&state, id).await?;
+        }
+        // scheduled messages don't wait for slow mode
+        assert_eq!(state.deliver_scheduled_messages().await?, 3);
+        assert!(state.list_scheduled_messages(5).await?.is_empty());
+
+        let pending = schedule(&state, scheduled_input("pending"), 2, 4).await?;
+        let input = crate::UpdateChat {
+            announcement: Some(true),
+            ..Default::default()
+        };
+        state.update_chat_by_id(&input, 2, 1).await?;
+        make_due(&state, pending.id).await?;
+        assert_eq!(state.deliver_scheduled_messages().await?, 0);
+        let failed = state.list_scheduled_messages(4).await?;
+        assert_eq!(
+            failed[0].error.as_deref(),
+            Some("only owners and admins can post in announcement chat 2")
+        );
+        // not tried again
+        assert_eq!(state.deliver_scheduled_messages().await?, 0);
+        Ok(())
+    }
+}
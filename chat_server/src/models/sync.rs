@@ -0,0 +1,478 @@
+use crate::{AppError, AppState};
+use chat_core::{Chat, Message, ReadState};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::collections::HashMap;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct SyncQuery {
+    /// cursor returned by the previous sync, without it only a new cursor is returned
+    pub since: Option<String>,
+    /// max number of changes read, capped by the server
+    pub limit: Option<u64>,
+}
+
+/// Changes since the cursor, each entity is given in its current state.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct SyncChanges {
+    /// chats created or updated, including chats the user was added to
+    pub chats: Vec<Chat>,
+    /// chats deleted or the user was removed from
+    pub removed_chats: Vec<i64>,
+    /// messages sent or edited
+    pub messages: Vec<Message>,
+    pub deleted_messages: Vec<DeletedMessage>,
+    pub read_states: Vec<ReadState>,
+    /// pass as `since` to fetch the next changes
+    pub cursor: String,
+    /// more changes are available right away with the new cursor
+    pub has_more: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
+pub struct DeletedMessage {
+    pub id: i64,
+    pub chat_id: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, sqlx::Type)]
+#[sqlx(type_name = "sync_kind", rename_all = "snake_case")]
+enum SyncKind {
+    Chat,
+    Message,
+    ReadState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "sync_op", rename_all = "snake_case")]
+enum SyncOp {
+    Upsert,
+    Delete,
+}
+
+#[derive(Debug, FromRow)]
+struct SyncChange {
+    id: i64,
+    txid: String,
+    chat_id: i64,
+    kind: SyncKind,
+    op: SyncOp,
+    entity_id: i64,
+}
+
+/// Position in the change log, changes are read in (txid, id) order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct SyncCursor {
+    txid: u64,
+    id: i64,
+    // unix timestamp the cursor was issued at, cursors older than the retention are rejected
+    issued_at: i64,
+}
+
+#[allow(dead_code)]
+impl AppState {
+    /// Changes relevant to the user since the cursor.
+    ///
+    /// Only changes of transactions older than every running transaction are read, so a
+    /// change committed after a newer one is never skipped by the returned cursor. The
+    /// snapshot xmin is cluster wide, any long running transaction holds back the log, so
+    /// changes older than `sync_max_lag` are read regardless.
+    pub async fn sync(&self, input: SyncQuery, user_id: u64) -> Result<SyncChanges, AppError> {
+        let limit = self.page_size(input.limit)?;
+        let since = input
+            .since
+            .as_deref()
+            .map(|s| self.decode_cursor(s))
+            .transpose()?;
+
+        let xmin: String =
+            sqlx::query_scalar("SELECT pg_snapshot_xmin(pg_current_snapshot())::text")
+                .fetch_one(&self.pool)
+                .await?;
+        let xmin = parse_txid(&xmin)?;
+        let head = SyncCursor {
+            txid: xmin,
+            id: 0,
+            issued_at: Utc::now().timestamp(),
+        };
+        let Some(since) = since else {
+            return Ok(SyncChanges::empty(head.encode()));
+        };
+
+        let mut changes: Vec<SyncChange> = sqlx::query_as(
+            r#"
+            SELECT id, txid::text AS txid, chat_id, kind, op, entity_id
+            FROM sync_changes
+            WHERE (txid, id) > ($1::xid8, $2)
+            AND (txid < $3::xid8 OR created_at < NOW() - $6 * INTERVAL '1 second')
+            AND (user_id = $4 OR (user_id IS NULL AND chat_id IN (SELECT chat_id FROM chat_members WHERE user_id = $4)))
+            ORDER BY txid, id
+            LIMIT $5
+            "#,
+        )
+        .bind(since.txid.to_string())
+        .bind(since.id)
+        .bind(xmin.to_string())
+        .bind(user_id as i64)
+        .bind(limit + 1)
+        .bind(self.config.chat.sync_max_lag as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let has_more = changes.len() as i64 > limit;
+        changes.truncate(limit as usize);
+        let last = match changes.last() {
+            Some(change) => SyncCursor {
+                txid: parse_txid(&change.txid)?,
+                id: change.id,
+                issued_at: since.issued_at,
+            },
+            None => since,
+        };
+        // paging keeps the issue time so a client can't page past the retention, the cursor is
+        // only renewed once the log is read up to the head
+        let cursor = if has_more {
+            last
+        } else {
+            SyncCursor {
+                issued_at: head.issued_at,
+                ..last.max(head)
+            }
+        };
+
+        let mut ret = self.load_changes(changes, user_id as i64).await?;
+        ret.cursor = cursor.encode();
+        ret.has_more = has_more;
+        Ok(ret)
+    }
+
+    /// Delete changes out of the retention, returns the number of deleted changes
+    pub async fn purge_sync_changes(&self) -> Result<u64, AppError> {
+        let res = sqlx::query(
+            "DELETE FROM sync_changes WHERE created_at < NOW() - $1 * INTERVAL '1 second'",
+        )
+        .bind(self.config.chat.sync_retention as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(res.rows_affected())
+    }
+
+    // load the current state of changed entities, the last change of an entity wins
+    async fn load_changes(
+        &self,
+        changes: Vec<SyncChange>,
+        user_id: i64,
+    ) -> Result<SyncChanges, AppError> {
+        let mut latest: HashMap<(SyncKind, i64), (SyncOp, i64)> = HashMap::new();
+        for change in changes {
+            latest.insert((change.kind, change.entity_id), (change.op, change.chat_id));
+        }
+
+        let mut ret = SyncChanges::empty(String::new());
+        let mut chat_ids = vec![];
+        let mut message_ids = vec![];
+        let mut read_chat_ids = vec![];
+        for ((kind, id), (op, chat_id)) in latest {
+            match (kind, op) {
+                (SyncKind::Chat, SyncOp::Upsert) => chat_ids.push(id),
+                (SyncKind::Chat, SyncOp::Delete) => ret.removed_chats.push(id),
+                (SyncKind::Message, SyncOp::Upsert) => message_ids.push((id, chat_id)),
+                (SyncKind::Message, SyncOp::Delete) => {
+                    ret.deleted_messages.push(DeletedMessage { id, chat_id })
+                }
+                (SyncKind::ReadState, _) => read_chat_ids.push(id),
+            }
+        }
+
+        // chats the user is no longer a member of are reported by their own changes
+        ret.chats = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, chat_member_ids(id) AS members, topic, description, avatar,
+              archived, announcement, slow_mode, expires_after, created_at
+            FROM chats
+            WHERE id = ANY($1) AND id IN (SELECT chat_id FROM chat_members WHERE user_id = $2)
+            ORDER BY id
+            "#,
+        )
+        .bind(&chat_ids)
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let ids: Vec<i64> = message_ids.iter().map(|(id, _)| *id).collect();
+        ret.messages = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, seq, sender_id, content, body, files, forwarded_from, kind, created_at, expires_at
+            FROM messages
+            WHERE id = ANY($1) AND (expires_at IS NULL OR expires_at > NOW())
+            ORDER BY id
+            "#,
+        )
+        .bind(&ids)
+        .fetch_all(&self.pool)
+        .await?;
+        // messages deleted since the change was logged
+        for (id, chat_id) in message_ids {
+            if !ret.messages.iter().any(|m| m.id == id) {
+                ret.deleted_messages.push(DeletedMessage { id, chat_id });
+            }
+        }
+
+        ret.read_states = sqlx::query_as(
+            r#"
+            SELECT user_id, chat_id, last_read_seq, read_at AS updated_at
+            FROM chat_members
+            WHERE user_id = $1 AND chat_id = ANY($2)
+            ORDER BY chat_id
+            "#,
+        )
+        .bind(user_id)
+        .bind(&read_chat_ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        ret.removed_chats.sort_unstable();
+        ret.deleted_messages.sort_unstable_by_key(|m| m.id);
+        Ok(ret)
+    }
+
+    fn decode_cursor(&self, s: &str) -> Result<SyncCursor, AppError> {
+        let cursor = SyncCursor::decode(s)
+            .ok_or_else(|| AppError::SyncError(format!("Invalid cursor: {}", s)))?;
+        let retention = self.config.chat.sync_retention as i64;
+        if Utc::now().timestamp() - cursor.issued_at > retention {
+            return Err(AppError::SyncCursorExpired);
+        }
+        Ok(cursor)
+    }
+}
+
+impl SyncChanges {
+    fn empty(cursor: String) -> Self {
+        Self {
+            chats: vec![],
+            removed_chats: vec![],
+            messages: vec![],
+            deleted_messages: vec![],
+            read_states: vec![],
+            cursor,
+            has_more: false,
+        }
+    }
+}
+
+impl SyncCursor {
+    fn encode(&self) -> String {
+        hex::encode(format!("{}.{}.{}", self.txid, self.id, self.issued_at))
+    }
+
+    fn decode(s: &str) -> Option<Self> {
+        let s = String::from_utf8(hex::decode(s).ok()?).ok()?;
+        let mut parts = s.split('.');
+        let cursor = Self {
+            txid: parts.next()?.parse().ok()?,
+            id: parts.next()?.parse().ok()?,
+            issued_at: parts.next()?.parse().ok()?,
+        };
+        parts.next().is_none().then_some(cursor)
+    }
+}
+
+fn parse_txid(s: &str) -> Result<u64, AppError> {
+    s.parse()
+        .map_err(|_| AppError::SyncError(format!("Invalid transaction id: {}", s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateMessage, MarkRead, UpdateChat};
+    use anyhow::Result;
+    use std::time::Duration;
+
+    // changes are held back while older transactions run, in other test databases too
+    async fn wait_for_writes(state: &AppState) -> Result<()> {
+        let txid: String = sqlx::query_scalar("SELECT pg_current_xact_id()::text")
+            .fetch_one(&state.pool)
+            .await?;
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let done: bool =
+                    sqlx::query_scalar("SELECT pg_snapshot_xmin(pg_current_snapshot()) > $1::xid8")
+                        .bind(&txid)
+                        .fetch_one(&state.pool)
+                        .await?;
+                if done {
+                    return anyhow::Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?
+    }
+
+    async fn sync_since(state: &AppState, since: &str, user_id: u64) -> Result<SyncChanges> {
+        wait_for_writes(state).await?;
+        let input = SyncQuery {
+            since: Some(since.to_string()),
+            limit: None,
+        };
+        Ok(state.sync(input, user_id).await?)
+    }
+
+    fn message(content: &str) -> CreateMessage {
+        CreateMessage {
+            content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_should_return_changes_since_cursor() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        wait_for_writes(&state).await?;
+        let head = state.sync(SyncQuery::default(), 1).await?;
+        assert_eq!(head, SyncChanges::empty(head.cursor.clone()));
+
+        let message = state.create_message(message("hello"), 1, 1).await?;
+        let read = state.mark_chat_read(MarkRead { seq: 11 }, 1, 2).await?;
+        let update = UpdateChat {
+            name: None,
+            members: Some(vec![1, 2, 3]),
+            expires_after: None,
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(&update, 2, 1).await?;
+
+        let changes = sync_since(&state, &head.cursor, 1).await?;
+        assert_eq!(changes.chats, vec![chat.clone()]);
+        assert_eq!(changes.messages[0], message);
+        // the update of chat 2 is in its timeline
+        let contents: Vec<_> = changes.messages[1..]
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["mitsuha removed okudera, sayaka"]);
+        // read states of other users are not synced
+        assert!(changes.read_states.is_empty());
+        assert!(!changes.has_more);
+
+        let changes = sync_since(&state, &head.cursor, 2).await?;
+        assert_eq!(changes.messages.len(), 2);
+        assert_eq!(changes.messages[0], message);
+        assert_eq!(changes.read_states, vec![read]);
+
+        // user 5 was removed from chat 2
+        let changes = sync_since(&state, &head.cursor, 5).await?;
+        assert!(changes.chats.is_empty());
+        assert_eq!(changes.removed_chats, vec![2]);
+
+        // nothing new since the last cursor
+        let next = sync_since(&state, &changes.cursor, 5).await?;
+        assert_eq!(next, SyncChanges::empty(next.cursor.clone()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_should_report_deleted_messages_and_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        wait_for_writes(&state).await?;
+        let head = state.sync(SyncQuery::default(), 1).await?;
+
+        let message = state.create_message(message("hello"), 1, 1).await?;
+        sqlx::query("DELETE FROM messages WHERE id = $1")
+            .bind(message.id)
+            .execute(&state.pool)
+            .await?;
+        state.delete_chat_by_id(2, 1).await?;
+
+        let changes = sync_since(&state, &head.cursor, 1).await?;
+        assert!(changes.messages.is_empty());
+        assert_eq!(
+            changes.deleted_messages,
+            vec![DeletedMessage {
+                id: message.id,
+                chat_id: 1
+            }]
+        );
+        assert_eq!(changes.removed_chats, vec![2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_should_paginate_changes() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        wait_for_writes(&state).await?;
+        let head = state.sync(SyncQuery::default(), 1).await?;
+        for i in 0..5 {
+            state
+                .create_message(message(&format!("message {}", i)), 1, 1)
+                .await?;
+        }
+        wait_for_writes(&state).await?;
+
+        // an older cursor keeps its issue time while paging, it's renewed at the head
+        let issued_at = Utc::now().timestamp() - 100;
+        let mut cursor = SyncCursor {
+            issued_at,
+            ..state.decode_cursor(&head.cursor)?
+        }
+        .encode();
+        let mut messages = vec![];
+        loop {
+            let input = SyncQuery {
+                since: Some(cursor),
+                limit: Some(2),
+            };
+            let changes = state.sync(input, 1).await?;
+            assert!(changes.messages.len() <= 2);
+            messages.extend(changes.messages);
+            cursor = changes.cursor;
+            let cursor_issued_at = state.decode_cursor(&cursor)?.issued_at;
+            if !changes.has_more {
+                assert!(cursor_issued_at > issued_at);
+                break;
+            }
+            assert_eq!(cursor_issued_at, issued_at);
+        }
+        let contents: Vec<_> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec![
+                "message 0",
+                "message 1",
+                "message 2",
+                "message 3",
+                "message 4"
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_should_reject_invalid_cursor() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = SyncQuery {
+            since: Some("not a cursor".to_string()),
+            limit: None,
+        };
+        let err = state.sync(input, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "sync error: Invalid cursor: not a cursor");
+
+        let expired = SyncCursor {
+            txid: 1,
+            id: 0,
+            issued_at: Utc::now().timestamp() - state.config.chat.sync_retention as i64 - 1,
+        };
+        let input = SyncQuery {
+            since: Some(expired.encode()),
+            limit: None,
+        };
+        let err = state.sync(input, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::SyncCursorExpired));
+        Ok(())
+    }
+}
@@ -1,10 +1,20 @@
-use crate::{handlers::*, UpdateChat};
 use crate::{
-    AppState, ChatUser, CreateChat, CreateMessage, CreateUser, ListMessages, OutputError,
-    SignInUser,
+    handlers::*, Channel, ChatMember, ChatSettings, ConvertChat, DeletedMessage, DndSchedule,
+    InboxChat, InboxPage, ListInbox, MarkRead, NotifyLevel, SyncChanges, SyncQuery,
+    TransferOwnership, UpdateChat, UpdateChatRole, UpdateChatSettings, UpdateDndSchedule,
+    UpdateDraft, UserChat,
+};
+use crate::{
+    AppState, ChatUser, CreateChat, CreateMessage, CreatePoll, CreateUser, CreateVote,
+    ForwardMessages, ListMessages, MessageFormat, MessagePage, OutputError, PinnedMessage, Poll,
+    PollOptionResult, PollResult, SaveMessage, SavedMessage, ScheduledMessage, SignInUser,
 };
 use axum::Router;
-use chat_core::{Chat, ChatType, Message, User, Workspace};
+use chat_core::{
+    Button, ButtonStyle, Chat, ChatPin, ChatRole, ChatType, ContentBlock, Draft, Field,
+    ForwardedFrom, Message, MessageBody, MessageKind, PollUpdate, ReadState, Reminder, SystemEvent,
+    User, Workspace,
+};
 use utoipa::{
     openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
     Modify, OpenApi,
@@ -23,14 +33,44 @@ pub(crate) trait OpenApiRouter {
             signup_handler,
             signin_handler,
             list_chat_handler,
+            list_inbox_handler,
             create_chat_handler,
+            open_direct_chat_handler,
             update_chat_handler,
+            convert_chat_handler,
             delete_chat_handler,
+            list_chat_member_handler,
+            update_chat_role_handler,
+            transfer_chat_handler,
+            list_channel_handler,
+            join_channel_handler,
+            leave_chat_handler,
             list_message_handler,
-            send_message_handler
+            send_message_handler,
+            forward_message_handler,
+            mark_read_handler,
+            update_chat_settings_handler,
+            get_dnd_handler,
+            set_dnd_handler,
+            clear_dnd_handler,
+            get_draft_handler,
+            save_draft_handler,
+            delete_draft_handler,
+            list_pin_handler,
+            pin_message_handler,
+            unpin_message_handler,
+            create_poll_handler,
+            get_poll_handler,
+            vote_poll_handler,
+            list_scheduled_message_handler,
+            cancel_scheduled_message_handler,
+            list_saved_handler,
+            save_message_handler,
+            unsave_message_handler,
+            sync_handler
         ),
         components(
-            schemas(User, Chat, ChatType, ChatUser, Message, Workspace, SignInUser, CreateUser, CreateChat, CreateMessage, ListMessages, AuthOutput, OutputError,UpdateChat),
+            schemas(User, Chat, ChatType, ChatUser, Message, Workspace, SignInUser, CreateUser, CreateChat, CreateMessage, ListMessages, AuthOutput, OutputError,UpdateChat, ConvertChat, ChatPin, PinnedMessage, MessagePage, MessageBody, ContentBlock, SystemEvent, Button, ButtonStyle, Field, MessageFormat, ScheduledMessage, ForwardMessages, ForwardedFrom, CreatePoll, CreateVote, Poll, PollResult, PollOptionResult, PollUpdate, SaveMessage, SavedMessage, Reminder, Draft, UpdateDraft, MarkRead, ReadState, SyncQuery, SyncChanges, DeletedMessage, ListInbox, InboxChat, InboxPage, ChatSettings, UpdateChatSettings, NotifyLevel, DndSchedule, UpdateDndSchedule, UserChat, ChatRole, ChatMember, UpdateChatRole, TransferOwnership, Channel, MessageKind),
         ),
         modifiers(&SecurityAddon),
         tags(
@@ -0,0 +1,37 @@
+use crate::AppState;
+use std::time::Duration;
+use tokio::time;
+use tracing::{info, warn};
+
+/// Spawn the background workers of the server, they run until the process exits
+pub fn spawn_workers(state: AppState) {
+    let period = Duration::from_secs(state.config.chat.worker_interval.max(1));
+    tokio::spawn(async move {
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            match state.deliver_scheduled_messages().await {
+                Ok(0) => {}
+                Ok(n) => info!("delivered {} scheduled messages", n),
+                Err(e) => warn!("deliver scheduled messages failed: {}", e),
+            }
+            match state.sweep_expired_messages().await {
+                Ok(0) => {}
+                Ok(n) => info!("deleted {} expired messages", n),
+                Err(e) => warn!("sweep expired messages failed: {}", e),
+            }
+            match state.fire_reminders().await {
+                Ok(0) => {}
+                Ok(n) => info!("fired {} reminders", n),
+                Err(e) => warn!("fire reminders failed: {}", e),
+            }
+            if let Err(e) = state.purge_idempotency_keys().await {
+                warn!("purge idempotency keys failed: {}", e);
+            }
+            if let Err(e) = state.purge_sync_changes().await {
+                warn!("purge sync changes failed: {}", e);
+            }
+        }
+    });
+}
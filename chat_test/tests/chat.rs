@@ -20,7 +20,7 @@ test1:
             password: 123456
         - create_chat
             name: test
-            members: [1, 2]
+            members: [3, 4]
         - create_message
             chat_id: 1
             content: hello
@@ -78,7 +78,7 @@ impl NotifyServer {
                         "NewChat" => {
                             let chat: Chat = serde_json::from_str(&message.data).unwrap();
                             assert_eq!(chat.name.as_ref().unwrap(), "test");
-                            assert_eq!(chat.members, vec![1, 2]);
+                            assert_eq!(chat.members, vec![3, 4]);
                             assert_eq!(chat.r#type, ChatType::PrivateChannel);
                         }
 
@@ -86,7 +86,7 @@ impl NotifyServer {
                             let msg: Message = serde_json::from_str(&message.data).unwrap();
                             assert_eq!(msg.content, "hello");
                             assert_eq!(msg.files.len(), 1);
-                            assert_eq!(msg.sender_id, 1);
+                            assert_eq!(msg.sender_id, 3);
                         }
                         _ => {
                             panic!("unexpected event: {:?}", message);
@@ -132,7 +132,7 @@ impl ChatServer {
     async fn signin(&self) -> Result<String> {
         let res = self
             .client
-            .post(&format!("http://{}/api/signin", self.addr))
+            .post(format!("http://{}/api/signin", self.addr))
             .header("Content-Type", "application/json")
             .body(r#"{"email": "taki@gmail.com","password":"takitaki"}"#)
             .send()
@@ -147,12 +147,12 @@ impl ChatServer {
             .post(format!("http://{}/api/chats", self.addr))
             .header("Authorization", format!("Bearer {}", self.token))
             .header("Content-Type", "application/json")
-            .body(r#"{"name": "test", "members": [1, 2, 3], "public": false}"#);
+            .body(r#"{"name": "test", "members": [3, 4], "public": false}"#);
         let res = res.send().await?;
         assert_eq!(res.status(), StatusCode::CREATED);
         let chat: Chat = res.json().await?;
         assert_eq!(chat.name.as_ref().unwrap(), "test");
-        assert_eq!(chat.members, vec![1, 2, 3]);
+        assert_eq!(chat.members, vec![3, 4]);
         assert_eq!(chat.r#type, ChatType::PrivateChannel);
 
         Ok(chat)
@@ -168,7 +168,7 @@ impl ChatServer {
 
         let res = self
             .client
-            .post(&format!("http://{}/api/upload", self.addr))
+            .post(format!("http://{}/api/upload", self.addr))
             .header("Authorization", format!("Bearer {}", self.token))
             .multipart(form)
             .send()
@@ -0,0 +1,46 @@
+-- Add migration script here
+
+-- create pinned message table
+CREATE TABLE IF NOT EXISTS chat_pins(
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  pinned_by bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  pinned_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, message_id)
+);
+
+-- create index for chat_pins for chat_id and pinned_at order by pinned_at desc
+CREATE INDEX IF NOT EXISTS chat_id_pinned_at_index ON chat_pins(chat_id, pinned_at DESC);
+
+-- add a function: if a message is pinned or unpinned, notify chat members with pin data
+CREATE OR REPLACE FUNCTION add_to_pin()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  PIN chat_pins;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    PIN := NEW;
+  ELSE
+    PIN := OLD;
+  END IF;
+  RAISE NOTICE 'add_to_pin: %', PIN;
+  -- chat may already be gone if the pin is removed by cascade
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = PIN.chat_id;
+  PERFORM
+    pg_notify('chat_pin_updated', json_build_object('op', TG_OP, 'pin', PIN, 'members', COALESCE(USERS, '{}'))::text);
+  RETURN PIN;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER add_to_pin_trigger
+  AFTER INSERT OR DELETE ON chat_pins
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_pin();
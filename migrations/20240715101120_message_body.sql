@@ -0,0 +1,5 @@
+-- Add migration script here
+
+-- structured message body, content keeps the plain text fallback for search and notifications
+ALTER TABLE messages
+  ADD COLUMN body jsonb;
@@ -0,0 +1,19 @@
+-- Add migration script here
+
+-- messages waiting to be delivered by the scheduler
+CREATE TABLE IF NOT EXISTS scheduled_messages(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  sender_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  content text NOT NULL,
+  body jsonb,
+  files text[] DEFAULT '{}',
+  send_at timestamptz NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for scheduled_messages for send_at, used to find due messages
+CREATE INDEX IF NOT EXISTS scheduled_messages_send_at_index ON scheduled_messages(send_at);
+
+-- create index for scheduled_messages for sender_id
+CREATE INDEX IF NOT EXISTS scheduled_messages_sender_id_index ON scheduled_messages(sender_id, send_at);
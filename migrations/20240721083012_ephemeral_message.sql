@@ -0,0 +1,40 @@
+-- Add migration script here
+
+-- seconds after which messages of the chat expire, null means never
+ALTER TABLE chats ADD COLUMN expires_after bigint;
+
+-- messages are deleted by the sweeper after expires_at, null means never
+ALTER TABLE messages ADD COLUMN expires_at timestamptz;
+
+ALTER TABLE scheduled_messages ADD COLUMN expires_after bigint;
+
+-- create index for expired messages lookup
+CREATE INDEX IF NOT EXISTS expires_at_index ON messages(expires_at) WHERE expires_at IS NOT NULL;
+
+-- add a function: if an expired message is deleted, notify chat members to remove it
+CREATE OR REPLACE FUNCTION remove_expired_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF OLD.expires_at IS NOT NULL AND OLD.expires_at <= NOW() THEN
+    RAISE NOTICE 'remove_expired_message: %', OLD.id;
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = OLD.chat_id;
+    PERFORM
+      pg_notify('chat_message_expired', json_build_object('message', json_build_object('id', OLD.id, 'chat_id', OLD.chat_id), 'members', COALESCE(USERS, '{}'))::text);
+  END IF;
+  RETURN OLD;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER remove_expired_message_trigger
+  AFTER DELETE ON messages
+  FOR EACH ROW
+  EXECUTE FUNCTION remove_expired_message();
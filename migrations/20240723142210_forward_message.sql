@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- original message of a forwarded message: message_id, chat_id, sender_id, created_at
+ALTER TABLE messages ADD COLUMN forwarded_from jsonb;
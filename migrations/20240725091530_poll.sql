@@ -0,0 +1,70 @@
+-- Add migration script here
+
+-- create poll table, a poll is attached to the message created with it and shares its id
+CREATE TABLE IF NOT EXISTS polls(
+  id bigint PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  creator_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  question text NOT NULL,
+  options text[] NOT NULL,
+  -- allow voting for more than one option
+  multiple boolean NOT NULL DEFAULT FALSE,
+  -- hide who voted for what
+  anonymous boolean NOT NULL DEFAULT FALSE,
+  closes_at timestamptz,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  -- bumped after votes changed, so that the results are pushed once per vote
+  updated_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create poll vote table, option is the index of the option in the poll
+CREATE TABLE IF NOT EXISTS poll_votes(
+  poll_id bigint NOT NULL REFERENCES polls(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  option_index int NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (poll_id, user_id, option_index)
+);
+
+-- add a function: if votes of a poll changed, notify chat members with the aggregated results
+CREATE OR REPLACE FUNCTION update_poll()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+  VOTES bigint[];
+  VOTERS bigint;
+BEGIN
+  RAISE NOTICE 'update_poll: %', NEW.id;
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  SELECT
+    array_agg((
+      SELECT
+        COUNT(*)
+      FROM poll_votes v
+      WHERE
+        v.poll_id = NEW.id AND v.option_index = i - 1) ORDER BY i) INTO VOTES
+  FROM
+    generate_subscripts(NEW.options, 1) i;
+  SELECT
+    COUNT(DISTINCT user_id) INTO VOTERS
+  FROM
+    poll_votes
+  WHERE
+    poll_id = NEW.id;
+  PERFORM
+    pg_notify('poll_updated', json_build_object('poll', json_build_object('poll_id', NEW.id, 'chat_id', NEW.chat_id, 'votes', VOTES, 'total_voters', VOTERS), 'members', COALESCE(USERS, '{}'))::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER update_poll_trigger
+  AFTER UPDATE ON polls
+  FOR EACH ROW
+  EXECUTE FUNCTION update_poll();
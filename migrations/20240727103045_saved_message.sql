@@ -0,0 +1,39 @@
+-- Add migration script here
+
+-- create saved message table, bookmarks of a user with an optional reminder
+CREATE TABLE IF NOT EXISTS saved_messages(
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  remind_at timestamptz,
+  -- set once the reminder is fired
+  reminded_at timestamptz,
+  saved_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, message_id)
+);
+
+-- create index for saved messages of a user order by saved_at desc
+CREATE INDEX IF NOT EXISTS user_id_saved_at_index ON saved_messages(user_id, saved_at DESC);
+
+-- create index for pending reminders
+CREATE INDEX IF NOT EXISTS remind_at_index ON saved_messages(remind_at) WHERE remind_at IS NOT NULL AND reminded_at IS NULL;
+
+-- add a function: if a reminder is fired, notify the user who saved the message
+CREATE OR REPLACE FUNCTION fire_reminder()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF OLD.reminded_at IS NULL AND NEW.reminded_at IS NOT NULL THEN
+    RAISE NOTICE 'fire_reminder: %', NEW;
+    PERFORM
+      pg_notify('reminder_fired', json_build_object('user_id', NEW.user_id, 'message_id', NEW.message_id, 'chat_id', NEW.chat_id, 'remind_at', NEW.remind_at)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER fire_reminder_trigger
+  AFTER UPDATE ON saved_messages
+  FOR EACH ROW
+  EXECUTE FUNCTION fire_reminder();
@@ -0,0 +1,37 @@
+-- Add migration script here
+
+-- create draft table, one unsent message per user and chat
+CREATE TABLE IF NOT EXISTS drafts(
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  content text NOT NULL,
+  body jsonb,
+  files text[] NOT NULL DEFAULT '{}',
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, chat_id)
+);
+
+-- add a function: if a draft changed, notify the sessions of its user, a deleted draft is sent empty
+CREATE OR REPLACE FUNCTION update_draft()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  DRAFT json;
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    DRAFT := json_build_object('user_id', OLD.user_id, 'chat_id', OLD.chat_id, 'content', '', 'body', NULL, 'files', '{}'::text[], 'updated_at', NOW());
+  ELSE
+    DRAFT := row_to_json(NEW);
+  END IF;
+  RAISE NOTICE 'update_draft: %', DRAFT;
+  PERFORM
+    pg_notify('draft_updated', DRAFT::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER update_draft_trigger
+  AFTER INSERT OR UPDATE OR DELETE ON drafts
+  FOR EACH ROW
+  EXECUTE FUNCTION update_draft();
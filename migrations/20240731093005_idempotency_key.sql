@@ -0,0 +1,19 @@
+-- Add migration script here
+
+-- client generated keys of sent messages, so that a retried send returns the original message
+CREATE TABLE IF NOT EXISTS idempotency_keys(
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  sender_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  key varchar(64) NOT NULL,
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, sender_id, key)
+);
+
+-- create index for purging expired keys
+CREATE INDEX IF NOT EXISTS idempotency_keys_created_at_index ON idempotency_keys(created_at);
+
+-- scheduled messages are deduplicated until they are delivered
+ALTER TABLE scheduled_messages ADD COLUMN client_msg_id varchar(64);
+
+CREATE UNIQUE INDEX IF NOT EXISTS scheduled_messages_client_msg_id_index ON scheduled_messages(chat_id, sender_id, client_msg_id) WHERE client_msg_id IS NOT NULL;
@@ -0,0 +1,45 @@
+-- Add migration script here
+
+-- last message sequence number of each chat
+CREATE TABLE IF NOT EXISTS chat_seqs(
+  chat_id bigint PRIMARY KEY REFERENCES chats(id) ON DELETE CASCADE,
+  last_seq bigint NOT NULL
+);
+
+-- per chat sequence number of messages, so that clients can detect missed messages
+ALTER TABLE messages ADD COLUMN seq bigint;
+
+UPDATE messages m SET seq = s.seq
+FROM (
+  SELECT id, ROW_NUMBER() OVER (PARTITION BY chat_id ORDER BY id) AS seq
+  FROM messages
+) s
+WHERE m.id = s.id;
+
+INSERT INTO chat_seqs (chat_id, last_seq)
+SELECT chat_id, MAX(seq) FROM messages GROUP BY chat_id;
+
+ALTER TABLE messages ALTER COLUMN seq SET NOT NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS chat_id_seq_index ON messages(chat_id, seq);
+
+-- add a function: assign the next sequence number of the chat to a new message, the counter
+-- row is locked until the transaction ends, so sequence numbers are gapless on commit
+CREATE OR REPLACE FUNCTION assign_message_seq()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  INSERT INTO chat_seqs (chat_id, last_seq)
+    VALUES (NEW.chat_id, 1)
+  ON CONFLICT (chat_id)
+    DO UPDATE SET last_seq = chat_seqs.last_seq + 1
+  RETURNING last_seq INTO NEW.seq;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER assign_message_seq_trigger
+  BEFORE INSERT ON messages
+  FOR EACH ROW
+  EXECUTE FUNCTION assign_message_seq();
@@ -0,0 +1,125 @@
+-- Add migration script here
+
+-- create read state table, the last message read by a user in a chat
+CREATE TABLE IF NOT EXISTS read_states(
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  last_read_seq bigint NOT NULL DEFAULT 0,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, chat_id)
+);
+
+-- create sync kind type: chat, message, read_state
+CREATE TYPE sync_kind AS ENUM(
+  'chat',
+  'message',
+  'read_state'
+);
+
+-- create sync op type: upsert, delete
+CREATE TYPE sync_op AS ENUM(
+  'upsert',
+  'delete'
+);
+
+-- create sync change table, a log of changes fetched by offline clients
+CREATE TABLE IF NOT EXISTS sync_changes(
+  id bigserial PRIMARY KEY,
+  -- changes are read in transaction order, so that a change committed late is never skipped
+  txid xid8 NOT NULL DEFAULT pg_current_xact_id(),
+  chat_id bigint NOT NULL,
+  -- null if the change is visible to all current members of the chat
+  user_id bigint,
+  kind sync_kind NOT NULL,
+  op sync_op NOT NULL,
+  entity_id bigint NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index for sync changes for txid and id
+CREATE INDEX IF NOT EXISTS sync_changes_txid_id_index ON sync_changes(txid, id);
+
+-- create index for purging old changes
+CREATE INDEX IF NOT EXISTS sync_changes_created_at_index ON sync_changes(created_at);
+
+-- add a function: log chat changes, removed members see the chat as deleted
+CREATE OR REPLACE FUNCTION log_chat_change()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    INSERT INTO sync_changes(chat_id, user_id, kind, op, entity_id)
+    SELECT
+      OLD.id, m, 'chat', 'delete', OLD.id
+    FROM
+      UNNEST(OLD.members) m;
+    RETURN OLD;
+  END IF;
+  INSERT INTO sync_changes(chat_id, kind, op, entity_id)
+    VALUES (NEW.id, 'chat', 'upsert', NEW.id);
+  IF TG_OP = 'UPDATE' THEN
+    INSERT INTO sync_changes(chat_id, user_id, kind, op, entity_id)
+    SELECT
+      NEW.id, m, 'chat', 'delete', NEW.id
+    FROM
+      UNNEST(OLD.members) m
+    WHERE
+      m <> ALL (NEW.members);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER log_chat_change_trigger
+  AFTER INSERT OR UPDATE OR DELETE ON chats
+  FOR EACH ROW
+  EXECUTE FUNCTION log_chat_change();
+
+-- add a function: log message changes
+CREATE OR REPLACE FUNCTION log_message_change()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    -- messages deleted with their chat are covered by the chat change
+    IF EXISTS (
+      SELECT
+        1
+      FROM
+        chats
+      WHERE
+        id = OLD.chat_id) THEN
+      INSERT INTO sync_changes(chat_id, kind, op, entity_id)
+        VALUES (OLD.chat_id, 'message', 'delete', OLD.id);
+    END IF;
+    RETURN OLD;
+  END IF;
+  INSERT INTO sync_changes(chat_id, kind, op, entity_id)
+    VALUES (NEW.chat_id, 'message', 'upsert', NEW.id);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER log_message_change_trigger
+  AFTER INSERT OR UPDATE OR DELETE ON messages
+  FOR EACH ROW
+  EXECUTE FUNCTION log_message_change();
+
+-- add a function: log read state changes, only visible to the reader
+CREATE OR REPLACE FUNCTION log_read_state_change()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  INSERT INTO sync_changes(chat_id, user_id, kind, op, entity_id)
+    VALUES (NEW.chat_id, NEW.user_id, 'read_state', 'upsert', NEW.chat_id);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER log_read_state_change_trigger
+  AFTER INSERT OR UPDATE ON read_states
+  FOR EACH ROW
+  EXECUTE FUNCTION log_read_state_change();
@@ -0,0 +1,12 @@
+-- Add migration script here
+
+-- create chat setting table, per user flags of a chat shown in the inbox
+CREATE TABLE IF NOT EXISTS chat_settings(
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  muted boolean NOT NULL DEFAULT FALSE,
+  starred boolean NOT NULL DEFAULT FALSE,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, chat_id)
+);
+
@@ -0,0 +1,30 @@
+-- Add migration script here
+
+-- create chat role type: owner, admin, member
+CREATE TYPE chat_role AS ENUM(
+  'owner',
+  'admin',
+  'member'
+);
+
+-- create chat member role table, members without a row have the member role
+CREATE TABLE IF NOT EXISTS chat_member_roles(
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  role chat_role NOT NULL,
+  PRIMARY KEY (chat_id, user_id)
+);
+
+-- a chat has at most one owner
+CREATE UNIQUE INDEX IF NOT EXISTS chat_member_roles_owner_index ON chat_member_roles(chat_id) WHERE role = 'owner';
+
+-- the first member of existing chats becomes the owner
+INSERT INTO chat_member_roles(chat_id, user_id, role)
+SELECT
+  id,
+  members[1],
+  'owner'
+FROM
+  chats
+WHERE
+  array_length(members, 1) > 0;
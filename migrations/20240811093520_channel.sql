@@ -0,0 +1,18 @@
+-- Add migration script here
+
+-- create message kind type: user, system
+CREATE TYPE message_kind AS ENUM(
+  'user',
+  'system'
+);
+
+-- system messages are sent by the server for chat events, the sender is the member acting
+ALTER TABLE messages
+  ADD COLUMN kind message_kind NOT NULL DEFAULT 'user';
+
+-- topic of a channel, shown when browsing channels
+ALTER TABLE chats
+  ADD COLUMN topic text;
+
+-- create index for browsing public channels of a workspace
+CREATE INDEX IF NOT EXISTS chats_ws_id_public_index ON chats(ws_id) WHERE type = 'public_channel';
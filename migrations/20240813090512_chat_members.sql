@@ -0,0 +1,366 @@
+-- Add migration script here
+
+-- create chat member table, one row per member of a chat with its role, settings and read state
+CREATE TABLE IF NOT EXISTS chat_members(
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  role chat_role NOT NULL DEFAULT 'member',
+  muted boolean NOT NULL DEFAULT FALSE,
+  starred boolean NOT NULL DEFAULT FALSE,
+  -- settings are muted and starred
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_read_seq bigint NOT NULL DEFAULT 0,
+  read_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  joined_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, user_id)
+);
+
+-- create index for chat members for user_id, the chats of a user
+CREATE INDEX IF NOT EXISTS chat_members_user_id_index ON chat_members(user_id);
+
+-- a chat has at most one owner
+CREATE UNIQUE INDEX IF NOT EXISTS chat_members_owner_index ON chat_members(chat_id)
+WHERE
+  role = 'owner';
+
+-- move members, roles, settings and read states of existing chats, unknown users are dropped
+INSERT INTO chat_members(chat_id, user_id, role, muted, starred, updated_at, last_read_seq, read_at)
+SELECT
+  c.id,
+  m,
+  COALESCE(r.role, 'member'),
+  COALESCE(s.muted, FALSE),
+  COALESCE(s.starred, FALSE),
+  COALESCE(s.updated_at, CURRENT_TIMESTAMP),
+  COALESCE(rs.last_read_seq, 0),
+  COALESCE(rs.updated_at, CURRENT_TIMESTAMP)
+FROM
+  chats c
+  CROSS JOIN UNNEST(c.members) m
+  JOIN users u ON u.id = m
+  LEFT JOIN chat_member_roles r ON r.chat_id = c.id AND r.user_id = m
+  LEFT JOIN chat_settings s ON s.chat_id = c.id AND s.user_id = m
+  LEFT JOIN read_states rs ON rs.chat_id = c.id AND rs.user_id = m
+ON CONFLICT
+  DO NOTHING;
+
+DROP TRIGGER IF EXISTS add_to_chat_trigger ON chats;
+
+DROP TRIGGER IF EXISTS log_chat_change_trigger ON chats;
+
+DROP TABLE IF EXISTS read_states;
+
+DROP FUNCTION IF EXISTS log_read_state_change();
+
+DROP TABLE IF EXISTS chat_settings;
+
+DROP TABLE IF EXISTS chat_member_roles;
+
+ALTER TABLE chats
+  DROP COLUMN members;
+
+-- add a function: ids of the members of a chat, the members field of the api
+CREATE OR REPLACE FUNCTION chat_member_ids(bigint)
+  RETURNS bigint[]
+  AS $$
+  SELECT
+    COALESCE(array_agg(user_id ORDER BY user_id), '{}')
+  FROM
+    chat_members
+  WHERE
+    chat_id = $1;
+$$
+LANGUAGE sql
+STABLE;
+
+-- add a function: chat data with its members, as sent in notifications
+CREATE OR REPLACE FUNCTION chat_json(c chats)
+  RETURNS jsonb
+  AS $$
+  SELECT
+    to_jsonb(c) || jsonb_build_object('members', chat_member_ids(c.id));
+$$
+LANGUAGE sql
+STABLE;
+
+-- add a function: if chat changed, notify with chat data, a deleted chat is notified with its members
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    RAISE NOTICE 'add_to_chat: %', OLD;
+    PERFORM
+      pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', chat_json(OLD), 'new', NULL)::text);
+    RETURN OLD;
+  END IF;
+  RAISE NOTICE 'add_to_chat: %', NEW;
+  PERFORM
+    pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', chat_json(OLD), 'new', chat_json(NEW))::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER add_to_chat_trigger
+  AFTER UPDATE ON chats
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_chat();
+
+-- members are still there before the chat is deleted
+CREATE TRIGGER remove_chat_trigger
+  BEFORE DELETE ON chats
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_chat();
+
+-- add a function: if members joined a chat, notify with chat data, the first members create the chat
+CREATE OR REPLACE FUNCTION add_to_chat_members()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  CHAT chats;
+  USERS bigint[];
+BEGIN
+  FOR CHAT IN
+  SELECT
+    *
+  FROM
+    chats
+  WHERE
+    id IN (
+      SELECT
+        chat_id
+      FROM
+        added)
+      LOOP
+        RAISE NOTICE 'add_to_chat_members: %', CHAT.id;
+        SELECT
+          COALESCE(array_agg(user_id ORDER BY user_id), '{}') INTO USERS
+        FROM
+          chat_members
+        WHERE
+          chat_id = CHAT.id
+          AND user_id NOT IN (
+            SELECT
+              user_id
+            FROM
+              added
+            WHERE
+              chat_id = CHAT.id);
+        IF cardinality(USERS) = 0 THEN
+          PERFORM
+            pg_notify('chat_updated', json_build_object('op', 'INSERT', 'old', NULL, 'new', chat_json(CHAT))::text);
+        ELSE
+          PERFORM
+            pg_notify('chat_updated', json_build_object('op', 'UPDATE', 'old', chat_json(CHAT) || jsonb_build_object('members', USERS), 'new', chat_json(CHAT))::text);
+        END IF;
+      END LOOP;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER add_to_chat_members_trigger
+  AFTER INSERT ON chat_members REFERENCING NEW TABLE AS added
+  FOR EACH STATEMENT
+  EXECUTE FUNCTION add_to_chat_members();
+
+-- add a function: if members left a chat, notify with chat data, chats being deleted are already notified
+CREATE OR REPLACE FUNCTION remove_from_chat_members()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  CHAT chats;
+  USERS bigint[];
+BEGIN
+  FOR CHAT IN
+  SELECT
+    *
+  FROM
+    chats
+  WHERE
+    id IN (
+      SELECT
+        chat_id
+      FROM
+        removed)
+      LOOP
+        RAISE NOTICE 'remove_from_chat_members: %', CHAT.id;
+        SELECT
+          array_agg(user_id ORDER BY user_id) INTO USERS
+        FROM (
+          SELECT
+            user_id
+          FROM
+            chat_members
+          WHERE
+            chat_id = CHAT.id
+          UNION
+          SELECT
+            user_id
+          FROM
+            removed
+          WHERE
+            chat_id = CHAT.id) m;
+        PERFORM
+          pg_notify('chat_updated', json_build_object('op', 'UPDATE', 'old', chat_json(CHAT) || jsonb_build_object('members', USERS), 'new', chat_json(CHAT))::text);
+      END LOOP;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER remove_from_chat_members_trigger
+  AFTER DELETE ON chat_members REFERENCING OLD TABLE AS removed
+  FOR EACH STATEMENT
+  EXECUTE FUNCTION remove_from_chat_members();
+
+-- notify triggers compute their audience from chat members
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', chat_member_ids(NEW.chat_id))::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_pin()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  PIN chat_pins;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    PIN := NEW;
+  ELSE
+    PIN := OLD;
+  END IF;
+  RAISE NOTICE 'add_to_pin: %', PIN;
+  -- chat may already be gone if the pin is removed by cascade, it has no members then
+  PERFORM
+    pg_notify('chat_pin_updated', json_build_object('op', TG_OP, 'pin', PIN, 'members', chat_member_ids(PIN.chat_id))::text);
+  RETURN PIN;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION remove_expired_message()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF OLD.expires_at IS NOT NULL AND OLD.expires_at <= NOW() THEN
+    RAISE NOTICE 'remove_expired_message: %', OLD.id;
+    PERFORM
+      pg_notify('chat_message_expired', json_build_object('message', json_build_object('id', OLD.id, 'chat_id', OLD.chat_id), 'members', chat_member_ids(OLD.chat_id))::text);
+  END IF;
+  RETURN OLD;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_poll()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  VOTES bigint[];
+  VOTERS bigint;
+BEGIN
+  RAISE NOTICE 'update_poll: %', NEW.id;
+  SELECT
+    array_agg((
+      SELECT
+        COUNT(*)
+      FROM poll_votes v
+      WHERE
+        v.poll_id = NEW.id AND v.option_index = i - 1) ORDER BY i) INTO VOTES
+  FROM
+    generate_subscripts(NEW.options, 1) i;
+  SELECT
+    COUNT(DISTINCT user_id) INTO VOTERS
+  FROM
+    poll_votes
+  WHERE
+    poll_id = NEW.id;
+  PERFORM
+    pg_notify('poll_updated', json_build_object('poll', json_build_object('poll_id', NEW.id, 'chat_id', NEW.chat_id, 'votes', VOTES, 'total_voters', VOTERS), 'members', chat_member_ids(NEW.chat_id))::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+-- add a function: log chat changes, members of a deleted chat see it as deleted
+CREATE OR REPLACE FUNCTION log_chat_change()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    INSERT INTO sync_changes(chat_id, user_id, kind, op, entity_id)
+    SELECT
+      OLD.id, user_id, 'chat', 'delete', OLD.id
+    FROM
+      chat_members
+    WHERE
+      chat_id = OLD.id;
+    RETURN OLD;
+  END IF;
+  INSERT INTO sync_changes(chat_id, kind, op, entity_id)
+    VALUES (NEW.id, 'chat', 'upsert', NEW.id);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER log_chat_change_trigger
+  AFTER INSERT OR UPDATE ON chats
+  FOR EACH ROW
+  EXECUTE FUNCTION log_chat_change();
+
+CREATE TRIGGER log_chat_delete_trigger
+  BEFORE DELETE ON chats
+  FOR EACH ROW
+  EXECUTE FUNCTION log_chat_change();
+
+-- add a function: log membership changes, removed members see the chat as deleted,
+-- read states are only visible to the reader
+CREATE OR REPLACE FUNCTION log_chat_member_change()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    -- members deleted with their chat are covered by the chat change
+    IF EXISTS (
+      SELECT
+        1
+      FROM
+        chats
+      WHERE
+        id = OLD.chat_id) THEN
+      INSERT INTO sync_changes(chat_id, user_id, kind, op, entity_id)
+        VALUES (OLD.chat_id, OLD.user_id, 'chat', 'delete', OLD.chat_id);
+      INSERT INTO sync_changes(chat_id, kind, op, entity_id)
+        VALUES (OLD.chat_id, 'chat', 'upsert', OLD.chat_id);
+    END IF;
+    RETURN OLD;
+  END IF;
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO sync_changes(chat_id, kind, op, entity_id)
+      VALUES (NEW.chat_id, 'chat', 'upsert', NEW.chat_id);
+  ELSIF NEW.last_read_seq <> OLD.last_read_seq THEN
+    INSERT INTO sync_changes(chat_id, user_id, kind, op, entity_id)
+      VALUES (NEW.chat_id, NEW.user_id, 'read_state', 'upsert', NEW.chat_id);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER log_chat_member_change_trigger
+  AFTER INSERT OR UPDATE OR DELETE ON chat_members
+  FOR EACH ROW
+  EXECUTE FUNCTION log_chat_member_change();
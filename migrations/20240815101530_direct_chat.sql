@@ -0,0 +1,38 @@
+-- Add migration script here
+
+-- the two members of a direct chat, ordered, null for other chats
+ALTER TABLE chats
+  ADD COLUMN dm_pair bigint[];
+
+-- the oldest single chat of a pair stays its direct chat
+UPDATE
+  chats
+SET
+  dm_pair = p.pair
+FROM (
+  SELECT DISTINCT ON (c.ws_id, m.pair)
+    c.id,
+    m.pair
+  FROM
+    chats c
+    CROSS JOIN LATERAL (
+      SELECT
+        array_agg(user_id ORDER BY user_id) AS pair
+      FROM
+        chat_members
+      WHERE
+        chat_id = c.id) m
+  WHERE
+    c.type = 'single'
+    AND cardinality(m.pair) = 2
+  ORDER BY
+    c.ws_id,
+    m.pair,
+    c.id) p
+WHERE
+  chats.id = p.id;
+
+-- a pair of users has at most one direct chat in a workspace
+CREATE UNIQUE INDEX IF NOT EXISTS chats_dm_pair_index ON chats(ws_id, dm_pair)
+WHERE
+  dm_pair IS NOT NULL;
@@ -0,0 +1,7 @@
+-- Add migration script here
+
+-- add description, avatar file url and archive state to chats, the topic already exists
+ALTER TABLE chats
+  ADD COLUMN description text,
+  ADD COLUMN avatar text,
+  ADD COLUMN archived boolean NOT NULL DEFAULT FALSE;
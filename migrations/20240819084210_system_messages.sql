@@ -0,0 +1,22 @@
+-- Add migration script here
+
+-- system messages are written by the server once and never edited
+CREATE OR REPLACE FUNCTION check_system_message()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF NEW.kind <> OLD.kind THEN
+    RAISE EXCEPTION 'kind of message % can''t be changed', OLD.id;
+  END IF;
+  IF OLD.kind = 'system' AND (NEW.content IS DISTINCT FROM OLD.content OR NEW.body IS DISTINCT FROM OLD.body OR NEW.files IS DISTINCT FROM OLD.files) THEN
+    RAISE EXCEPTION 'system message % can''t be edited', OLD.id;
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER check_system_message_trigger
+  BEFORE UPDATE ON messages
+  FOR EACH ROW
+  EXECUTE FUNCTION check_system_message();
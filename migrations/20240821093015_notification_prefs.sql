@@ -0,0 +1,101 @@
+-- Add migration script here
+
+-- create notify level type: all, mentions, muted
+CREATE TYPE notify_level AS ENUM(
+  'all',
+  'mentions',
+  'muted'
+);
+
+-- notification preference of a member, muted_until mutes the chat for a while whatever the level
+ALTER TABLE chat_members
+  ADD COLUMN notify notify_level NOT NULL DEFAULT 'all',
+  ADD COLUMN muted_until timestamptz;
+
+UPDATE
+  chat_members
+SET
+  notify = 'muted'
+WHERE
+  muted;
+
+ALTER TABLE chat_members
+  DROP COLUMN muted;
+
+-- do not disturb schedule of a user for all chats of its workspace, in local time
+CREATE TABLE IF NOT EXISTS dnd_schedules(
+  user_id bigint PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  starts_at time NOT NULL,
+  ends_at time NOT NULL,
+  -- minutes east of utc of the local time
+  utc_offset int NOT NULL DEFAULT 0,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- a schedule may span midnight, e.g. from 22:00 to 07:00
+CREATE OR REPLACE FUNCTION dnd_active(d dnd_schedules, at timestamptz)
+  RETURNS boolean
+  AS $$
+  SELECT
+    CASE WHEN d.starts_at < d.ends_at THEN
+      l.t >= d.starts_at
+      AND l.t < d.ends_at
+    ELSE
+      l.t >= d.starts_at
+      OR l.t < d.ends_at
+    END
+  FROM (
+    SELECT
+      ((at AT TIME ZONE 'UTC') + d.utc_offset * INTERVAL '1 minute')::time AS t) l
+$$
+LANGUAGE sql
+STABLE;
+
+-- user names mentioned in the text, same rule as the mentions rendered by the server
+CREATE OR REPLACE FUNCTION mentioned_names(content text)
+  RETURNS text[]
+  AS $$
+  SELECT
+    COALESCE(array_agg(r[1]), '{}')
+  FROM
+    regexp_matches(content, '(?:^|[^[:alnum:]_])@([A-Za-z0-9_.-]*[A-Za-z0-9_])', 'g') r
+$$
+LANGUAGE sql
+IMMUTABLE;
+
+-- members getting the message without alert nor badge: muted, mentions only but not
+-- mentioned, or in their do not disturb schedule
+CREATE OR REPLACE FUNCTION silent_member_ids(m messages)
+  RETURNS bigint[]
+  AS $$
+  SELECT
+    COALESCE(array_agg(cm.user_id ORDER BY cm.user_id), '{}')
+  FROM
+    chat_members cm
+    JOIN users u ON u.id = cm.user_id
+    LEFT JOIN dnd_schedules d ON d.user_id = cm.user_id
+  WHERE
+    cm.chat_id = m.chat_id
+    AND (cm.notify = 'muted'
+      OR cm.muted_until > NOW()
+      OR (cm.notify = 'mentions'
+        AND u.name <> ALL (mentioned_names(m.content)))
+      OR (d.user_id IS NOT NULL
+        AND dnd_active(d, NOW())))
+$$
+LANGUAGE sql
+STABLE;
+
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', chat_member_ids(NEW.chat_id), 'silent', silent_member_ids(NEW))::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- only owners and admins post in announcement chats, slow mode limits members to a message
+-- per slow_mode seconds
+ALTER TABLE chats
+  ADD COLUMN announcement boolean NOT NULL DEFAULT FALSE,
+  ADD COLUMN slow_mode bigint;
+
+-- create index for the last message of a member in a chat
+CREATE INDEX IF NOT EXISTS messages_chat_id_sender_id_created_at_index ON messages(chat_id, sender_id, created_at DESC);
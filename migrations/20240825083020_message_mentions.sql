@@ -0,0 +1,29 @@
+-- Add migration script here
+
+-- users mentioned by the message, resolved by the server which skips mentions in code
+ALTER TABLE messages
+  ADD COLUMN mentions bigint[] NOT NULL DEFAULT '{}';
+
+-- members getting the message without alert nor badge: muted, mentions only but not
+-- mentioned, or in their do not disturb schedule
+CREATE OR REPLACE FUNCTION silent_member_ids(m messages)
+  RETURNS bigint[]
+  AS $$
+  SELECT
+    COALESCE(array_agg(cm.user_id ORDER BY cm.user_id), '{}')
+  FROM
+    chat_members cm
+    LEFT JOIN dnd_schedules d ON d.user_id = cm.user_id
+  WHERE
+    cm.chat_id = m.chat_id
+    AND (cm.notify = 'muted'
+      OR cm.muted_until > NOW()
+      OR (cm.notify = 'mentions'
+        AND cm.user_id <> ALL (m.mentions))
+      OR (d.user_id IS NOT NULL
+        AND dnd_active(d, NOW())))
+$$
+LANGUAGE sql
+STABLE;
+
+DROP FUNCTION IF EXISTS mentioned_names(text);
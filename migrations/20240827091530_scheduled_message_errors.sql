@@ -0,0 +1,6 @@
+-- Add migration script here
+
+-- why the delivery of a scheduled message failed, failed messages are kept for their author
+-- until cancelled and are not delivered again
+ALTER TABLE scheduled_messages
+  ADD COLUMN error text;
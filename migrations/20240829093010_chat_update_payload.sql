@@ -0,0 +1,125 @@
+-- Add migration script here
+
+-- add a function: notify a chat change with its current members and the members who joined or left,
+-- notifications are limited to 8000 bytes, large chats are sent without members and catch up with sync
+CREATE OR REPLACE FUNCTION notify_chat_updated(op text, chat jsonb, added bigint[], removed bigint[], was_archived boolean)
+  RETURNS void
+  AS $$
+DECLARE
+  PAYLOAD jsonb;
+BEGIN
+  PAYLOAD := jsonb_build_object('op', op, 'chat', chat, 'added', added, 'removed', removed, 'was_archived', was_archived);
+  IF octet_length(PAYLOAD::text) > 7900 THEN
+    RAISE WARNING 'notify_chat_updated: payload of chat % is too large', chat -> 'id';
+    PAYLOAD := PAYLOAD || jsonb_build_object('chat', chat || '{"members": []}', 'truncated', TRUE);
+  END IF;
+  IF octet_length(PAYLOAD::text) > 7900 THEN
+    PAYLOAD := PAYLOAD || jsonb_build_object('chat', PAYLOAD -> 'chat' || '{"topic": null, "description": null}', 'added', '[]'::jsonb, 'removed', '[]'::jsonb);
+  END IF;
+  PERFORM
+    pg_notify('chat_updated', PAYLOAD::text);
+END;
+$$
+LANGUAGE plpgsql;
+
+-- a deleted chat is notified to its members, an unchanged chat is not notified
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    RAISE NOTICE 'add_to_chat: %', OLD.id;
+    PERFORM
+      notify_chat_updated(TG_OP, chat_json(OLD), '{}', '{}', OLD.archived);
+    RETURN OLD;
+  END IF;
+  IF OLD IS DISTINCT FROM NEW THEN
+    RAISE NOTICE 'add_to_chat: %', NEW.id;
+    PERFORM
+      notify_chat_updated(TG_OP, chat_json(NEW), '{}', '{}', OLD.archived);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+-- the first members create the chat
+CREATE OR REPLACE FUNCTION add_to_chat_members()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  CHAT chats;
+  USERS bigint[];
+BEGIN
+  FOR CHAT IN
+  SELECT
+    *
+  FROM
+    chats
+  WHERE
+    id IN (
+      SELECT
+        chat_id
+      FROM
+        added)
+      LOOP
+        RAISE NOTICE 'add_to_chat_members: %', CHAT.id;
+        SELECT
+          array_agg(user_id ORDER BY user_id) INTO USERS
+        FROM
+          added
+        WHERE
+          chat_id = CHAT.id;
+        IF NOT EXISTS (
+          SELECT
+            1
+          FROM
+            chat_members
+          WHERE
+            chat_id = CHAT.id
+            AND user_id <> ALL (USERS)) THEN
+          PERFORM
+            notify_chat_updated('INSERT', chat_json(CHAT), USERS, '{}', CHAT.archived);
+        ELSE
+          PERFORM
+            notify_chat_updated('UPDATE', chat_json(CHAT), USERS, '{}', CHAT.archived);
+        END IF;
+      END LOOP;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION remove_from_chat_members()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  CHAT chats;
+  USERS bigint[];
+BEGIN
+  FOR CHAT IN
+  SELECT
+    *
+  FROM
+    chats
+  WHERE
+    id IN (
+      SELECT
+        chat_id
+      FROM
+        removed)
+      LOOP
+        RAISE NOTICE 'remove_from_chat_members: %', CHAT.id;
+        SELECT
+          array_agg(user_id ORDER BY user_id) INTO USERS
+        FROM
+          removed
+        WHERE
+          chat_id = CHAT.id;
+        PERFORM
+          notify_chat_updated('UPDATE', chat_json(CHAT), '{}', USERS, CHAT.archived);
+      END LOOP;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
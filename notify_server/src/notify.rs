@@ -1,5 +1,5 @@
 use crate::AppState;
-use chat_core::{Chat, Message};
+use chat_core::{Chat, ChatPin, Draft, Message, PollUpdate, Reminder};
 use futures::StreamExt;
 use jwt_simple::reexports::serde_json;
 use serde::{Deserialize, Serialize};
@@ -13,7 +13,25 @@ pub enum AppEvent {
     NewChat(Chat),
     AddToChat(Chat),
     RemoveFromChat(Chat),
+    ChatUpdated(Chat),
+    ChatArchived(Chat),
+    ChatRestored(Chat),
     NewMessage(Message),
+    // new message which should neither alert the user nor badge the chat
+    SilentMessage(Message),
+    MessagePinned(ChatPin),
+    MessageUnpinned(ChatPin),
+    MessageExpired(ExpiredMessage),
+    PollUpdated(PollUpdate),
+    Reminder(Reminder),
+    DraftUpdated(Draft),
+}
+
+// clients should remove the message once it expired
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExpiredMessage {
+    pub id: i64,
+    pub chat_id: i64,
 }
 
 #[derive(Debug)]
@@ -23,38 +41,75 @@ struct Notification {
     event: Arc<AppEvent>,
 }
 
-// pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', OLD, 'new', NEW)::text);
+// pg_notify('chat_updated', json_build_object('op', TG_OP, 'chat', CHAT, 'added', USERS, 'removed', USERS, 'was_archived', OLD.archived)::text);
 #[derive(Debug, Serialize, Deserialize)]
 struct ChatUpdated {
     op: String,
-    old: Option<Chat>,
-    new: Option<Chat>,
+    chat: Chat,
+    added: Vec<i64>,
+    removed: Vec<i64>,
+    was_archived: bool,
+    // members are left out of payloads too large to notify, clients catch up with sync
+    #[serde(default)]
+    truncated: bool,
 }
 
-// pg_notify('chat_message_created', row_to_json(NEW)::text);
+// pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS, 'silent', USERS)::text);
 #[derive(Debug, Serialize, Deserialize)]
 struct ChatMessageCreated {
     message: Message,
     members: Vec<i64>,
+    // members who muted the chat, are not mentioned in a mentions only chat, or in do not disturb
+    #[serde(default)]
+    silent: Vec<i64>,
+}
+
+// pg_notify('chat_pin_updated', json_build_object('op', TG_OP, 'pin', PIN, 'members', USERS)::text);
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatPinUpdated {
+    op: String,
+    pin: ChatPin,
+    members: Vec<i64>,
+}
+
+// pg_notify('chat_message_expired', json_build_object('message', MESSAGE, 'members', USERS)::text);
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatMessageExpired {
+    message: ExpiredMessage,
+    members: Vec<i64>,
+}
+
+// pg_notify('poll_updated', json_build_object('poll', POLL, 'members', USERS)::text);
+#[derive(Debug, Serialize, Deserialize)]
+struct PollUpdated {
+    poll: PollUpdate,
+    members: Vec<i64>,
 }
 
 pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
     let mut listener = PgListener::connect(&state.config.server.db_url).await?;
     listener.listen("chat_updated").await?;
     listener.listen("chat_message_created").await?;
+    listener.listen("chat_pin_updated").await?;
+    listener.listen("chat_message_expired").await?;
+    listener.listen("poll_updated").await?;
+    listener.listen("reminder_fired").await?;
+    listener.listen("draft_updated").await?;
 
     let mut stream = listener.into_stream();
 
     tokio::spawn(async move {
         while let Some(Ok(notif)) = stream.next().await {
             info!("Received notification: {:?}", notif);
-            let notification = Notification::load(notif.channel(), notif.payload())?;
+            let notifications = Notification::load(notif.channel(), notif.payload())?;
             let users = &state.users;
-            for user_id in notification.user_ids {
-                if let Some(tx) = users.get(&user_id) {
-                    info!("Sending notification to user {}", user_id);
-                    if let Err(e) = tx.send(notification.event.clone()) {
-                        warn!("Failed to send notification to user {}: {}", user_id, e);
+            for notification in notifications {
+                for user_id in notification.user_ids {
+                    if let Some(tx) = users.get(&user_id) {
+                        info!("Sending notification to user {}", user_id);
+                        if let Err(e) = tx.send(notification.event.clone()) {
+                            warn!("Failed to send notification to user {}: {}", user_id, e);
+                        }
                     }
                 }
             }
@@ -66,17 +121,95 @@ pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
 }
 
 impl Notification {
-    fn load(r#type: &str, payload: &str) -> anyhow::Result<Self> {
+    // a chat update notifies the members who stay and the removed members differently,
+    // a new message is silent for members not to be alerted
+    fn load(r#type: &str, payload: &str) -> anyhow::Result<Vec<Self>> {
+        if r#type == "chat_message_created" {
+            return Self::load_message_created(payload);
+        }
+        if r#type != "chat_updated" {
+            return Ok(vec![Self::load_one(r#type, payload)?]);
+        }
+        let payload: ChatUpdated = serde_json::from_str(payload)?;
+        info!("ChatUpdated: {:?}", payload);
+        if payload.truncated {
+            warn!(
+                "chat {} updated without its members, only joined or left members are notified",
+                payload.chat.id
+            );
+        }
+        let chat = payload.chat;
+        let mut user_ids: HashSet<u64> = chat.members.iter().map(|v| *v as u64).collect();
+        let notifications = match payload.op.as_str() {
+            "INSERT" => {
+                user_ids.extend(payload.added.iter().map(|v| *v as u64));
+                vec![Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::NewChat(chat)),
+                }]
+            }
+            // same members, the chat itself changed
+            "UPDATE" if payload.added.is_empty() && payload.removed.is_empty() => {
+                let event = match (payload.was_archived, chat.archived) {
+                    (false, true) => AppEvent::ChatArchived(chat),
+                    (true, false) => AppEvent::ChatRestored(chat),
+                    _ => AppEvent::ChatUpdated(chat),
+                };
+                vec![Self {
+                    user_ids,
+                    event: Arc::new(event),
+                }]
+            }
+            "UPDATE" => {
+                user_ids.extend(payload.added.iter().map(|v| *v as u64));
+                vec![
+                    Self {
+                        user_ids: payload.removed.iter().map(|v| *v as u64).collect(),
+                        event: Arc::new(AppEvent::RemoveFromChat(chat.clone())),
+                    },
+                    Self {
+                        user_ids,
+                        event: Arc::new(AppEvent::AddToChat(chat)),
+                    },
+                ]
+            }
+            "DELETE" => vec![Self {
+                user_ids,
+                event: Arc::new(AppEvent::RemoveFromChat(chat)),
+            }],
+            _ => return Err(anyhow::anyhow!("Invalid operation")),
+        };
+        Ok(notifications)
+    }
+
+    fn load_message_created(payload: &str) -> anyhow::Result<Vec<Self>> {
+        let payload: ChatMessageCreated = serde_json::from_str(payload)?;
+        let (silent, alerted): (HashSet<u64>, HashSet<u64>) = payload
+            .members
+            .iter()
+            .map(|v| *v as u64)
+            .partition(|id| payload.silent.contains(&(*id as i64)));
+        let mut notifications = vec![Self {
+            user_ids: alerted,
+            event: Arc::new(AppEvent::NewMessage(payload.message.clone())),
+        }];
+        if !silent.is_empty() {
+            notifications.push(Self {
+                user_ids: silent,
+                event: Arc::new(AppEvent::SilentMessage(payload.message)),
+            });
+        }
+        Ok(notifications)
+    }
+
+    fn load_one(r#type: &str, payload: &str) -> anyhow::Result<Self> {
         match r#type {
-            "chat_updated" => {
-                let payload: ChatUpdated = serde_json::from_str(payload)?;
-                info!("ChatUpdated: {:?}", payload);
-                let user_ids =
-                    get_affected_chat_user_ids(payload.old.as_ref(), payload.new.as_ref());
+            "chat_pin_updated" => {
+                let payload: ChatPinUpdated = serde_json::from_str(payload)?;
+                let user_ids = payload.members.iter().map(|v| *v as u64).collect();
                 let event = match payload.op.as_str() {
-                    "INSERT" => AppEvent::NewChat(payload.new.expect("new should exist")),
-                    "UPDATE" => AppEvent::AddToChat(payload.new.expect("new should exist")),
-                    "DELETE" => AppEvent::RemoveFromChat(payload.old.expect("old should exist")),
+                    "INSERT" => AppEvent::MessagePinned(payload.pin),
+                    "DELETE" => AppEvent::MessageUnpinned(payload.pin),
                     _ => return Err(anyhow::anyhow!("Invalid operation")),
                 };
                 Ok(Self {
@@ -84,33 +217,39 @@ impl Notification {
                     event: Arc::new(event),
                 })
             }
-            "chat_message_created" => {
-                let payload: ChatMessageCreated = serde_json::from_str(payload)?;
+            "chat_message_expired" => {
+                let payload: ChatMessageExpired = serde_json::from_str(payload)?;
                 let user_ids = payload.members.iter().map(|v| *v as u64).collect();
                 Ok(Self {
                     user_ids,
-                    event: Arc::new(AppEvent::NewMessage(payload.message)),
+                    event: Arc::new(AppEvent::MessageExpired(payload.message)),
                 })
             }
-            _ => Err(anyhow::anyhow!("Invalid notification type")),
-        }
-    }
-}
-
-fn get_affected_chat_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet<u64> {
-    match (old, new) {
-        (Some(old), Some(new)) => {
-            // diff old/new members, if identical, no need to notify, otherwise notify the union of both
-            let old_user_ids: HashSet<_> = old.members.iter().map(|v| *v as u64).collect();
-            let new_user_ids: HashSet<_> = new.members.iter().map(|v| *v as u64).collect();
-            if old_user_ids == new_user_ids {
-                HashSet::new()
-            } else {
-                old_user_ids.union(&new_user_ids).copied().collect()
+            "poll_updated" => {
+                let payload: PollUpdated = serde_json::from_str(payload)?;
+                let user_ids = payload.members.iter().map(|v| *v as u64).collect();
+                Ok(Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::PollUpdated(payload.poll)),
+                })
+            }
+            // pg_notify('reminder_fired', json_build_object('user_id', ...)::text);
+            "reminder_fired" => {
+                let payload: Reminder = serde_json::from_str(payload)?;
+                Ok(Self {
+                    user_ids: HashSet::from([payload.user_id as u64]),
+                    event: Arc::new(AppEvent::Reminder(payload)),
+                })
+            }
+            // pg_notify('draft_updated', row_to_json(NEW)::text);
+            "draft_updated" => {
+                let payload: Draft = serde_json::from_str(payload)?;
+                Ok(Self {
+                    user_ids: HashSet::from([payload.user_id as u64]),
+                    event: Arc::new(AppEvent::DraftUpdated(payload)),
+                })
             }
+            _ => Err(anyhow::anyhow!("Invalid notification type")),
         }
-        (Some(old), None) => old.members.iter().map(|v| *v as u64).collect(),
-        (None, Some(new)) => new.members.iter().map(|v| *v as u64).collect(),
-        _ => HashSet::new(),
     }
 }